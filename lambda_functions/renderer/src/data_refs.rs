@@ -0,0 +1,312 @@
+use crate::defaults;
+use crate::storage::{ObjectStore, S3Store, StoreError};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::time::Duration;
+use thiserror::Error;
+use url::Url;
+
+// External JSON merged into a job's data before rendering
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DataRef {
+    // s3://bucket/key or https://host/path, allowed by DATA_REF_ALLOWLIST
+    pub source: String,
+    // Dot-separated path in `data` to merge the fetched JSON at; empty for the root
+    #[serde(default)]
+    pub merge_path: String,
+}
+
+#[derive(Error, Debug)]
+pub enum DataRefError {
+    #[error("Invalid data source {0}: {1}")]
+    InvalidSource(String, String),
+    #[error("Data source {0} is not in DATA_REF_ALLOWLIST")]
+    NotAllowed(String),
+    #[error("Failed to fetch data source {0}: {1}")]
+    Fetch(String, String),
+}
+
+// Fetches data refs from S3 and HTTPS, limited to allowlisted locations
+#[derive(Debug)]
+pub struct DataRefFetcher {
+    s3_client: aws_sdk_s3::Client,
+    http: reqwest::Client,
+    // Allowed sources: a source matches an entry with the same scheme, host
+    // and port whose path is the source's path or one of its parent directories
+    allowlist: Vec<Url>,
+    max_bytes: usize,
+}
+
+impl DataRefFetcher {
+    pub fn new(
+        s3_client: aws_sdk_s3::Client,
+        allowlist: Vec<Url>,
+        max_bytes: usize,
+        timeout: Duration,
+    ) -> Result<Self, reqwest::Error> {
+        // Redirects could lead outside the allowlist, so they aren't followed
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(timeout)
+            .build()?;
+        Ok(Self {
+            s3_client,
+            http,
+            allowlist,
+            max_bytes,
+        })
+    }
+
+    pub async fn fetch(&self, source: &str) -> Result<Value, DataRefError> {
+        let url = Url::parse(source)
+            .map_err(|e| DataRefError::InvalidSource(source.to_string(), e.to_string()))?;
+        if !matches!(url.scheme(), "s3" | "https") {
+            return Err(DataRefError::InvalidSource(
+                source.to_string(),
+                "only s3:// and https:// sources are supported".to_string(),
+            ));
+        }
+        if !self.is_allowed(&url) {
+            return Err(DataRefError::NotAllowed(source.to_string()));
+        }
+
+        let bytes = match url.scheme() {
+            "s3" => self.fetch_s3(&url).await,
+            _ => self.fetch_https(url).await,
+        }
+        .map_err(|e| DataRefError::Fetch(source.to_string(), e))?;
+        serde_json::from_slice(&bytes)
+            .map_err(|e| DataRefError::Fetch(source.to_string(), format!("invalid JSON: {}", e)))
+    }
+
+    fn is_allowed(&self, url: &Url) -> bool {
+        // Paths like /a/../b would otherwise pass a prefix check for /a/
+        let path = percent_decode_str(url.path()).decode_utf8_lossy();
+        if path.split('/').any(|segment| segment == "..") {
+            return false;
+        }
+        self.allowlist.iter().any(|allowed| {
+            allowed.scheme() == url.scheme()
+                && allowed.host_str() == url.host_str()
+                && allowed.port_or_known_default() == url.port_or_known_default()
+                && within(url.path(), allowed.path())
+        })
+    }
+
+    async fn fetch_s3(&self, url: &Url) -> Result<Vec<u8>, String> {
+        let bucket = url.host_str().ok_or("missing bucket")?;
+        let key = percent_decode_str(url.path().trim_start_matches('/')).decode_utf8_lossy();
+        let store = S3Store::new(self.s3_client.clone(), bucket);
+        let size = store.head(&key).await.map_err(describe_store_error)?;
+        if size > self.max_bytes as u64 {
+            return Err(format!(
+                "{} bytes exceeds the limit of {} bytes",
+                size, self.max_bytes
+            ));
+        }
+        store.get(&key).await.map_err(describe_store_error)
+    }
+
+    async fn fetch_https(&self, url: Url) -> Result<Vec<u8>, String> {
+        let mut response = self
+            .http
+            .get(url)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| e.to_string())?;
+
+        // Read in chunks so an oversized response is dropped without buffering it all
+        let mut bytes = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+            bytes.extend_from_slice(&chunk);
+            if bytes.len() > self.max_bytes {
+                return Err(format!(
+                    "response exceeds the limit of {} bytes",
+                    self.max_bytes
+                ));
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+// Whether `path` is `allowed` itself or lies below it. Matching stops at a
+// '/' boundary, so /reports doesn't allow /reports-private.
+fn within(path: &str, allowed: &str) -> bool {
+    match path.strip_prefix(allowed) {
+        Some(rest) => allowed.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+fn describe_store_error(e: StoreError) -> String {
+    match e {
+        StoreError::NotFound(_) => "object not found".to_string(),
+        StoreError::AccessDenied(_) => "access denied".to_string(),
+        StoreError::ChecksumMismatch(_) | StoreError::AlreadyExists(_) | StoreError::Backend(_) => {
+            e.to_string()
+        }
+    }
+}
+
+// Parse a comma-separated DATA_REF_ALLOWLIST
+pub fn parse_allowlist(config: &str) -> Result<Vec<Url>, url::ParseError> {
+    config
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(Url::parse)
+        .collect()
+}
+
+// Deep-merge `fragment` into `data` at a dot-separated path, creating objects
+// along the way
+pub fn merge_at(data: &mut Value, path: &str, fragment: Value) {
+    let mut target = data;
+    for key in path.split('.').filter(|key| !key.is_empty()) {
+        if !target.is_object() {
+            *target = Value::Object(Map::new());
+        }
+        target = target
+            .as_object_mut()
+            .expect("target was just made an object")
+            .entry(key)
+            .or_insert(Value::Null);
+    }
+    defaults::deep_merge(target, fragment);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_s3::MockS3;
+    use serde_json::json;
+
+    fn fetcher(allowlist: &str) -> DataRefFetcher {
+        fetcher_with(&MockS3::default(), allowlist)
+    }
+
+    fn fetcher_with(s3: &MockS3, allowlist: &str) -> DataRefFetcher {
+        DataRefFetcher::new(
+            s3.client(),
+            parse_allowlist(allowlist).unwrap(),
+            1024,
+            Duration::from_secs(1),
+        )
+        .unwrap()
+    }
+
+    fn allowed(fetcher: &DataRefFetcher, source: &str) -> bool {
+        fetcher.is_allowed(&Url::parse(source).unwrap())
+    }
+
+    #[test]
+    fn allows_sources_below_an_entry_at_a_path_boundary() {
+        let fetcher = fetcher("s3://data/reports, https://api.example.com/v1/, s3://shared");
+        assert!(allowed(&fetcher, "s3://data/reports"));
+        assert!(allowed(&fetcher, "s3://data/reports/2024.json"));
+        assert!(!allowed(&fetcher, "s3://data/reports-private/2024.json"));
+        assert!(!allowed(&fetcher, "s3://data/reportsx.json"));
+        assert!(allowed(&fetcher, "https://api.example.com/v1/customers/1"));
+        assert!(!allowed(
+            &fetcher,
+            "https://api.example.com/v10/customers/1"
+        ));
+        assert!(allowed(&fetcher, "s3://shared/any/key.json"));
+    }
+
+    #[test]
+    fn rejects_other_hosts_schemes_ports_and_parent_segments() {
+        let fetcher = fetcher("https://api.example.com/v1/");
+        assert!(!allowed(&fetcher, "https://evil.example.com/v1/a"));
+        assert!(!allowed(&fetcher, "http://api.example.com/v1/a"));
+        assert!(!allowed(&fetcher, "https://api.example.com:8443/v1/a"));
+        assert!(allowed(&fetcher, "https://api.example.com:443/v1/a"));
+        assert!(!allowed(
+            &fetcher,
+            "https://api.example.com/v1/%2E%2E/admin"
+        ));
+    }
+
+    #[test]
+    fn parses_comma_separated_allowlists() {
+        let allowlist =
+            parse_allowlist(" s3://data/reports , ,https://api.example.com/v1/").unwrap();
+        let entries: Vec<&str> = allowlist.iter().map(Url::as_str).collect();
+        assert_eq!(
+            entries,
+            ["s3://data/reports", "https://api.example.com/v1/"]
+        );
+        assert!(parse_allowlist("").unwrap().is_empty());
+        assert!(parse_allowlist("s3://data, not a url").is_err());
+    }
+
+    #[test]
+    fn merges_fragments_at_dot_separated_paths() {
+        let mut data = json!({"customer": {"name": "Ada"}, "total": 3});
+        merge_at(&mut data, "customer", json!({"vat_id": "DE1"}));
+        merge_at(&mut data, "meta.source.system", json!("crm"));
+        merge_at(&mut data, "", json!({"currency": "EUR"}));
+        assert_eq!(
+            data,
+            json!({
+                "customer": {"name": "Ada", "vat_id": "DE1"},
+                "meta": {"source": {"system": "crm"}},
+                "total": 3,
+                "currency": "EUR",
+            })
+        );
+
+        // Non-object values along the path are replaced
+        merge_at(&mut data, "total.amount", json!(3));
+        assert_eq!(data["total"], json!({"amount": 3}));
+    }
+
+    #[tokio::test]
+    async fn fetches_allowed_s3_sources_within_the_size_limit() {
+        let s3 = MockS3::default();
+        s3.respond_with_headers(200, &[("content-length", "14")], "")
+            .respond(200, r#"{"rate": 0.19}"#)
+            .respond_with_headers(200, &[("content-length", "4096")], "");
+        let fetcher = fetcher_with(&s3, "s3://data/reports/");
+
+        let value = fetcher
+            .fetch("s3://data/reports/tax%20rates.json")
+            .await
+            .unwrap();
+        assert_eq!(value, json!({"rate": 0.19}));
+        let requests = s3.requests();
+        assert_eq!(requests[0].method, "HEAD");
+        assert!(requests[1].uri.contains("/reports/tax%20rates.json"));
+
+        // Oversized objects are refused from their size, without downloading them
+        let error = fetcher
+            .fetch("s3://data/reports/big.json")
+            .await
+            .unwrap_err();
+        assert!(matches!(error, DataRefError::Fetch(_, ref e) if e.contains("exceeds")));
+        assert_eq!(s3.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn refuses_unsupported_and_unlisted_sources_without_fetching() {
+        let s3 = MockS3::default();
+        let fetcher = fetcher_with(&s3, "s3://data/reports/");
+        assert!(matches!(
+            fetcher.fetch("s3://data/private/a.json").await,
+            Err(DataRefError::NotAllowed(_))
+        ));
+        assert!(matches!(
+            fetcher.fetch("ftp://data/reports/a.json").await,
+            Err(DataRefError::InvalidSource(..))
+        ));
+        assert!(matches!(
+            fetcher.fetch("reports/a.json").await,
+            Err(DataRefError::InvalidSource(..))
+        ));
+        assert!(s3.requests().is_empty());
+    }
+}
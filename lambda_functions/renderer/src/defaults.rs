@@ -0,0 +1,68 @@
+use serde_json::Value;
+
+// Suffix of the defaults object stored next to a template
+pub const DEFAULTS_SUFFIX: &str = ".defaults.json";
+
+pub fn defaults_key(template_id: &str) -> String {
+    format!("{}{}", template_id, DEFAULTS_SUFFIX)
+}
+
+// Deep-merge `overrides` into `base`. Objects are merged key by key; any other
+// value in `overrides` (including arrays and null) replaces the one in `base`.
+pub fn deep_merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => deep_merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn merges_objects_and_replaces_other_values() {
+        let mut base = json!({
+            "company": {"name": "Acme", "country": "DE"},
+            "lines": [1, 2],
+            "currency": "EUR",
+            "note": "default",
+        });
+        deep_merge(
+            &mut base,
+            json!({
+                "company": {"name": "Globex"},
+                "lines": [3],
+                "note": null,
+                "total": 10,
+            }),
+        );
+        assert_eq!(
+            base,
+            json!({
+                "company": {"name": "Globex", "country": "DE"},
+                "lines": [3],
+                "currency": "EUR",
+                "note": null,
+                "total": 10,
+            })
+        );
+    }
+
+    #[test]
+    fn non_object_overrides_replace_the_base() {
+        let mut base = json!({"a": 1});
+        deep_merge(&mut base, json!([1]));
+        assert_eq!(base, json!([1]));
+    }
+}
@@ -0,0 +1,84 @@
+use papermake::render::RenderError as PapermakeDiagnostic;
+use serde::Serialize;
+use std::fmt;
+
+// A template compile error located in the template source
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub severity: &'static str,
+    // 1-based line and column of the start of the error span
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+// Resolve papermake's byte-offset spans into line/column positions
+pub fn from_render_errors(source: &str, errors: &[PapermakeDiagnostic]) -> Vec<Diagnostic> {
+    errors
+        .iter()
+        .map(|error| {
+            let (line, column) = line_column(source, error.start);
+            Diagnostic {
+                severity: "error",
+                line,
+                column,
+                message: error.message.clone(),
+            }
+        })
+        .collect()
+}
+
+fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let mut offset = offset.min(source.len());
+    while !source.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_offsets_to_lines_and_columns() {
+        let source = "first\nsecond line\nthird";
+        assert_eq!(line_column(source, 0), (1, 1));
+        assert_eq!(line_column(source, 6), (2, 1));
+        assert_eq!(line_column(source, 13), (2, 8));
+        assert_eq!(line_column(source, source.len()), (3, 6));
+    }
+
+    #[test]
+    fn counts_columns_in_characters_and_clamps_offsets() {
+        let source = "äöü x";
+        // "x" starts at byte 7 but is the 5th character
+        assert_eq!(line_column(source, 7), (1, 5));
+        // Inside "ö", resolved to the character's start
+        assert_eq!(line_column(source, 3), (1, 2));
+        assert_eq!(line_column(source, 1000), (1, 6));
+    }
+
+    #[test]
+    fn converts_render_errors() {
+        let errors = [PapermakeDiagnostic {
+            message: "unknown variable: x".to_string(),
+            start: 4,
+            end: 5,
+        }];
+        let diagnostics = from_render_errors("a\nb #x", &errors);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].to_string(), "2:3: unknown variable: x");
+        assert_eq!(diagnostics[0].severity, "error");
+    }
+}
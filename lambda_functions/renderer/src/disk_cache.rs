@@ -0,0 +1,187 @@
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+// Length of the SHA-256 checksum written before each entry's content
+const CHECKSUM_LEN: usize = 32;
+
+// Byte cache in a local directory, e.g. Lambda's /tmp, which can outlive a
+// single execution environment's in-memory caches. Each file holds a checksum
+// of its content, so truncated or corrupted entries are detected and dropped.
+// Bounded by the total size of the cached files, evicting the oldest first.
+#[derive(Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    ttl: Duration,
+}
+
+impl DiskCache {
+    pub fn new(dir: impl Into<PathBuf>, max_bytes: u64, ttl: Duration) -> Self {
+        Self {
+            dir: dir.into(),
+            max_bytes,
+            ttl,
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(hex::encode(Sha256::digest(key.as_bytes())))
+    }
+
+    pub async fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let path = self.path(key);
+        let modified = tokio::fs::metadata(&path).await.ok()?.modified().ok()?;
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        if age > self.ttl {
+            let _ = tokio::fs::remove_file(&path).await;
+            return None;
+        }
+
+        let mut contents = tokio::fs::read(&path).await.ok()?;
+        let valid = contents.len() >= CHECKSUM_LEN
+            && contents[..CHECKSUM_LEN] == Sha256::digest(&contents[CHECKSUM_LEN..])[..];
+        if !valid {
+            warn!("Discarding corrupted disk cache entry {}", path.display());
+            let _ = tokio::fs::remove_file(&path).await;
+            return None;
+        }
+        Some(contents.split_off(CHECKSUM_LEN))
+    }
+
+    // Drop an entry, logging rather than failing if it can't be deleted
+    pub async fn remove(&self, key: &str) {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove disk cache entry: {}", e),
+        }
+    }
+
+    // Store an entry, logging rather than failing if the disk can't be written
+    pub async fn insert(&self, key: &str, bytes: &[u8]) {
+        if (bytes.len() + CHECKSUM_LEN) as u64 > self.max_bytes {
+            return;
+        }
+        if let Err(e) = self.write(key, bytes).await {
+            warn!("Failed to write disk cache entry: {}", e);
+        }
+    }
+
+    async fn write(&self, key: &str, bytes: &[u8]) -> std::io::Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+
+        let mut contents = Sha256::digest(bytes).to_vec();
+        contents.extend_from_slice(bytes);
+        // Write to a temporary file first so readers never see a partial entry
+        let path = self.path(key);
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, &contents).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+
+        self.evict().await
+    }
+
+    // Remove the oldest entries until the cache fits in max_bytes
+    async fn evict(&self) -> std::io::Result<()> {
+        let mut entries = Vec::new();
+        let mut total_bytes = 0;
+        let mut dir = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if metadata.is_file() {
+                total_bytes += metadata.len();
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                entries.push((modified, metadata.len(), entry.path()));
+            }
+        }
+
+        entries.sort();
+        for (_, size, path) in entries {
+            if total_bytes <= self.max_bytes {
+                break;
+            }
+            tokio::fs::remove_file(&path).await?;
+            total_bytes -= size;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(name: &str, max_bytes: u64, ttl: Duration) -> DiskCache {
+        let dir = std::env::temp_dir().join(format!(
+            "renderer-disk-cache-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        DiskCache::new(dir, max_bytes, ttl)
+    }
+
+    #[tokio::test]
+    async fn round_trips_and_removes_entries() {
+        let cache = cache("round-trip", 1024, Duration::from_secs(60));
+        assert_eq!(cache.get("invoice.typ").await, None);
+        cache.insert("invoice.typ", b"Hello").await;
+        assert_eq!(
+            cache.get("invoice.typ").await.as_deref(),
+            Some(&b"Hello"[..])
+        );
+        cache.remove("invoice.typ").await;
+        cache.remove("invoice.typ").await;
+        assert_eq!(cache.get("invoice.typ").await, None);
+        std::fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn drops_corrupted_and_expired_entries() {
+        let cache = cache("corrupted", 1024, Duration::from_secs(60));
+        cache.insert("invoice.typ", b"Hello").await;
+        let path = cache.path("invoice.typ");
+        let mut contents = std::fs::read(&path).unwrap();
+        *contents.last_mut().unwrap() ^= 1;
+        std::fs::write(&path, contents).unwrap();
+        assert_eq!(cache.get("invoice.typ").await, None);
+        assert!(!path.exists());
+
+        let expired = DiskCache::new(cache.dir.clone(), 1024, Duration::ZERO);
+        expired.insert("invoice.typ", b"Hello").await;
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(expired.get("invoice.typ").await, None);
+        std::fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn evicts_the_oldest_entries_beyond_max_bytes() {
+        let cache = cache(
+            "evict",
+            2 * (CHECKSUM_LEN as u64 + 10),
+            Duration::from_secs(60),
+        );
+        cache.insert("a", &[1; 10]).await;
+        std::fs::File::options()
+            .write(true)
+            .open(cache.path("a"))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(30))
+            .unwrap();
+        cache.insert("b", &[2; 10]).await;
+        cache.insert("c", &[3; 10]).await;
+
+        assert_eq!(cache.get("a").await, None);
+        assert!(cache.get("b").await.is_some());
+        assert!(cache.get("c").await.is_some());
+
+        // Entries larger than the whole cache aren't written
+        cache.insert("d", &[4; 100]).await;
+        assert_eq!(cache.get("d").await, None);
+        std::fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
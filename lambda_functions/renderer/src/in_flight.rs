@@ -0,0 +1,69 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Process-wide count of requests being handled, with an upper bound
+#[derive(Debug)]
+pub struct InFlightLimiter {
+    count: AtomicUsize,
+    max: usize,
+}
+
+// Holds one in-flight slot, released when dropped (including on panic)
+#[derive(Debug)]
+pub struct InFlightGuard<'a> {
+    limiter: &'a InFlightLimiter,
+}
+
+impl InFlightLimiter {
+    pub fn new(max: usize) -> Self {
+        Self {
+            count: AtomicUsize::new(0),
+            max,
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    // Take a slot, or None if `max` requests are already in flight
+    pub fn try_acquire(&self) -> Option<InFlightGuard<'_>> {
+        self.count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < self.max).then_some(count + 1)
+            })
+            .ok()
+            .map(|_| InFlightGuard { limiter: self })
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.limiter.count.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_slots_and_releases_them_on_drop() {
+        let limiter = InFlightLimiter::new(2);
+        let first = limiter.try_acquire().unwrap();
+        let _second = limiter.try_acquire().unwrap();
+        assert!(limiter.try_acquire().is_none());
+        drop(first);
+        assert!(limiter.try_acquire().is_some());
+    }
+
+    #[test]
+    fn releases_slots_when_a_holder_panics() {
+        let limiter = InFlightLimiter::new(1);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _guard = limiter.try_acquire().unwrap();
+            panic!("render failed");
+        }));
+        assert!(result.is_err());
+        assert!(limiter.try_acquire().is_some());
+    }
+}
@@ -0,0 +1,71 @@
+use std::path::Path;
+use std::sync::Mutex;
+use ulid::{Generator, Ulid};
+
+// Longest template hint kept in a job id
+const MAX_HINT_LEN: usize = 16;
+
+// Shared so ids created within the same millisecond still sort in creation order
+static GENERATOR: Mutex<Generator> = Mutex::new(Generator::new());
+
+// Time-sortable job id prefixed with a short hint of the template,
+// e.g. "invoice-01JA2M3Q4ZK8V6T1X9R7N5B3C2" for "invoices/invoice.typ"
+pub fn new_job_id(template_id: &str) -> String {
+    let ulid = GENERATOR
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .generate()
+        // Only fails if the random part overflows within one millisecond
+        .unwrap_or_else(|_| Ulid::new());
+
+    match template_hint(template_id) {
+        Some(hint) => format!("{}-{}", hint, ulid),
+        None => ulid.to_string(),
+    }
+}
+
+// File stem of the template id, lowercased and reduced to [a-z0-9-]
+fn template_hint(template_id: &str) -> Option<String> {
+    let stem = Path::new(template_id).file_stem()?.to_string_lossy();
+    let hint: String = stem
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .take(MAX_HINT_LEN)
+        .collect();
+    let hint = hint.trim_matches('-');
+    (!hint.is_empty()).then(|| hint.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_ids_with_a_template_hint() {
+        let id = new_job_id("invoices/Invoice_2024.typ");
+        let (hint, ulid) = id.rsplit_once('-').unwrap();
+        assert_eq!(hint, "invoice-2024");
+        assert!(Ulid::from_string(ulid).is_ok());
+
+        assert_eq!(
+            template_hint("a-very-long-template-name.typ").as_deref(),
+            Some("a-very-long-temp")
+        );
+        assert_eq!(template_hint("__.typ"), None);
+        assert!(Ulid::from_string(&new_job_id("")).is_ok());
+    }
+
+    #[test]
+    fn ids_sort_in_creation_order() {
+        let ids: Vec<String> = (0..100).map(|_| new_job_id("invoice.typ")).collect();
+        let mut sorted = ids.clone();
+        sorted.sort();
+        assert_eq!(ids, sorted);
+    }
+}
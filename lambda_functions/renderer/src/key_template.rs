@@ -0,0 +1,121 @@
+use crate::pointer::extract_pointer;
+use serde_json::Value;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum KeyTemplateError {
+    #[error("Unclosed placeholder in output_key_template")]
+    UnclosedPlaceholder,
+    #[error("Unknown placeholder {{{0}}} in output_key_template")]
+    UnknownPlaceholder(String),
+    #[error(
+        "Field {0} referenced by output_key_template is missing from data or not a string, number or boolean"
+    )]
+    MissingField(String),
+}
+
+// Fill in an output key template such as "invoices/{data.invoice_no}/{job_id}".
+// Placeholders are {job_id}, {template_id} and data fields, given as a
+// dot-separated path ({data.customer.id}) or a JSON Pointer ({data/customer/id}).
+pub fn interpolate(
+    template: &str,
+    job_id: &str,
+    template_id: &str,
+    data: Option<&Value>,
+) -> Result<String, KeyTemplateError> {
+    let mut key = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        key.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or(KeyTemplateError::UnclosedPlaceholder)?;
+        let placeholder = &rest[start + 1..start + end];
+        match placeholder {
+            "job_id" => key.push_str(job_id),
+            "template_id" => key.push_str(template_id),
+            _ => match data_path(placeholder) {
+                Some(path) => key.push_str(&data_field(data, path)?),
+                None => {
+                    return Err(KeyTemplateError::UnknownPlaceholder(
+                        placeholder.to_string(),
+                    ))
+                }
+            },
+        }
+        rest = &rest[start + end + 1..];
+    }
+    key.push_str(rest);
+    Ok(key)
+}
+
+// Path into data of a data placeholder: "a.b" for data.a.b, "/a/b" for data/a/b
+fn data_path(placeholder: &str) -> Option<&str> {
+    let path = placeholder.strip_prefix("data")?;
+    match path.strip_prefix('.') {
+        Some(path) => Some(path),
+        None => path.starts_with('/').then_some(path),
+    }
+}
+
+fn data_field(data: Option<&Value>, path: &str) -> Result<String, KeyTemplateError> {
+    data.and_then(|data| extract_pointer(data, path))
+        .ok_or_else(|| KeyTemplateError::MissingField(path.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn interpolate_with(template: &str, data: &Value) -> Result<String, KeyTemplateError> {
+        interpolate(template, "01JOB", "invoice.typ", Some(data))
+    }
+
+    #[test]
+    fn fills_in_ids_and_data_fields() {
+        let data = json!({"invoice_no": 42, "customer": {"id": "c-7", "vip": true}});
+        assert_eq!(
+            interpolate_with("invoices/{data.invoice_no}/{job_id}", &data).unwrap(),
+            "invoices/42/01JOB"
+        );
+        assert_eq!(
+            interpolate_with(
+                "{template_id}/{data/customer/id}-{data.customer.vip}",
+                &data
+            )
+            .unwrap(),
+            "invoice.typ/c-7-true"
+        );
+        assert_eq!(interpolate_with("static/key", &data).unwrap(), "static/key");
+    }
+
+    #[test]
+    fn reports_malformed_templates_and_missing_fields() {
+        let data = json!({"customer": {"id": "c-7", "tags": ["a"]}});
+        assert!(matches!(
+            interpolate_with("invoices/{job_id", &data),
+            Err(KeyTemplateError::UnclosedPlaceholder)
+        ));
+        assert!(matches!(
+            interpolate_with("{tenant}/{job_id}", &data),
+            Err(KeyTemplateError::UnknownPlaceholder(p)) if p == "tenant"
+        ));
+        assert!(matches!(
+            interpolate_with("{datax}", &data),
+            Err(KeyTemplateError::UnknownPlaceholder(_))
+        ));
+        assert!(matches!(
+            interpolate_with("{data.customer.name}", &data),
+            Err(KeyTemplateError::MissingField(f)) if f == "customer.name"
+        ));
+        assert!(matches!(
+            interpolate_with("{data.customer.tags}", &data),
+            Err(KeyTemplateError::MissingField(_))
+        ));
+        assert!(matches!(
+            interpolate("{data.a}", "01JOB", "invoice.typ", None),
+            Err(KeyTemplateError::MissingField(_))
+        ));
+    }
+}
@@ -1,33 +1,354 @@
+use aws_lambda_events::eventbridge::EventBridgeEvent;
 use aws_lambda_events::lambda_function_urls::LambdaFunctionUrlRequest;
-use futures;
+use aws_lambda_events::s3::S3Event;
+use aws_sdk_s3::primitives::{DateTime, DateTimeFormat};
+use aws_sdk_s3::types::StorageClass;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
-use opentelemetry::{global, trace::TracerProvider, KeyValue};
+use opentelemetry::{global, trace::TracerProvider};
 use opentelemetry_otlp::WithExportConfig;
-use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider};
 use papermake::{CachedTemplate, TemplateBuilder, TemplateId};
+use percent_encoding::percent_decode_str;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::sync::Arc;
+use std::future::Future;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, OnceLock};
+use std::time::{Duration, SystemTime};
+use subtle::ConstantTimeEq;
 use thiserror::Error;
 use tokio::{
-    sync::{OnceCell, RwLock},
+    signal,
+    sync::{mpsc, OnceCell, RwLock, Semaphore},
+    task::JoinHandle,
     time::Instant,
 };
-use tracing::{error, info, instrument, Span};
-use tracing_subscriber::{layer::SubscriberExt, Registry};
-use uuid::Uuid;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, field, info, warn, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Registry};
 
+mod data_refs;
+mod defaults;
+mod diagnostics;
+mod disk_cache;
+mod in_flight;
+mod job_id;
+mod key_template;
+#[cfg(test)]
+mod mock_exporter;
+#[cfg(test)]
+mod mock_s3;
+mod parse_error;
+mod pdf;
+mod pointer;
+mod rate_limit;
+mod result_cache;
+mod retry;
+mod storage;
+mod svg;
+mod telemetry;
+mod template_permits;
+mod thumbnail;
+mod transform;
+
+use data_refs::{DataRef, DataRefError, DataRefFetcher};
+use diagnostics::Diagnostic;
+use disk_cache::DiskCache;
+use in_flight::InFlightLimiter;
+use rate_limit::TemplateRateLimiter;
+use result_cache::{CachedOutputs, ResultCache};
+use retry::RetryPolicy;
+use storage::{InMemoryStore, ObjectStore, PutOptions, S3Store, StoreError};
+use telemetry::CountingBatchProcessor;
+use template_permits::{RenderPermit, TemplatePermits};
+use transform::Transform;
+
+// Unknown fields are rejected so misspelled options fail the request
+// instead of being silently ignored
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct RenderRequest {
-    jobs: Vec<RenderJobRequest>,
+    // Each job is parsed into a RenderJobRequest on its own, see process_batch
+    jobs: Vec<Value>,
+    // Return results in the same order as `jobs`
+    #[serde(default)]
+    preserve_order: bool,
+    // Upload all rendered jobs as one combined PDF, in input order
+    #[serde(default)]
+    combine: bool,
+    // What to do with failed jobs when combining
+    #[serde(default)]
+    on_combine_error: CombineErrorMode,
+    // What to do when a result key already exists
+    #[serde(default)]
+    on_conflict: OnConflict,
+    // Render without uploading, returning a hash and size of each job's output
+    #[serde(default)]
+    fingerprint_only: bool,
+    // Selects the tenant's results bucket from TENANT_RESULTS_BUCKETS
+    #[serde(default)]
+    tenant_id: Option<String>,
+    // Fraction of jobs (0 to 1) that may fail or time out before the whole
+    // batch is reported as failed
+    #[serde(default, deserialize_with = "deserialize_fraction")]
+    failure_threshold: Option<f64>,
+    // Delete the uploads of successful jobs when the batch fails
+    #[serde(default)]
+    rollback: bool,
+    // Also upload every output to DR_RESULTS_BUCKET, under the same keys
+    #[serde(default)]
+    replicate: bool,
+    // Key of each job's output in place of the job id, e.g.
+    // "invoices/{data.invoice_no}/{job_id}", see key_template::interpolate
+    #[serde(default)]
+    output_key_template: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+fn deserialize_fraction<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<f64>, D::Error> {
+    match Option::<f64>::deserialize(deserializer)? {
+        Some(fraction) if !(0.0..=1.0).contains(&fraction) => Err(serde::de::Error::custom(
+            format!("{} is not between 0 and 1", fraction),
+        )),
+        fraction => Ok(fraction),
+    }
+}
+
+fn deserialize_storage_class<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<StorageClass>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(name) if !StorageClass::values().contains(&name.as_str()) => {
+            Err(serde::de::Error::custom(format!(
+                "unknown storage class {}, expected one of {}",
+                name,
+                StorageClass::values().join(", ")
+            )))
+        }
+        name => Ok(name.as_deref().map(StorageClass::from)),
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum CombineErrorMode {
+    // Don't upload a combined PDF if any job failed
+    #[default]
+    Abort,
+    // Put a page describing the error in place of each failed job
+    ErrorPage,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum OutputFormat {
+    #[default]
+    Pdf,
+    // One SVG per page, uploaded as {job_id}/page-{n}.svg
+    Svg,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct RenderJobRequest {
+    // Required unless template_selector is set, which fills it in
+    #[serde(default)]
+    template_id: String,
+    // Choose the template from a field of `data`
+    #[serde(default)]
+    template_selector: Option<TemplateSelector>,
+    // Template input; jobs without it render with an empty object, so static
+    // templates only need a template_id
+    #[serde(default)]
+    data: Option<Value>,
+    // Render once per element instead of `data`, uploading {job_id}/{index}.pdf
+    #[serde(default)]
+    data_array: Option<Vec<Value>>,
+    // Key of a JSON object in DATA_BUCKET to use as `data`, keeping payloads small
+    #[serde(default)]
+    data_key: Option<String>,
+    // Upload each page as its own PDF under {job_id}/page-{n}.pdf
+    #[serde(default)]
+    split_pages: bool,
+    // Output format of the job's documents, PDF unless set to "svg"
+    #[serde(default)]
+    format: OutputFormat,
+    // Merge `data` over the template's {template_id}.defaults.json
+    #[serde(default)]
+    merge_defaults: bool,
+    // Document properties to write into each output PDF
+    #[serde(default)]
+    metadata: Option<DocumentMetadata>,
+    // Upload to this bucket instead of the results bucket; must be listed in
+    // OUTPUT_BUCKET_ALLOWLIST
+    #[serde(default)]
+    output_bucket: Option<String>,
+    // JSON fetched from S3 or HTTPS and merged into the data (each element of
+    // data_array) before rendering, in order
+    #[serde(default)]
+    data_refs: Vec<DataRef>,
+    // Jobs with a higher priority are rendered first, see job_priority
+    #[serde(default)]
+    priority: i32,
+    // S3 storage class of the job's uploads, e.g. STANDARD_IA; STANDARD if unset
+    #[serde(default, deserialize_with = "deserialize_storage_class")]
+    storage_class: Option<StorageClass>,
+    // Also upload a PNG of the first page as {job_id}-thumb.png
+    #[serde(default)]
+    thumbnail: bool,
+    // Resolution of the thumbnail, defaults to 72 DPI
+    #[serde(default)]
+    thumbnail_dpi: Option<u32>,
+    // Also upload the job as sent, with its resolved template, as
+    // {job_id}.request.json so the render can be reproduced
+    #[serde(default)]
+    store_request: bool,
+}
+
+impl RenderJobRequest {
+    // Resolve template_selector into template_id, checking exactly one is given
+    fn with_selected_template(mut self) -> Result<Self, RenderError> {
+        let Some(selector) = self.template_selector.take() else {
+            if self.template_id.is_empty() {
+                return Err(RenderError::JobParseError(
+                    "one of template_id and template_selector is required".to_string(),
+                ));
+            }
+            return Ok(self);
+        };
+        if !self.template_id.is_empty() {
+            return Err(RenderError::JobParseError(
+                "only one of template_id and template_selector may be set".to_string(),
+            ));
+        }
+        if self.data_array.is_some() || self.data_key.is_some() {
+            return Err(RenderError::JobParseError(
+                "template_selector can't be combined with data_array or data_key".to_string(),
+            ));
+        }
+        self.template_id = selector.select(self.data.as_ref())?;
+        Ok(self)
+    }
+}
+
+// Picks the template from a field of the job's data, e.g.
+// {"field": "address.country", "templates": {"DE": "invoice-de.typ"}, "default": "invoice.typ"}
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TemplateSelector {
+    // Dot-separated path or JSON Pointer into `data`, see pointer::extract_pointer;
+    // strings, numbers and booleans can be matched
+    field: String,
+    // Field value to template_id
+    templates: HashMap<String, String>,
+    // Used when the field is missing or has no entry in `templates`
+    #[serde(default)]
+    default: Option<String>,
+}
+
+impl TemplateSelector {
+    fn select(&self, data: Option<&Value>) -> Result<String, RenderError> {
+        let key = data.and_then(|data| pointer::extract_pointer(data, &self.field));
+
+        key.as_ref()
+            .and_then(|key| self.templates.get(key))
+            .or(self.default.as_ref())
+            .cloned()
+            .ok_or_else(|| {
+                RenderError::InvalidRequest(match key {
+                    Some(key) => format!("no template selected for {} = {:?}", self.field, key),
+                    None => format!("no template selected, {} is missing", self.field),
+                })
+            })
+    }
+}
+
+// PDF document properties. The job and template ids are always added as
+// X-Job-Id and X-Template-Id.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DocumentMetadata {
+    title: Option<String>,
+    author: Option<String>,
+}
+
+impl DocumentMetadata {
+    fn info_entries<'a>(
+        &'a self,
+        job_id: &'a str,
+        template_id: &'a str,
+    ) -> Vec<(&'a str, &'a str)> {
+        let mut entries = vec![("X-Job-Id", job_id), ("X-Template-Id", template_id)];
+        if let Some(title) = &self.title {
+            entries.push(("Title", title));
+        }
+        if let Some(author) = &self.author {
+            entries.push(("Author", author));
+        }
+        entries
+    }
+}
+
+// Function URL requests that aren't render batches, e.g. {"action": "list_templates"}
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case", deny_unknown_fields)]
+enum ActionRequest {
+    ListTemplates {
+        // Only list template ids starting with this prefix
+        #[serde(default)]
+        prefix: Option<String>,
+    },
+    // Size, hit rate and evictions of this container's template cache
+    CacheStats,
+    // Reachability of the buckets the renderer depends on, for readiness probes
+    Health,
+}
+
+#[derive(Debug, Serialize)]
+struct TemplateInfo {
     template_id: String,
-    data: serde_json::Value,
+    size: u64,
+    // RFC 3339 timestamp, if known
+    last_modified: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct TemplateCacheStats {
+    entries: usize,
+    // Total size of the cached template sources
+    source_bytes: usize,
+    // Lookups since the container started, including template prefetches
+    hits: u64,
+    misses: u64,
+    // Templates dropped after a panicking render
+    evictions: u64,
+}
+
+// Detail of a (scheduled) EventBridge event pointing at a manifest in S3.
+// The manifest is a RenderRequest; `bucket` defaults to the templates bucket.
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestDetail {
+    #[serde(default)]
+    bucket: Option<String>,
+    key: String,
+}
+
+// Events the renderer can be invoked with
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum IncomingEvent {
+    EventBridge(Box<EventBridgeEvent<ManifestDetail>>),
+    S3(Box<S3Event>),
+    FunctionUrl(Box<LambdaFunctionUrlRequest>),
 }
 
 #[derive(Debug, Serialize)]
@@ -35,22 +356,152 @@ struct JobResult {
     job_id: String,
     template_id: String,
     status: String,
+    // Attempts taken by the job's upload, including retries
+    attempts: u32,
+    // Bucket the results were uploaded to
+    bucket: Option<String>,
+    // Disaster recovery bucket holding a copy under the same keys, for
+    // replicated batches whose copies were all uploaded
+    replica_bucket: Option<String>,
     s3_key: Option<String>,
+    // All uploaded keys, for jobs producing more than one object
+    s3_keys: Option<Vec<String>>,
     file_size: Option<u64>,
+    // For fingerprint_only batches: SHA-256 of the rendered bytes, in output
+    // order (the PDF's own hash for single-output jobs)
+    sha256: Option<String>,
+    // Pages rendered for the job, across all of its outputs
+    page_count: Option<usize>,
+    // PNG of the first page, for jobs requesting a thumbnail
+    thumbnail_s3_key: Option<String>,
+    // Record of the job's request, for jobs with store_request set
+    request_s3_key: Option<String>,
+    // Whether the job's outputs were rendered for it or taken from the result cache
+    source: Option<ResultSource>,
+    // Machine-readable identifier for the failure, see RenderError::error_code
+    error_code: Option<String>,
     error: Option<String>,
 }
 
+impl JobResult {
+    fn failure(
+        job_id: String,
+        template_id: String,
+        status: &str,
+        error_code: &str,
+        error: String,
+    ) -> Self {
+        JobResult {
+            job_id,
+            template_id,
+            status: status.to_string(),
+            attempts: 1,
+            bucket: None,
+            replica_bucket: None,
+            s3_key: None,
+            s3_keys: None,
+            file_size: None,
+            sha256: None,
+            page_count: None,
+            thumbnail_s3_key: None,
+            request_s3_key: None,
+            source: None,
+            error_code: Some(error_code.to_string()),
+            error: Some(error),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum OnConflict {
+    // Replace the existing object
+    #[default]
+    Overwrite,
+    // Fail the job with result_exists
+    Fail,
+    // Keep the existing object and report it as the job's result
+    Skip,
+}
+
+// A rendered job whose output objects are waiting to be uploaded
+struct RenderedJob {
+    // Position of the job in the request
+    index: usize,
+    job_id: String,
+    template_id: String,
+    // (s3_key, data) for each object to upload
+    outputs: Vec<(String, Vec<u8>)>,
+    // Report the outputs as a list of keys rather than a single key
+    multi_output: bool,
+    // Store chosen by the job's output_bucket, overriding the batch's results store
+    output_store: Option<Arc<dyn ObjectStore>>,
+    // (s3_key, png) of the first page, for jobs requesting a thumbnail
+    thumbnail: Option<(String, Vec<u8>)>,
+    // (s3_key, json) recording the job's request, for jobs with store_request set
+    request_record: Option<(String, Vec<u8>)>,
+    storage_class: Option<StorageClass>,
+    source: ResultSource,
+}
+
+// Objects rendered for a job, keyed by S3 key
+struct JobOutputs {
+    outputs: Vec<(String, Vec<u8>)>,
+    thumbnail: Option<(String, Vec<u8>)>,
+    request_record: Option<(String, Vec<u8>)>,
+    source: ResultSource,
+}
+
+// Where a job's outputs came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ResultSource {
+    // Rendered by this job
+    Rendered,
+    // Taken from the result cache; for data_array jobs, every element was
+    Cache,
+}
+
+impl JobOutputs {
+    // Replace the job_id prefix of every key with `key_base`
+    fn rekeyed(self, job_id: &str, key_base: &str) -> Self {
+        let rekey = |(s3_key, data): (String, Vec<u8>)| {
+            (format!("{}{}", key_base, &s3_key[job_id.len()..]), data)
+        };
+        JobOutputs {
+            outputs: self.outputs.into_iter().map(rekey).collect(),
+            thumbnail: self.thumbnail.map(rekey),
+            request_record: self.request_record.map(rekey),
+            source: self.source,
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 struct BatchResponse {
+    // X-Request-Id sent by the caller, or one generated for this invocation
+    request_id: String,
     results: Vec<JobResult>,
+    // Key of the combined PDF, for batches with `combine` set
+    combined_s3_key: Option<String>,
+    // Key of the copy of this response in the results bucket, when BATCH_MANIFEST_PREFIX is set
+    manifest_s3_key: Option<String>,
     summary: BatchSummary,
 }
 
 #[derive(Debug, Serialize)]
 struct BatchSummary {
+    // "failed" when more jobs failed than the request's failure_threshold allows,
+    // "empty" for a request without jobs
+    batch_status: &'static str,
     total: usize,
     success: usize,
     failed: usize,
+    timed_out: usize,
+    // Jobs not rendered because the batch was cancelled, e.g. on SIGTERM
+    cancelled: usize,
+    // Successful jobs whose uploads were deleted because the batch failed
+    rolled_back: usize,
 }
 
 #[derive(Error, Debug)]
@@ -59,40 +510,718 @@ pub enum RenderError {
     JobParseError(String),
     #[error("Failed to render PDF: {0}")]
     RenderingError(String),
+    #[error("Render panicked: {0}")]
+    RenderPanic(String),
+    #[error("Render exceeded its time budget of {budget_ms}ms")]
+    RenderTimeout { budget_ms: u64 },
+    #[error("Batch was cancelled before the render finished")]
+    Cancelled,
+    #[error("Failed to process PDF: {0}")]
+    PdfProcessingError(String),
+    #[error("Rendered PDF is invalid: {0}")]
+    InvalidOutput(String),
+    #[error("Combined PDF not created: {0}")]
+    CombineAborted(String),
+    #[error("Template {template_id} failed to compile at {}", describe_diagnostics(.diagnostics))]
+    CompileError {
+        template_id: String,
+        diagnostics: Vec<Diagnostic>,
+    },
+    #[error("Template not found: {template_id}")]
+    TemplateNotFound { template_id: String },
+    #[error("Data object not found: {key}")]
+    DataNotFound { key: String },
+    #[error("Access denied reading template: {template_id}")]
+    TemplateAccessDenied { template_id: String },
+    #[error("Template {template_id} doesn't match its S3 checksum")]
+    TemplateCorrupt { template_id: String },
+    #[error("Template {template_id} is {size} bytes, over the limit of {max_bytes}")]
+    TemplateTooLarge {
+        template_id: String,
+        size: u64,
+        max_bytes: u64,
+    },
+    #[error("Template {template_id} is a {detected_type} file, not a Typst template")]
+    NotATemplate {
+        template_id: String,
+        detected_type: &'static str,
+    },
+    #[error("Invalid object key {key:?}: {reason}")]
+    InvalidKey { key: String, reason: &'static str },
+    #[error("Result object already exists: {0}")]
+    ResultExists(String),
     #[error("S3 operation failed: {0}")]
     S3Error(String),
     #[error("Environment variable not found: {0}")]
     EnvVarError(String),
+    #[error("Failed to decode request body: {0}")]
+    BodyDecodeError(String),
+    #[error("Unsupported Content-Encoding: {0}")]
+    UnsupportedEncoding(String),
+    #[error("Invalid request format: {0}")]
+    InvalidRequest(String),
+    #[error("Request body too large: {0}")]
+    PayloadTooLarge(String),
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    DataRefNotAllowed(String),
+    #[error("{0}")]
+    DataRefFetchFailed(String),
+    #[error("Too many requests in flight: {0}")]
+    Overloaded(String),
+    #[error("Service unavailable: {0}")]
+    Unavailable(String),
+    #[error("Rate limit exceeded for template {template_id}, retry after {retry_after_ms}ms")]
+    RateLimited {
+        template_id: String,
+        retry_after_ms: u64,
+    },
+}
+
+// First diagnostic with its location, noting how many more there are
+fn describe_diagnostics(diagnostics: &[Diagnostic]) -> String {
+    match diagnostics {
+        [] => "unknown location".to_string(),
+        [first] => first.to_string(),
+        [first, rest @ ..] => format!("{} (and {} more errors)", first, rest.len()),
+    }
+}
+
+impl From<DataRefError> for RenderError {
+    fn from(e: DataRefError) -> Self {
+        match e {
+            DataRefError::InvalidSource(..) => RenderError::InvalidRequest(e.to_string()),
+            DataRefError::NotAllowed(_) => RenderError::DataRefNotAllowed(e.to_string()),
+            DataRefError::Fetch(..) => RenderError::DataRefFetchFailed(e.to_string()),
+        }
+    }
+}
+
+impl RenderError {
+    // HTTP status code reported to function URL callers
+    fn status_code(&self) -> u16 {
+        match self {
+            RenderError::JobParseError(_)
+            | RenderError::BodyDecodeError(_)
+            | RenderError::InvalidRequest(_)
+            | RenderError::InvalidKey { .. } => 400,
+            RenderError::Unauthorized(_) => 401,
+            RenderError::DataRefNotAllowed(_) => 403,
+            RenderError::TemplateNotFound { .. } | RenderError::DataNotFound { .. } => 404,
+            RenderError::ResultExists(_) => 409,
+            RenderError::PayloadTooLarge(_) => 413,
+            RenderError::UnsupportedEncoding(_) => 415,
+            RenderError::RateLimited { .. } | RenderError::Overloaded(_) => 429,
+            RenderError::DataRefFetchFailed(_) => 502,
+            RenderError::Unavailable(_) | RenderError::Cancelled => 503,
+            RenderError::RenderTimeout { .. } => 504,
+            RenderError::CompileError { .. }
+            | RenderError::TemplateTooLarge { .. }
+            | RenderError::NotATemplate { .. } => 422,
+            RenderError::RenderingError(_)
+            | RenderError::RenderPanic(_)
+            | RenderError::PdfProcessingError(_)
+            | RenderError::InvalidOutput(_)
+            | RenderError::CombineAborted(_)
+            | RenderError::TemplateAccessDenied { .. }
+            | RenderError::TemplateCorrupt { .. }
+            | RenderError::S3Error(_)
+            | RenderError::EnvVarError(_) => 500,
+        }
+    }
+
+    // Stable machine-readable error identifier
+    fn error_code(&self) -> &'static str {
+        match self {
+            RenderError::JobParseError(_) => "job_parse_error",
+            RenderError::RenderingError(_) => "rendering_error",
+            RenderError::RenderPanic(_) => "render_panic",
+            RenderError::RenderTimeout { .. } => "render_timeout",
+            RenderError::Cancelled => "cancelled",
+            RenderError::PdfProcessingError(_) => "pdf_processing_error",
+            RenderError::InvalidOutput(_) => "invalid_output",
+            RenderError::CombineAborted(_) => "combine_aborted",
+            RenderError::CompileError { .. } => "compile_error",
+            RenderError::TemplateNotFound { .. } => "template_not_found",
+            RenderError::DataNotFound { .. } => "data_not_found",
+            RenderError::TemplateAccessDenied { .. } => "template_access_denied",
+            RenderError::TemplateCorrupt { .. } => "template_corrupt",
+            RenderError::TemplateTooLarge { .. } => "template_too_large",
+            RenderError::NotATemplate { .. } => "not_a_template",
+            RenderError::ResultExists(_) => "result_exists",
+            RenderError::S3Error(_) => "s3_error",
+            RenderError::EnvVarError(_) => "configuration_error",
+            RenderError::BodyDecodeError(_) => "body_decode_error",
+            RenderError::UnsupportedEncoding(_) => "unsupported_encoding",
+            RenderError::InvalidRequest(_) => "invalid_request",
+            RenderError::InvalidKey { .. } => "invalid_key",
+            RenderError::PayloadTooLarge(_) => "payload_too_large",
+            RenderError::Unauthorized(_) => "unauthorized",
+            RenderError::DataRefNotAllowed(_) => "data_ref_not_allowed",
+            RenderError::DataRefFetchFailed(_) => "data_ref_fetch_failed",
+            RenderError::RateLimited { .. } => "rate_limited",
+            RenderError::Overloaded(_) => "overloaded",
+            RenderError::Unavailable(_) => "unavailable",
+        }
+    }
+
+    // Function URL response carrying this error as a JSON body
+    fn to_response(&self) -> Value {
+        let body = json!({
+            "error_code": self.error_code(),
+            "message": self.to_string(),
+        });
+        json!({
+            "statusCode": self.status_code(),
+            "headers": { "content-type": "application/json" },
+            "body": body.to_string(),
+        })
+    }
 }
 
+// Results stores keyed by tenant_id or bucket name
+type NamedStores = HashMap<String, Arc<dyn ObjectStore>>;
+
 // Shared resources across invocations
 #[derive(Debug)]
 struct SharedResources {
     s3_client: aws_sdk_s3::Client,
-    templates_bucket: String,
-    results_bucket: String,
+    // Object stores for templates and rendered results (S3 unless STORAGE_BACKEND=memory)
+    templates: Arc<dyn ObjectStore>,
+    results: Arc<dyn ObjectStore>,
+    // Results stores of tenants with their own bucket, configured via TENANT_RESULTS_BUCKETS
+    tenant_results: NamedStores,
+    // Objects jobs reference with data_key: DATA_BUCKET, or the templates store
+    data_objects: Arc<dyn ObjectStore>,
+    // Copy of the results in another region, configured via DR_RESULTS_BUCKET and DR_REGION
+    replica_results: Option<Arc<dyn ObjectStore>>,
+    // Buckets jobs may pick with output_bucket, configured via OUTPUT_BUCKET_ALLOWLIST
+    output_buckets: NamedStores,
     // Cache compiled templates with their content - much simpler than manual world management
     template_cache: RwLock<HashMap<String, (Vec<u8>, CachedTemplate)>>,
+    // Template cache lookups, reported by the cache_stats action
+    template_cache_hits: AtomicU64,
+    template_cache_misses: AtomicU64,
+    // Templates evicted after a render of them panicked
+    template_cache_evictions: AtomicU64,
+    // Raw template bytes kept in /tmp across cold starts, enabled by TEMPLATE_DISK_CACHE_MAX_BYTES
+    template_disk_cache: Option<DiskCache>,
+    // Per-template default data, None if the template has no defaults object
+    defaults_cache: RwLock<HashMap<String, Option<Value>>>,
+    // Per-template data transform, None if the template has no transform object
+    transform_cache: RwLock<HashMap<String, Option<Arc<Transform>>>>,
+    // Per-template token buckets, configured via TEMPLATE_RATE_LIMITS
+    rate_limiter: TemplateRateLimiter,
+    // Time reserved before the Lambda deadline for finishing uploads and responding
+    deadline_safety_margin: Duration,
+    // Recently rendered outputs, enabled by RESULT_CACHE_MAX_BYTES
+    result_cache: Option<ResultCache>,
+    // Required x-api-key header value for function URL requests, if set
+    api_key: Option<String>,
+    // Maximum request body size, after decompression
+    max_request_body_bytes: usize,
+    // Responses at least this large are gzip-compressed for callers accepting it
+    response_gzip_min_bytes: usize,
+    // Templates larger than this are refused before downloading
+    max_template_bytes: u64,
+    // Check rendered PDFs for a header, trailer and pages before they are used,
+    // enabled by VALIDATE_OUTPUT_PDF
+    validate_output_pdf: bool,
+    // Metadata applied to uploaded results
+    result_settings: ResultObjectSettings,
+    // Retries for result uploads, configured via UPLOAD_MAX_ATTEMPTS
+    upload_retry: RetryPolicy,
+    // Prefix of per-batch copies of the response, configured via BATCH_MANIFEST_PREFIX
+    batch_manifest_prefix: Option<String>,
+    // Key layout for renders triggered by S3 uploads
+    s3_trigger: S3TriggerSettings,
+    // Bound on concurrently handled invocations, configured via MAX_IN_FLIGHT
+    in_flight: InFlightLimiter,
+    // Fetches data_refs from locations in DATA_REF_ALLOWLIST
+    data_ref_fetcher: DataRefFetcher,
+    // Renders running at once across all invocations, configured via GLOBAL_RENDER_PERMITS
+    render_permits: Arc<Semaphore>,
+    // Renders running at once per template, configured via TEMPLATE_CONCURRENCY
+    template_permits: TemplatePermits,
+    // Jobs uploading at once across all invocations, configured via GLOBAL_UPLOAD_PERMITS
+    upload_permits: Arc<Semaphore>,
+    // Start each job's upload as soon as it is rendered instead of after the
+    // whole render phase, configured via PIPELINE_UPLOADS
+    pipeline_uploads: bool,
+    // Accept requests without jobs, configured via ALLOW_EMPTY_BATCH
+    allow_empty_batch: bool,
+}
+
+// Where S3-triggered renders read data objects from and write results to
+#[derive(Debug, Clone)]
+struct S3TriggerSettings {
+    // Prefix of data objects, followed by {template_id}/{name}.json
+    data_prefix: String,
+    // Prefix of rendered results in the results store
+    results_prefix: String,
+}
+
+// Caching metadata set on every uploaded result object
+#[derive(Debug, Clone, Default)]
+struct ResultObjectSettings {
+    // Cache-Control header, e.g. "no-store" or "public, max-age=31536000"
+    cache_control: Option<String>,
+    // Expires header, relative to the upload time
+    expires_after: Option<Duration>,
+}
+
+impl ResultObjectSettings {
+    fn put_options(&self, content_type: &str, storage_class: Option<StorageClass>) -> PutOptions {
+        PutOptions {
+            content_type: Some(content_type.to_string()),
+            cache_control: self.cache_control.clone(),
+            expires: self
+                .expires_after
+                .map(|expires_after| SystemTime::now() + expires_after),
+            storage_class,
+            if_absent: false,
+        }
+    }
+}
+
+impl SharedResources {
+    // Results store for a tenant, falling back to RESULTS_BUCKET for unmapped tenants
+    fn results_store(&self, tenant_id: Option<&str>) -> Arc<dyn ObjectStore> {
+        tenant_id
+            .and_then(|tenant_id| self.tenant_results.get(tenant_id))
+            .unwrap_or(&self.results)
+            .clone()
+    }
+}
+
+// Cancelled on SIGTERM; batches then stop starting new renders
+static SHUTDOWN: LazyLock<CancellationToken> = LazyLock::new(CancellationToken::new);
+
+// OTLP trace export, set in main when OTLP_ENDPOINT is configured
+#[derive(Debug)]
+struct TraceExport {
+    provider: SdkTracerProvider,
+    // Longest an invocation waits for its spans to be exported
+    flush_timeout: Duration,
 }
 
+static TRACE_EXPORT: OnceLock<TraceExport> = OnceLock::new();
+
 // Use OnceCell instead of Lazy to initialize asynchronously
 static RESOURCES: OnceCell<Arc<SharedResources>> = OnceCell::const_new();
 
-// Render PDF without uploading to S3
+// Fetch a job's data_refs, returning each fragment with its merge path
+async fn fetch_data_refs(
+    resources: &SharedResources,
+    refs: &[DataRef],
+) -> Result<Vec<(String, Value)>, RenderError> {
+    if refs.is_empty() {
+        return Ok(Vec::new());
+    }
+    let fetch_span = tracing::info_span!("data_refs_fetch", count = refs.len());
+    let fetches = refs.iter().map(|data_ref| async move {
+        let fragment = resources.data_ref_fetcher.fetch(&data_ref.source).await?;
+        Ok::<_, RenderError>((data_ref.merge_path.clone(), fragment))
+    });
+    futures::future::try_join_all(fetches)
+        .instrument(fetch_span)
+        .await
+}
+
+fn with_fragments(mut data: Value, fragments: &[(String, Value)]) -> Value {
+    for (merge_path, fragment) in fragments {
+        data_refs::merge_at(&mut data, merge_path, fragment.clone());
+    }
+    data
+}
+
+// Render PDF without uploading to S3, returning the objects to upload
 async fn render_pdf(
     resources: &SharedResources,
     job_id: &str,
     job_request: &RenderJobRequest,
-) -> Result<(String, Vec<u8>), RenderError> {
+) -> Result<JobOutputs, RenderError> {
+    let thumbnail_dpi = job_request.thumbnail_dpi.unwrap_or(thumbnail::DEFAULT_DPI);
+    if job_request.thumbnail && !(1..=thumbnail::MAX_DPI).contains(&thumbnail_dpi) {
+        return Err(RenderError::InvalidRequest(format!(
+            "thumbnail_dpi must be between 1 and {}",
+            thumbnail::MAX_DPI
+        )));
+    }
+
+    // Inline data, or the object data_key refers to
+    let data = match &job_request.data_key {
+        Some(_) if job_request.data.is_some() || job_request.data_array.is_some() => {
+            return Err(RenderError::InvalidRequest(
+                "Only one of data, data_array and data_key may be set".to_string(),
+            ))
+        }
+        Some(data_key) => Some(fetch_data_object(resources, data_key).await?),
+        None => job_request.data.clone(),
+    };
+    let fragments = fetch_data_refs(resources, &job_request.data_refs).await?;
+
+    let (outputs, source) = match &job_request.data_array {
+        Some(_) if data.is_some() => {
+            return Err(RenderError::InvalidRequest(
+                "Only one of data and data_array may be set".to_string(),
+            ))
+        }
+        // One render per element, with outputs under {job_id}/{index}
+        Some(data_array) => {
+            let mut outputs = Vec::new();
+            // An empty array has nothing cached, so it only counts as cached
+            // when there are elements and all of them were
+            let mut all_cached = !data_array.is_empty();
+            for (index, data) in data_array.iter().enumerate() {
+                let data = with_fragments(data.clone(), &fragments);
+                let (element_outputs, element_source) =
+                    render_cached(resources, job_id, job_request, &data).await?;
+                all_cached &= element_source == ResultSource::Cache;
+                outputs.extend(
+                    element_outputs
+                        .into_iter()
+                        .map(|(suffix, data)| (format!("/{}{}", index, suffix), data)),
+                );
+            }
+            let source = if all_cached {
+                ResultSource::Cache
+            } else {
+                ResultSource::Rendered
+            };
+            (outputs, source)
+        }
+        None => {
+            let data = data.clone().unwrap_or_else(|| json!({}));
+            let data = with_fragments(data, &fragments);
+            render_cached(resources, job_id, job_request, &data).await?
+        }
+    };
+
+    // Written after the result cache, since cached outputs are shared between jobs
+    let outputs = match &job_request.metadata {
+        Some(metadata) => {
+            let info = metadata.info_entries(job_id, &job_request.template_id);
+            outputs
+                .into_iter()
+                .map(|(suffix, data)| {
+                    let data = pdf::set_info(&data, &info).map_err(|e| {
+                        RenderError::PdfProcessingError(format!("Failed to set metadata: {}", e))
+                    })?;
+                    Ok((suffix, data))
+                })
+                .collect::<Result<Vec<_>, RenderError>>()?
+        }
+        None => outputs,
+    };
+
+    // The thumbnail shows the first document of a data_array job
+    let thumbnail_data = match &job_request.data_array {
+        Some(data_array) => data_array.first().cloned(),
+        None => Some(data.unwrap_or_else(|| json!({}))),
+    };
+    let thumbnail = match thumbnail_data.filter(|_| job_request.thumbnail) {
+        Some(data) => {
+            let data = with_fragments(data, &fragments);
+            let png = render_thumbnail(resources, job_request, &data, thumbnail_dpi).await?;
+            Some((format!("{}{}", job_id, THUMBNAIL_SUFFIX), png))
+        }
+        None => None,
+    };
+
+    Ok(JobOutputs {
+        outputs: outputs
+            .into_iter()
+            .map(|(suffix, data)| (format!("{}{}", job_id, suffix), data))
+            .collect(),
+        thumbnail,
+        request_record: None,
+        source,
+    })
+}
+
+// Fetch and parse the JSON object a job's data_key refers to
+async fn fetch_data_object(
+    resources: &SharedResources,
+    data_key: &str,
+) -> Result<Value, RenderError> {
+    validate_key(data_key)?;
+    let store = resources.data_objects.as_ref();
+    let fetch_span = tracing::info_span!(
+        "data_object_fetch",
+        bucket = %store.bucket(),
+        key = %data_key
+    );
+    let fetch_error = |e: StoreError| match e {
+        StoreError::NotFound(_) => RenderError::DataNotFound {
+            key: data_key.to_string(),
+        },
+        e => RenderError::S3Error(format!("Failed to fetch data object {}: {}", data_key, e)),
+    };
+
+    // Data objects stand in for request bodies, so they get the same size limit
+    let size = store
+        .head(data_key)
+        .instrument(fetch_span.clone())
+        .await
+        .map_err(fetch_error)?;
+    if size > resources.max_request_body_bytes as u64 {
+        return Err(RenderError::PayloadTooLarge(format!(
+            "data object {} is {} bytes, over the limit of {}",
+            data_key, size, resources.max_request_body_bytes
+        )));
+    }
+    let bytes = store
+        .get(data_key)
+        .instrument(fetch_span)
+        .await
+        .map_err(fetch_error)?;
+    serde_json::from_slice(&bytes).map_err(|e| {
+        RenderError::InvalidRequest(format!("Data object {} is not valid JSON: {}", data_key, e))
+    })
+}
+
+// Key suffix of a job's thumbnail, relative to the job_id
+const THUMBNAIL_SUFFIX: &str = "-thumb.png";
+// Key suffix of a job's request record, relative to the job_id
+const REQUEST_RECORD_SUFFIX: &str = ".request.json";
+
+// Record of a job for reproducing its render: the job as sent, the template it
+// resolved to and the SHA-256 of that template's source as rendered. The hash
+// rather than the S3 version id identifies the template, since templates
+// loaded from the disk cache carry no S3 metadata.
+async fn request_record(
+    resources: &SharedResources,
+    job: &Value,
+    job_id: &str,
+    template_id: &str,
+) -> Vec<u8> {
+    let template_sha256 = resources
+        .template_cache
+        .read()
+        .await
+        .get(template_id)
+        .map(|(source, _)| hex::encode(Sha256::digest(source)));
+    let record = json!({
+        "job_id": job_id,
+        "template_id": template_id,
+        "template_sha256": template_sha256,
+        "request": job,
+    });
+    serde_json::to_vec_pretty(&record).unwrap_or_default()
+}
+
+// Data as the template receives it: reshaped by the template's transform, if
+// it has one, then merged over its defaults if the job asks for it
+async fn prepare_data(
+    resources: &SharedResources,
+    job_request: &RenderJobRequest,
+    data: &Value,
+) -> Result<Value, RenderError> {
+    let template_id = &job_request.template_id;
+    let data = match get_template_transform(resources, template_id).await? {
+        Some(transform) => transform.apply(data.clone()).map_err(|e| {
+            RenderError::InvalidRequest(format!(
+                "Failed to transform data for template {}: {}",
+                template_id, e
+            ))
+        })?,
+        None => data.clone(),
+    };
+    if !job_request.merge_defaults {
+        return Ok(data);
+    }
+    match get_template_defaults(resources, template_id).await? {
+        Some(mut merged) => {
+            defaults::deep_merge(&mut merged, data);
+            Ok(merged)
+        }
+        None => Ok(data),
+    }
+}
+
+// Wait for a render slot, both the template's (see TEMPLATE_CONCURRENCY) and
+// one of the global permits shared by all invocations
+async fn acquire_render_permit(
+    resources: &SharedResources,
+    template_id: &str,
+) -> Result<RenderPermit, RenderError> {
+    resources
+        .template_permits
+        .acquire(template_id, &resources.render_permits)
+        .instrument(tracing::info_span!("render_permit_wait"))
+        .await
+        .map_err(|e| RenderError::RenderingError(format!("Render permits closed: {}", e)))
+}
+
+// Rasterize the first page of a job rendered with `data` into a PNG
+async fn render_thumbnail(
+    resources: &SharedResources,
+    job_request: &RenderJobRequest,
+    data: &Value,
+    dpi: u32,
+) -> Result<Vec<u8>, RenderError> {
+    let data = prepare_data(resources, job_request, data).await?;
+    let template = get_cached_template(resources, &job_request.template_id)
+        .await?
+        .template()
+        .content
+        .clone();
+
+    let render_permit = acquire_render_permit(resources, &job_request.template_id).await?;
+    let thumbnail_span = tracing::info_span!("thumbnail_render", dpi);
+    let thumbnail = tokio::task::spawn_blocking(move || {
+        let _render_permit = render_permit;
+        let _enter = thumbnail_span.enter();
+        thumbnail::first_page_png(&template, &data, dpi)
+    })
+    .await
+    .map_err(|e| match e.try_into_panic() {
+        Ok(payload) => RenderError::RenderPanic(panic_message(payload.as_ref())),
+        Err(e) => RenderError::RenderingError(format!("Thumbnail task failed: {}", e)),
+    });
+    if let Err(RenderError::RenderPanic(_)) = &thumbnail {
+        evict_template(resources, &job_request.template_id).await;
+    }
+    thumbnail?
+        .map_err(|e| RenderError::RenderingError(format!("Failed to render thumbnail: {}", e)))
+}
+
+// Draw each page of a job rendered with `data` as an SVG, keyed /page-{n}.svg
+async fn render_svg_pages(
+    resources: &SharedResources,
+    job_request: &RenderJobRequest,
+    data: Value,
+) -> Result<CachedOutputs, RenderError> {
+    let template = get_cached_template(resources, &job_request.template_id)
+        .await?
+        .template()
+        .content
+        .clone();
+
+    let render_permit = acquire_render_permit(resources, &job_request.template_id).await?;
+    let render_span = tracing::info_span!("svg_render", page_count = field::Empty);
+    let pages = tokio::task::spawn_blocking(move || {
+        let _render_permit = render_permit;
+        let _enter = render_span.enter();
+        let pages = svg::render_pages(&template, &data);
+        if let Ok(pages) = &pages {
+            Span::current().record("page_count", pages.len());
+        }
+        pages
+    })
+    .await
+    .map_err(|e| match e.try_into_panic() {
+        Ok(payload) => RenderError::RenderPanic(panic_message(payload.as_ref())),
+        Err(e) => RenderError::RenderingError(format!("SVG render task failed: {}", e)),
+    });
+    if let Err(RenderError::RenderPanic(_)) = &pages {
+        evict_template(resources, &job_request.template_id).await;
+    }
+    let pages =
+        pages?.map_err(|e| RenderError::RenderingError(format!("Failed to render SVG: {}", e)))?;
+
+    Ok(pages
+        .into_iter()
+        .enumerate()
+        .map(|(i, page)| (format!("/page-{}.svg", i + 1), page.into_bytes()))
+        .collect())
+}
+
+// Render one set of data for a job, going through the result cache if enabled
+async fn render_cached(
+    resources: &SharedResources,
+    job_id: &str,
+    job_request: &RenderJobRequest,
+    data: &Value,
+) -> Result<(CachedOutputs, ResultSource), RenderError> {
+    let data = prepare_data(resources, job_request, data).await?;
+
+    let cache_entry = resources.result_cache.as_ref().map(|cache| {
+        let data = serde_json::to_vec(&data).unwrap_or_default();
+        let key = result_cache::cache_key(&[
+            job_request.template_id.as_bytes(),
+            &data,
+            &[job_request.split_pages as u8, job_request.format as u8],
+        ]);
+        (cache, key)
+    });
+
+    match cache_entry {
+        Some((cache, key)) => match cache.get(&key) {
+            Some(outputs) => {
+                info!("Using cached render result for job {}", job_id);
+                Ok((outputs, ResultSource::Cache))
+            }
+            None => {
+                let outputs = render_outputs(resources, job_request, data).await?;
+                cache.insert(key, outputs.clone());
+                Ok((outputs, ResultSource::Rendered))
+            }
+        },
+        None => Ok((
+            render_outputs(resources, job_request, data).await?,
+            ResultSource::Rendered,
+        )),
+    }
+}
+
+// Render a job with the given data into its output objects, keyed by suffix
+// relative to the job_id
+async fn render_outputs(
+    resources: &SharedResources,
+    job_request: &RenderJobRequest,
+    data: Value,
+) -> Result<CachedOutputs, RenderError> {
+    // Refuse to start renders for templates that are over their rate limit
+    resources
+        .rate_limiter
+        .try_acquire(&job_request.template_id)
+        .map_err(|retry_after| RenderError::RateLimited {
+            template_id: job_request.template_id.clone(),
+            retry_after_ms: retry_after.as_millis().try_into().unwrap_or(u64::MAX),
+        })?;
+
+    if job_request.format == OutputFormat::Svg {
+        return render_svg_pages(resources, job_request, data).await;
+    }
+
     // Get or create cached template
     let cached_template = get_cached_template(resources, &job_request.template_id).await?;
 
+    // Concurrent invocations share the container's CPUs, so wait for a render slot
+    let render_permit = acquire_render_permit(resources, &job_request.template_id).await?;
+
     // Render PDF
-    let render_span = tracing::info_span!("pdf_render");
+    let render_span = tracing::info_span!("pdf_render", output_bytes = field::Empty);
     let start_time = Instant::now();
-    let render_result = {
+    // Render on the blocking pool, which also turns a panic inside papermake
+    // into an error for this job instead of aborting the whole batch
+    // The permit moves into the blocking task, so a render whose job timed out
+    // keeps its slot until it actually finishes
+    let render_task = tokio::task::spawn_blocking(move || {
+        let _render_permit = render_permit;
         let _enter = render_span.enter();
-        cached_template.render(&job_request.data)
+        let result = cached_template.render(&data);
+        if let Some(pdf) = result.as_ref().ok().and_then(|r| r.pdf.as_ref()) {
+            Span::current().record("output_bytes", pdf.len());
+        }
+        (cached_template, result)
+    })
+    .await
+    .map_err(|e| match e.try_into_panic() {
+        Ok(payload) => RenderError::RenderPanic(panic_message(payload.as_ref())),
+        Err(e) => RenderError::RenderingError(format!("Render task failed: {}", e)),
+    });
+    let (cached_template, render_result) = match render_task {
+        Ok(rendered) => rendered,
+        Err(e) => {
+            if matches!(e, RenderError::RenderPanic(_)) {
+                evict_template(resources, &job_request.template_id).await;
+            }
+            return Err(e);
+        }
     };
 
     let pdf_data = match render_result {
@@ -101,6 +1230,15 @@ async fn render_pdf(
             info!("Render time: {:?}", render_time);
             match result.pdf {
                 Some(pdf) => pdf,
+                None if !result.errors.is_empty() => {
+                    return Err(RenderError::CompileError {
+                        template_id: job_request.template_id.clone(),
+                        diagnostics: diagnostics::from_render_errors(
+                            &cached_template.template().content,
+                            &result.errors,
+                        ),
+                    })
+                }
                 None => {
                     return Err(RenderError::RenderingError(
                         "Render result is empty".to_string(),
@@ -111,84 +1249,459 @@ async fn render_pdf(
         Err(e) => return Err(RenderError::RenderingError(e.to_string())),
     };
 
-    let s3_key = format!("{}.pdf", job_id);
-    Ok((s3_key, pdf_data))
+    // Checked before the result cache and page splitting, so a broken PDF is
+    // never stored
+    if resources.validate_output_pdf {
+        pdf::check_structure(&pdf_data).map_err(RenderError::InvalidOutput)?;
+    }
+
+    if job_request.split_pages {
+        let split_span = tracing::info_span!("pdf_split");
+        let _enter = split_span.enter();
+        let pages = pdf::split_pages(&pdf_data).map_err(|e| {
+            RenderError::PdfProcessingError(format!("Failed to split pages: {}", e))
+        })?;
+        info!("Split PDF into {} pages", pages.len());
+        return Ok(pages
+            .into_iter()
+            .enumerate()
+            .map(|(i, page)| (format!("/page-{}.pdf", i + 1), page))
+            .collect());
+    }
+
+    Ok(vec![(".pdf".to_string(), pdf_data)])
+}
+
+// Drop a template from the memory and disk caches after a render of it
+// panicked, so its next job fetches and compiles it from scratch instead of
+// reusing anything the panicking render may have left inconsistent
+async fn evict_template(resources: &SharedResources, template_id: &str) {
+    warn!("Evicting template {} from the caches", template_id);
+    let evicted = resources.template_cache.write().await.remove(template_id);
+    if evicted.is_some() {
+        resources
+            .template_cache_evictions
+            .fetch_add(1, Ordering::Relaxed);
+    }
+    if let Some(disk_cache) = &resources.template_disk_cache {
+        disk_cache.remove(template_id).await;
+    }
+}
+
+// Extract the message from a panic payload
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+// A result object after upload_pdf_to_s3, with its size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Upload {
+    // Written by the upload
+    Written(u64),
+    // Left as it was under OnConflict::Skip
+    Kept(u64),
+}
+
+impl Upload {
+    fn size(self) -> u64 {
+        match self {
+            Upload::Written(size) | Upload::Kept(size) => size,
+        }
+    }
 }
 
-// Upload PDF to S3
+// Upload PDF to the results store, retrying transient failures. Returns the
+// outcome together with the number of attempts made. Unless overwriting, the
+// object is only created if its key is free, so concurrent jobs writing the
+// same key can't both succeed.
 async fn upload_pdf_to_s3(
-    resources: &SharedResources,
+    store: &dyn ObjectStore,
+    put_options: PutOptions,
+    retry_policy: &RetryPolicy,
+    on_conflict: OnConflict,
     job_id: &str,
     s3_key: &str,
     pdf_data: Vec<u8>,
-) -> Result<u64, RenderError> {
-    let upload_span = tracing::info_span!("s3_pdf_upload", job_id = %job_id);
+) -> (Result<Upload, RenderError>, u32) {
+    if let Err(e) = validate_key(s3_key) {
+        return (Err(e), 0);
+    }
     let file_size = pdf_data.len() as u64;
+    let upload_span = tracing::info_span!(
+        "s3_pdf_upload",
+        job_id = %job_id,
+        bucket = %store.bucket(),
+        key = %s3_key,
+        bytes = file_size
+    );
+    let put_options = PutOptions {
+        if_absent: on_conflict != OnConflict::Overwrite,
+        ..put_options
+    };
 
-    {
+    let (result, attempts) = {
         let _enter = upload_span.enter();
-        resources
-            .s3_client
-            .put_object()
-            .bucket(&resources.results_bucket)
-            .key(s3_key)
-            .body(pdf_data.into())
-            .send()
-            .await
-            .map_err(|e| RenderError::S3Error(format!("Failed to upload PDF: {}", e)))?;
-    }
+        retry::with_retries(
+            retry_policy,
+            |e| matches!(e, StoreError::Backend(_)),
+            || store.put(s3_key, pdf_data.clone(), put_options.clone()),
+        )
+        .await
+    };
 
-    info!("Successfully uploaded PDF for job {}", job_id);
-    Ok(file_size)
+    match result {
+        Ok(()) => {
+            info!("Successfully uploaded PDF for job {}", job_id);
+            (Ok(Upload::Written(file_size)), attempts)
+        }
+        Err(StoreError::AlreadyExists(_)) if on_conflict == OnConflict::Skip => {
+            info!("Result {} already exists, skipping upload", s3_key);
+            let existing = store
+                .head(s3_key)
+                .instrument(upload_span)
+                .await
+                .map(Upload::Kept)
+                .map_err(|e| {
+                    RenderError::S3Error(format!("Failed to check for existing result: {}", e))
+                });
+            (existing, attempts)
+        }
+        Err(StoreError::AlreadyExists(_)) => {
+            (Err(RenderError::ResultExists(s3_key.to_string())), attempts)
+        }
+        Err(e) => (
+            Err(RenderError::S3Error(format!("Failed to upload PDF: {}", e))),
+            attempts,
+        ),
+    }
 }
 
-// Get cached template or fetch from S3
-async fn get_cached_template(
-    resources: &SharedResources,
-    template_id: &str,
-) -> Result<CachedTemplate, RenderError> {
-    let cache_span = tracing::info_span!("template_cache_lookup");
-    let _enter = cache_span.enter();
-
-    let cache = resources.template_cache.read().await;
-    if let Some((_, cached_template)) = cache.get(template_id) {
-        info!("Using cached template for {}", template_id);
-        Span::current().record("cache_hit", true);
-        return Ok(cached_template.clone());
+// Content type of an uploaded object; everything but thumbnails and request
+// records is a PDF
+fn content_type(s3_key: &str) -> &'static str {
+    if s3_key.ends_with(".png") {
+        "image/png"
+    } else if s3_key.ends_with(".svg") {
+        "image/svg+xml"
+    } else if s3_key.ends_with(".json") {
+        "application/json"
+    } else {
+        "application/pdf"
     }
-    drop(cache);
+}
 
-    Span::current().record("cache_hit", false);
-    info!("Template {} not in cache, fetching from S3", template_id);
+// S3 rejects keys longer than this
+const MAX_KEY_LEN: usize = 1024;
 
-    // Fetch template from S3
-    let s3_fetch_span = tracing::info_span!("s3_template_fetch");
-    let s3_start = Instant::now();
-    let template_result = {
-        let _enter = s3_fetch_span.enter();
-        resources
-            .s3_client
-            .get_object()
-            .bucket(&resources.templates_bucket)
-            .key(template_id)
-            .send()
-            .await
+// Check that a key taken from a request stays a plain relative path of
+// [A-Za-z0-9._-] segments, so it can't reach outside its intended prefix
+fn validate_key(key: &str) -> Result<(), RenderError> {
+    let reason = if key.is_empty() {
+        Some("key is empty")
+    } else if key.len() > MAX_KEY_LEN {
+        Some("key is too long")
+    } else if key.starts_with('/') {
+        Some("key must not start with /")
+    } else if !key
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '/'))
+    {
+        Some("key may only contain A-Z, a-z, 0-9, '.', '_', '-' and '/'")
+    } else if key
+        .split('/')
+        .any(|segment| segment.is_empty() || segment == "." || segment == "..")
+    {
+        Some("key must not contain empty, '.' or '..' path segments")
+    } else {
+        None
     };
-    let s3_fetch_time = s3_start.elapsed();
-    info!("S3 fetch time: {:?}", s3_fetch_time);
 
-    let template_object = template_result
-        .map_err(|e| RenderError::S3Error(format!("Failed to fetch template: {}", e)))?;
+    match reason {
+        Some(reason) => Err(RenderError::InvalidKey {
+            key: key.to_string(),
+            reason,
+        }),
+        None => Ok(()),
+    }
+}
 
-    let template_data = template_object
-        .body
-        .collect()
+// Map a failed template fetch to the matching render error
+fn template_fetch_error(template_id: &str, e: StoreError) -> RenderError {
+    match e {
+        StoreError::NotFound(_) => RenderError::TemplateNotFound {
+            template_id: template_id.to_string(),
+        },
+        StoreError::AccessDenied(_) => RenderError::TemplateAccessDenied {
+            template_id: template_id.to_string(),
+        },
+        StoreError::ChecksumMismatch(_) => RenderError::TemplateCorrupt {
+            template_id: template_id.to_string(),
+        },
+        StoreError::AlreadyExists(_) | StoreError::Backend(_) => {
+            RenderError::S3Error(format!("Failed to fetch template: {}", e))
+        }
+    }
+}
+
+// Get the template's default data, fetching {template_id}.defaults.json on first use
+async fn get_template_defaults(
+    resources: &SharedResources,
+    template_id: &str,
+) -> Result<Option<Value>, RenderError> {
+    validate_key(template_id)?;
+    if let Some(defaults) = resources.defaults_cache.read().await.get(template_id) {
+        return Ok(defaults.clone());
+    }
+
+    let defaults_key = defaults::defaults_key(template_id);
+    let fetch_span = tracing::info_span!(
+        "s3_defaults_fetch",
+        bucket = %resources.templates.bucket(),
+        key = %defaults_key,
+        bytes = field::Empty
+    );
+    let defaults = match resources
+        .templates
+        .get(&defaults_key)
+        .instrument(fetch_span.clone())
+        .await
+    {
+        Ok(bytes) => {
+            fetch_span.record("bytes", bytes.len());
+            Some(serde_json::from_slice(&bytes).map_err(|e| {
+                RenderError::RenderingError(format!(
+                    "Failed to parse defaults for template {}: {}",
+                    template_id, e
+                ))
+            })?)
+        }
+        Err(StoreError::NotFound(_)) => None,
+        Err(e) => return Err(template_fetch_error(template_id, e)),
+    };
+
+    resources
+        .defaults_cache
+        .write()
+        .await
+        .insert(template_id.to_string(), defaults.clone());
+    Ok(defaults)
+}
+
+// Get the template's data transform, fetching {template_id}.transform.json on first use
+async fn get_template_transform(
+    resources: &SharedResources,
+    template_id: &str,
+) -> Result<Option<Arc<Transform>>, RenderError> {
+    validate_key(template_id)?;
+    if let Some(transform) = resources.transform_cache.read().await.get(template_id) {
+        return Ok(transform.clone());
+    }
+
+    let transform_key = transform::transform_key(template_id);
+    let fetch_span = tracing::info_span!(
+        "s3_transform_fetch",
+        bucket = %resources.templates.bucket(),
+        key = %transform_key,
+        bytes = field::Empty
+    );
+    let transform = match resources
+        .templates
+        .get(&transform_key)
+        .instrument(fetch_span.clone())
+        .await
+    {
+        Ok(bytes) => {
+            fetch_span.record("bytes", bytes.len());
+            Some(Arc::new(Transform::parse(&bytes).map_err(|e| {
+                RenderError::RenderingError(format!(
+                    "Failed to parse transform for template {}: {}",
+                    template_id, e
+                ))
+            })?))
+        }
+        Err(StoreError::NotFound(_)) => None,
+        Err(e) => return Err(template_fetch_error(template_id, e)),
+    };
+
+    resources
+        .transform_cache
+        .write()
+        .await
+        .insert(template_id.to_string(), transform.clone());
+    Ok(transform)
+}
+
+// Leading bytes of a gzip stream
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+
+// Magic numbers of binary formats commonly uploaded by mistake in place of a template
+const BINARY_SIGNATURES: [(&[u8], &str); 7] = [
+    (b"\x89PNG\r\n\x1a\n", "PNG"),
+    (b"\xff\xd8\xff", "JPEG"),
+    (b"GIF8", "GIF"),
+    (b"%PDF-", "PDF"),
+    (b"PK\x03\x04", "ZIP"),
+    (GZIP_MAGIC, "gzip"),
+    (b"\x00asm", "WebAssembly"),
+];
+
+// Name of the binary format the data starts with, if any
+fn sniff_binary_type(data: &[u8]) -> Option<&'static str> {
+    BINARY_SIGNATURES
+        .iter()
+        .find(|(signature, _)| data.starts_with(signature))
+        .map(|(_, name)| *name)
+}
+
+// Templates may be stored gzip-compressed; inflate them, bounded by
+// MAX_TEMPLATE_BYTES. Anything else is returned unchanged.
+fn decompress_template(
+    template_id: &str,
+    data: Vec<u8>,
+    max_bytes: u64,
+) -> Result<Vec<u8>, RenderError> {
+    if !data.starts_with(GZIP_MAGIC) {
+        return Ok(data);
+    }
+
+    // Read at most one byte past the limit so a gzip bomb isn't inflated fully
+    let mut decompressed = Vec::new();
+    flate2::read::GzDecoder::new(data.as_slice())
+        .take(max_bytes + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| {
+            RenderError::RenderingError(format!(
+                "Failed to decompress template {}: {}",
+                template_id, e
+            ))
+        })?;
+    if decompressed.len() as u64 > max_bytes {
+        return Err(RenderError::TemplateTooLarge {
+            template_id: template_id.to_string(),
+            size: decompressed.len() as u64,
+            max_bytes,
+        });
+    }
+    info!(
+        "Decompressed template {} from {} to {} bytes",
+        template_id,
+        data.len(),
+        decompressed.len()
+    );
+    Ok(decompressed)
+}
+
+// Download a template, refusing it from its size alone if over MAX_TEMPLATE_BYTES
+async fn fetch_template(
+    resources: &SharedResources,
+    template_id: &str,
+) -> Result<Vec<u8>, RenderError> {
+    let size = resources
+        .templates
+        .head(template_id)
+        .await
+        .map_err(|e| template_fetch_error(template_id, e))?;
+    if size > resources.max_template_bytes {
+        return Err(RenderError::TemplateTooLarge {
+            template_id: template_id.to_string(),
+            size,
+            max_bytes: resources.max_template_bytes,
+        });
+    }
+
+    resources
+        .templates
+        .get(template_id)
         .await
-        .map_err(|e| RenderError::S3Error(format!("Failed to read template data: {}", e)))?
-        .to_vec();
+        .map_err(|e| template_fetch_error(template_id, e))
+}
+
+// Get cached template or fetch from S3
+async fn get_cached_template(
+    resources: &SharedResources,
+    template_id: &str,
+) -> Result<CachedTemplate, RenderError> {
+    validate_key(template_id)?;
+
+    let cache_span = tracing::info_span!(
+        "template_cache_lookup",
+        cache_hit = field::Empty,
+        disk_cache_hit = field::Empty
+    );
+    let _enter = cache_span.enter();
+
+    let cache = resources.template_cache.read().await;
+    if let Some((_, cached_template)) = cache.get(template_id) {
+        info!("Using cached template for {}", template_id);
+        Span::current().record("cache_hit", true);
+        resources
+            .template_cache_hits
+            .fetch_add(1, Ordering::Relaxed);
+        return Ok(cached_template.clone());
+    }
+    drop(cache);
+    resources
+        .template_cache_misses
+        .fetch_add(1, Ordering::Relaxed);
+
+    Span::current().record("cache_hit", false);
+    info!("Template {} not in cache, fetching from S3", template_id);
+
+    let disk_cached = match &resources.template_disk_cache {
+        Some(disk_cache) => disk_cache.get(template_id).await,
+        None => None,
+    };
+    Span::current().record("disk_cache_hit", disk_cached.is_some());
+    let template_data = match disk_cached {
+        Some(template_data) => {
+            info!("Using disk cached template for {}", template_id);
+            template_data
+        }
+        None => {
+            // Fetch template from S3
+            let s3_fetch_span = tracing::info_span!(
+                "s3_template_fetch",
+                bucket = %resources.templates.bucket(),
+                key = %template_id,
+                bytes = field::Empty
+            );
+            let s3_start = Instant::now();
+            let template_result = {
+                let _enter = s3_fetch_span.enter();
+                let result = fetch_template(resources, template_id).await;
+                if let Ok(bytes) = &result {
+                    Span::current().record("bytes", bytes.len());
+                }
+                result
+            };
+            let s3_fetch_time = s3_start.elapsed();
+            info!("S3 fetch time: {:?}", s3_fetch_time);
+
+            let template_data = template_result?;
+            if let Some(disk_cache) = &resources.template_disk_cache {
+                disk_cache.insert(template_id, &template_data).await;
+            }
+            template_data
+        }
+    };
+
+    let template_data =
+        decompress_template(template_id, template_data, resources.max_template_bytes)?;
+    if let Some(detected_type) = sniff_binary_type(&template_data) {
+        return Err(RenderError::NotATemplate {
+            template_id: template_id.to_string(),
+            detected_type,
+        });
+    }
 
     // Parse template content and create cached template
-    let compile_span = tracing::info_span!("template_compile");
+    let compile_span = tracing::info_span!("template_compile", source_bytes = template_data.len());
     let compile_start = Instant::now();
 
     let template_content = String::from_utf8(template_data.clone()).map_err(|e| {
@@ -220,59 +1733,656 @@ async fn get_cached_template(
     Ok(cached_template)
 }
 
+// Decode the request body, decompressing it according to the Content-Encoding header
+fn decode_request_body(
+    request: &LambdaFunctionUrlRequest,
+    max_bytes: usize,
+) -> Result<String, RenderError> {
+    let body = request
+        .body
+        .as_deref()
+        .ok_or_else(|| RenderError::BodyDecodeError("Missing request body".to_string()))?;
+    if body.len() > max_bytes {
+        return Err(RenderError::PayloadTooLarge(format!(
+            "{} bytes exceeds the limit of {} bytes",
+            body.len(),
+            max_bytes
+        )));
+    }
+
+    let encoding = request
+        .headers
+        .get("content-encoding")
+        .map(|value| {
+            value
+                .to_str()
+                .map(|s| s.trim().to_ascii_lowercase())
+                .map_err(|e| RenderError::UnsupportedEncoding(e.to_string()))
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    // Function URLs base64-encode bodies with binary content types, which
+    // includes every compressed body
+    let raw = if request.is_base64_encoded {
+        BASE64
+            .decode(body)
+            .map_err(|e| RenderError::BodyDecodeError(format!("Invalid base64: {}", e)))?
+    } else {
+        body.as_bytes().to_vec()
+    };
+
+    if encoding.is_empty() || encoding == "identity" {
+        return String::from_utf8(raw)
+            .map_err(|e| RenderError::BodyDecodeError(format!("Body is not valid UTF-8: {}", e)));
+    }
+
+    let decoder: Box<dyn Read + '_> = match encoding.as_str() {
+        "gzip" | "x-gzip" => Box::new(flate2::read::GzDecoder::new(raw.as_slice())),
+        "zstd" => Box::new(
+            zstd::stream::read::Decoder::new(raw.as_slice())
+                .map_err(|e| RenderError::BodyDecodeError(format!("Invalid zstd data: {}", e)))?,
+        ),
+        other => return Err(RenderError::UnsupportedEncoding(other.to_string())),
+    };
+
+    // Read at most one byte past the limit so oversized bodies are rejected
+    // without inflating them fully
+    let mut decompressed = Vec::new();
+    decoder
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| RenderError::BodyDecodeError(format!("Invalid {} data: {}", encoding, e)))?;
+    if decompressed.len() > max_bytes {
+        return Err(RenderError::PayloadTooLarge(format!(
+            "decompressed body exceeds the limit of {} bytes",
+            max_bytes
+        )));
+    }
+
+    String::from_utf8(decompressed)
+        .map_err(|e| RenderError::BodyDecodeError(format!("Body is not valid UTF-8: {}", e)))
+}
+
+// Check the x-api-key header against the configured API key. The keys are
+// compared as SHA-256 digests in constant time, so response times reveal
+// neither how much of a guess matched nor the key's length.
+fn authorize(request: &LambdaFunctionUrlRequest, api_key: Option<&str>) -> Result<(), RenderError> {
+    let Some(expected) = api_key else {
+        return Ok(());
+    };
+    let matches = |provided: &[u8]| {
+        let provided = Sha256::digest(provided);
+        let expected = Sha256::digest(expected.as_bytes());
+        bool::from(provided[..].ct_eq(&expected[..]))
+    };
+    match request.headers.get("x-api-key").map(|v| v.as_bytes()) {
+        Some(provided) if matches(provided) => Ok(()),
+        Some(_) => Err(RenderError::Unauthorized("Invalid API key".to_string())),
+        None => Err(RenderError::Unauthorized(
+            "Missing x-api-key header".to_string(),
+        )),
+    }
+}
+
+// Share of the remaining invocation time, less the safety margin, for each of
+// the jobs still to render. Jobs finishing early leave more time for later ones.
+fn job_time_budget(
+    deadline: SystemTime,
+    safety_margin: Duration,
+    remaining_jobs: usize,
+) -> Duration {
+    let available = deadline
+        .duration_since(SystemTime::now())
+        .unwrap_or_default()
+        .saturating_sub(safety_margin);
+    available / remaining_jobs.max(1) as u32
+}
+
+// Whether the remaining invocation time has dropped below the safety margin
+fn deadline_reached(deadline: SystemTime, safety_margin: Duration) -> bool {
+    deadline
+        .duration_since(SystemTime::now())
+        .map_or(true, |remaining| remaining <= safety_margin)
+}
+
+// Read an optional environment variable, falling back to a default when unset or invalid
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+// S3 client for the loaded config. S3-compatible local endpoints don't resolve
+// virtual-hosted bucket names, so an endpoint override switches to path-style.
+fn build_s3_client(config: &aws_config::SdkConfig) -> aws_sdk_s3::Client {
+    let s3_config = aws_sdk_s3::config::Builder::from(config)
+        .force_path_style(config.endpoint_url().is_some())
+        .build();
+    aws_sdk_s3::Client::from_conf(s3_config)
+}
+
 // Initialize resources asynchronously
 async fn initialize_resources() -> Arc<SharedResources> {
     // Read environment variables
-    let templates_bucket =
-        env::var("TEMPLATES_BUCKET").expect("TEMPLATES_BUCKET environment variable not set");
-    let results_bucket =
-        env::var("RESULTS_BUCKET").expect("RESULTS_BUCKET environment variable not set");
+    let rate_limiter = match env::var("TEMPLATE_RATE_LIMITS") {
+        Ok(config) if !config.is_empty() => TemplateRateLimiter::from_json(&config)
+            .expect("TEMPLATE_RATE_LIMITS must be a JSON object of template_id to limit"),
+        _ => TemplateRateLimiter::default(),
+    };
+
+    let template_permits = match env::var("TEMPLATE_CONCURRENCY") {
+        Ok(config) if !config.is_empty() => TemplatePermits::from_json(&config)
+            .expect("TEMPLATE_CONCURRENCY must be a JSON object of template_id to permit count"),
+        _ => TemplatePermits::default(),
+    };
+
+    let deadline_safety_margin = Duration::from_millis(env_or("DEADLINE_SAFETY_MARGIN_MS", 10_000));
+
+    let result_cache = match env_or("RESULT_CACHE_MAX_BYTES", 0) {
+        0 => None,
+        max_bytes => Some(ResultCache::new(
+            max_bytes,
+            Duration::from_secs(env_or("RESULT_CACHE_TTL_SECS", 300)),
+        )),
+    };
+
+    let template_disk_cache = match env_or("TEMPLATE_DISK_CACHE_MAX_BYTES", 0) {
+        0 => None,
+        max_bytes => Some(DiskCache::new(
+            env::var("TEMPLATE_DISK_CACHE_DIR").unwrap_or_else(|_| "/tmp/templates".to_string()),
+            max_bytes,
+            Duration::from_secs(env_or("TEMPLATE_DISK_CACHE_TTL_SECS", 3600)),
+        )),
+    };
+
+    let api_key = env::var("API_KEY").ok().filter(|s| !s.is_empty());
+    let max_request_body_bytes = env_or("MAX_REQUEST_BODY_BYTES", 6 * 1024 * 1024);
+    let max_template_bytes = env_or("MAX_TEMPLATE_BYTES", 10 * 1024 * 1024);
+    let data_ref_allowlist =
+        data_refs::parse_allowlist(&env::var("DATA_REF_ALLOWLIST").unwrap_or_default())
+            .expect("DATA_REF_ALLOWLIST must be a comma-separated list of s3:// and https:// URLs");
+    // Rendering is CPU bound, so by default allow one render per available core
+    let default_render_permits = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let render_permits = env_or("GLOBAL_RENDER_PERMITS", default_render_permits).max(1);
+
+    let result_settings = ResultObjectSettings {
+        cache_control: env::var("RESULT_CACHE_CONTROL")
+            .ok()
+            .filter(|s| !s.is_empty()),
+        expires_after: env::var("RESULT_EXPIRES_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs),
+    };
+
+    let s3_trigger = S3TriggerSettings {
+        data_prefix: env::var("S3_TRIGGER_DATA_PREFIX").unwrap_or_else(|_| "incoming/".to_string()),
+        results_prefix: env::var("S3_TRIGGER_RESULTS_PREFIX")
+            .unwrap_or_else(|_| "rendered/".to_string()),
+    };
+
+    let upload_retry = RetryPolicy {
+        max_attempts: env_or("UPLOAD_MAX_ATTEMPTS", 3).max(1),
+        base_delay: Duration::from_millis(env_or("UPLOAD_RETRY_BASE_DELAY_MS", 100)),
+    };
 
-    // Initialize AWS client
-    let config = aws_config::defaults(aws_config::BehaviorVersion::latest()).load().await;
-    let s3_client = aws_sdk_s3::Client::new(&config);
+    // Initialize AWS client, optionally against another region or endpoint (e.g. LocalStack)
+    let region = env::var("AWS_REGION").ok().filter(|s| !s.is_empty());
+    let endpoint_url = env::var("AWS_ENDPOINT_URL").ok().filter(|s| !s.is_empty());
+    let mut config_loader = aws_config::defaults(aws_config::BehaviorVersion::latest());
+    if let Some(region) = region {
+        config_loader = config_loader.region(aws_config::Region::new(region));
+    }
+    if let Some(endpoint_url) = &endpoint_url {
+        config_loader = config_loader.endpoint_url(endpoint_url);
+    }
+    let config = config_loader.load().await;
+    let s3_client = build_s3_client(&config);
+
+    // Uploads of replicated batches are copied to a bucket in the DR region
+    let replica_results: Option<Arc<dyn ObjectStore>> = env::var("DR_RESULTS_BUCKET")
+        .ok()
+        .filter(|s| !s.is_empty())
+        .map(|bucket| {
+            let dr_region = env::var("DR_REGION")
+                .expect("DR_REGION environment variable not set, required by DR_RESULTS_BUCKET");
+            let dr_config = config
+                .to_builder()
+                .region(aws_config::Region::new(dr_region))
+                .build();
+            let store: Arc<dyn ObjectStore> = Arc::new(
+                S3Store::new(build_s3_client(&dr_config), bucket).with_multipart_threshold(env_or(
+                    "MULTIPART_THRESHOLD_BYTES",
+                    16 * 1024 * 1024,
+                )),
+            );
+            store
+        });
+
+    let data_ref_fetcher = DataRefFetcher::new(
+        s3_client.clone(),
+        data_ref_allowlist,
+        max_request_body_bytes,
+        Duration::from_millis(env_or("DATA_REF_TIMEOUT_MS", 5_000)),
+    )
+    .expect("Failed to build the data_refs HTTP client");
+
+    let (templates, results, tenant_results, output_buckets): (
+        Arc<dyn ObjectStore>,
+        Arc<dyn ObjectStore>,
+        NamedStores,
+        NamedStores,
+    ) = match env::var("STORAGE_BACKEND").as_deref() {
+        Ok("memory") => {
+            let templates_dir =
+                env::var("LOCAL_TEMPLATES_DIR").unwrap_or_else(|_| "templates".to_string());
+            let templates = InMemoryStore::from_dir(&templates_dir)
+                .expect("Failed to load templates from LOCAL_TEMPLATES_DIR");
+            (
+                Arc::new(templates),
+                Arc::new(InMemoryStore::default()),
+                HashMap::new(),
+                HashMap::new(),
+            )
+        }
+        _ => {
+            let templates_bucket = env::var("TEMPLATES_BUCKET")
+                .expect("TEMPLATES_BUCKET environment variable not set");
+            let results_bucket =
+                env::var("RESULTS_BUCKET").expect("RESULTS_BUCKET environment variable not set");
+            let multipart_threshold = env_or("MULTIPART_THRESHOLD_BYTES", 16 * 1024 * 1024);
+
+            // Clean up after crashed invocations in the background, so it
+            // doesn't add to the cold start
+            if let Some(max_age) = env::var("MULTIPART_CLEANUP_AGE_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+            {
+                let store = S3Store::new(s3_client.clone(), results_bucket.clone());
+                tokio::spawn(async move {
+                    match store
+                        .abort_stale_multipart_uploads(Duration::from_secs(max_age))
+                        .await
+                    {
+                        Ok(aborted) => info!("Aborted {} stale multipart uploads", aborted),
+                        Err(e) => warn!("Failed to clean up stale multipart uploads: {}", e),
+                    }
+                });
+            }
+            let results_store = |bucket: String| -> Arc<dyn ObjectStore> {
+                Arc::new(
+                    S3Store::new(s3_client.clone(), bucket)
+                        .with_multipart_threshold(multipart_threshold),
+                )
+            };
+
+            let tenant_buckets: HashMap<String, String> = match env::var("TENANT_RESULTS_BUCKETS") {
+                Ok(config) if !config.is_empty() => serde_json::from_str(&config)
+                    .expect("TENANT_RESULTS_BUCKETS must be a JSON object of tenant_id to bucket"),
+                _ => HashMap::new(),
+            };
+            let tenant_results = tenant_buckets
+                .into_iter()
+                .map(|(tenant_id, bucket)| (tenant_id, results_store(bucket)))
+                .collect();
+            let output_buckets = env::var("OUTPUT_BUCKET_ALLOWLIST")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|bucket| !bucket.is_empty())
+                .map(|bucket| (bucket.to_string(), results_store(bucket.to_string())))
+                .collect();
+
+            (
+                Arc::new(S3Store::new(s3_client.clone(), templates_bucket)),
+                results_store(results_bucket),
+                tenant_results,
+                output_buckets,
+            )
+        }
+    };
+
+    let data_objects: Arc<dyn ObjectStore> = match env::var("DATA_BUCKET") {
+        Ok(bucket) if !bucket.is_empty() => Arc::new(S3Store::new(s3_client.clone(), bucket)),
+        _ => Arc::clone(&templates),
+    };
 
     // Create and return resources
     Arc::new(SharedResources {
         s3_client,
-        templates_bucket,
-        results_bucket,
+        templates,
+        results,
+        tenant_results,
+        data_objects,
+        replica_results,
+        output_buckets,
         template_cache: RwLock::new(HashMap::new()),
+        template_cache_hits: AtomicU64::new(0),
+        template_cache_misses: AtomicU64::new(0),
+        template_cache_evictions: AtomicU64::new(0),
+        defaults_cache: RwLock::new(HashMap::new()),
+        transform_cache: RwLock::new(HashMap::new()),
+        rate_limiter,
+        deadline_safety_margin,
+        result_cache,
+        template_disk_cache,
+        api_key,
+        max_request_body_bytes,
+        response_gzip_min_bytes: env_or("RESPONSE_GZIP_MIN_BYTES", 1024),
+        max_template_bytes,
+        validate_output_pdf: env_or("VALIDATE_OUTPUT_PDF", false),
+        result_settings,
+        upload_retry,
+        batch_manifest_prefix: env::var("BATCH_MANIFEST_PREFIX").ok(),
+        s3_trigger,
+        in_flight: InFlightLimiter::new(env_or("MAX_IN_FLIGHT", usize::MAX).max(1)),
+        render_permits: Arc::new(Semaphore::new(render_permits)),
+        template_permits,
+        upload_permits: Arc::new(Semaphore::new(
+            env_or("GLOBAL_UPLOAD_PERMITS", 32usize).max(1),
+        )),
+        pipeline_uploads: env_or("PIPELINE_UPLOADS", false),
+        allow_empty_batch: env_or("ALLOW_EMPTY_BATCH", true),
+        data_ref_fetcher,
     })
 }
 
-#[instrument(skip(event), fields(batch_size))]
-async fn function_handler(event: LambdaEvent<LambdaFunctionUrlRequest>) -> Result<Value, Error> {
-    // Parse request body
-    let body = event
-        .payload
-        .body
-        .ok_or_else(|| Error::from("Missing request body"))?;
-    let request: RenderRequest = serde_json::from_str(&body).map_err(|e| {
-        error!("Error parsing request body: {}", e);
-        Error::from(format!("Invalid request format: {}", e))
-    })?;
+// Template id of a job that may not parse as a RenderJobRequest
+fn job_template_id(job: &Value) -> String {
+    job.get("template_id")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string()
+}
+
+// Priority of an unparsed job, read before the render loop orders the batch
+fn job_priority(job: &Value) -> i64 {
+    job.get("priority").and_then(Value::as_i64).unwrap_or(0)
+}
+
+// Fetch and compile each distinct template of the batch once, concurrently, so
+// the render loop doesn't wait on S3. Failures are left for the jobs to report.
+async fn prefetch_templates(resources: &Arc<SharedResources>, jobs: &[Value]) {
+    let template_ids: HashSet<String> = jobs
+        .iter()
+        .map(job_template_id)
+        .filter(|template_id| !template_id.is_empty())
+        .collect();
+    let prefetch_span =
+        tracing::info_span!("template_prefetch", template_count = template_ids.len());
+
+    let tasks = template_ids.into_iter().map(|template_id| {
+        let resources = Arc::clone(resources);
+        tokio::spawn(
+            async move {
+                if let Err(e) = get_cached_template(&resources, &template_id).await {
+                    warn!("Failed to prefetch template {}: {}", template_id, e);
+                }
+            }
+            .instrument(prefetch_span.clone()),
+        )
+    });
+    futures::future::join_all(tasks)
+        .instrument(prefetch_span)
+        .await;
+}
+
+// Replicated batches need a DR bucket, and aren't supported when combining
+fn check_replicate(
+    resources: &SharedResources,
+    replicate: bool,
+    combine: bool,
+) -> Result<(), RenderError> {
+    if !replicate {
+        return Ok(());
+    }
+    if combine {
+        return Err(RenderError::InvalidRequest(
+            "replicate can't be used in combined batches".to_string(),
+        ));
+    }
+    if resources.replica_results.is_none() {
+        return Err(RenderError::InvalidRequest(
+            "replicate requires DR_RESULTS_BUCKET to be configured".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// PDF options don't apply to SVG jobs, and only PDFs can be combined
+fn check_format(job_request: &RenderJobRequest, combine: bool) -> Result<(), RenderError> {
+    if job_request.format != OutputFormat::Svg {
+        return Ok(());
+    }
+    if combine {
+        return Err(RenderError::InvalidRequest(
+            "svg jobs can't be used in combined batches".to_string(),
+        ));
+    }
+    if job_request.split_pages || job_request.metadata.is_some() {
+        return Err(RenderError::InvalidRequest(
+            "split_pages and metadata only apply to pdf jobs".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Store for a job's output_bucket, if it sets one and the bucket is allowed
+fn output_store(
+    resources: &SharedResources,
+    job_request: &RenderJobRequest,
+    combine: bool,
+) -> Result<Option<Arc<dyn ObjectStore>>, RenderError> {
+    let Some(bucket) = &job_request.output_bucket else {
+        return Ok(None);
+    };
+    if combine {
+        return Err(RenderError::InvalidRequest(
+            "output_bucket can't be used in combined batches".to_string(),
+        ));
+    }
+    match resources.output_buckets.get(bucket) {
+        Some(store) => Ok(Some(Arc::clone(store))),
+        None => Err(RenderError::InvalidRequest(format!(
+            "Output bucket {} is not in OUTPUT_BUCKET_ALLOWLIST",
+            bucket
+        ))),
+    }
+}
+
+// Base key of a job's outputs from the request's output_key_template, used in
+// place of the job id. A trailing ".pdf" is dropped since each output adds its own.
+fn output_key_base(
+    template: Option<&str>,
+    job_id: &str,
+    job_request: &RenderJobRequest,
+    combine: bool,
+) -> Result<Option<String>, RenderError> {
+    let Some(template) = template else {
+        return Ok(None);
+    };
+    if combine {
+        return Err(RenderError::InvalidRequest(
+            "output_key_template can't be used in combined batches".to_string(),
+        ));
+    }
+    let key = key_template::interpolate(
+        template,
+        job_id,
+        &job_request.template_id,
+        job_request.data.as_ref(),
+    )
+    .map_err(|e| RenderError::InvalidRequest(e.to_string()))?;
+    let key_base = key.strip_suffix(".pdf").unwrap_or(&key);
+    validate_key(&format!("{}.pdf", key_base))?;
+    Ok(Some(key_base.to_string()))
+}
+
+// A request without jobs does no work; with ALLOW_EMPTY_BATCH=false it is
+// refused, otherwise it is answered with batch_status "empty"
+fn check_empty_batch(
+    resources: &SharedResources,
+    request: &RenderRequest,
+) -> Result<(), RenderError> {
+    if request.jobs.is_empty() && !resources.allow_empty_batch {
+        return Err(RenderError::InvalidRequest(
+            "Request has no jobs".to_string(),
+        ));
+    }
+    Ok(())
+}
 
-    // Get the shared resources
-    let resources = RESOURCES.get().expect("Resources not initialized");
+// Render and upload a batch of jobs, stopping early if the deadline approaches
+async fn process_batch(
+    resources: &Arc<SharedResources>,
+    request: RenderRequest,
+    deadline: SystemTime,
+    request_id: String,
+    cancel: &CancellationToken,
+) -> BatchResponse {
+    let RenderRequest {
+        jobs,
+        preserve_order,
+        combine,
+        on_combine_error,
+        on_conflict,
+        fingerprint_only,
+        tenant_id,
+        failure_threshold,
+        rollback,
+        output_key_template,
+        replicate,
+    } = request;
+    info!("Processing batch of {} jobs", jobs.len());
+    Span::current().record("batch_size", jobs.len());
 
-    info!("Processing batch of {} jobs", request.jobs.len());
-    Span::current().record("batch_size", request.jobs.len());
+    prefetch_templates(resources, &jobs).await;
+
+    let results_store = resources.results_store(tenant_id.as_deref());
+
+    // In pipelined mode rendered jobs are sent to an upload worker as they
+    // finish, so uploads overlap with the rest of the render phase
+    let (upload_tx, upload_worker) = if resources.pipeline_uploads && !combine && !fingerprint_only
+    {
+        let (upload_tx, mut upload_rx) = mpsc::unbounded_channel::<RenderedJob>();
+        let resources = Arc::clone(resources);
+        let results_store = Arc::clone(&results_store);
+        let upload_worker = tokio::spawn(
+            async move {
+                let mut uploads = Vec::new();
+                while let Some(rendered_job) = upload_rx.recv().await {
+                    uploads.push(spawn_upload(
+                        &resources,
+                        &results_store,
+                        replicate,
+                        on_conflict,
+                        rendered_job,
+                    ));
+                }
+                uploads
+            }
+            .instrument(tracing::info_span!("upload_pipeline")),
+        );
+        (Some(upload_tx), Some(upload_worker))
+    } else {
+        (None, None)
+    };
 
     // Step 1: Render all PDFs sequentially (maintains proper tracing)
     let render_span = tracing::info_span!("render_phase");
     let mut rendered_jobs = Vec::new();
-    let mut failed_jobs = Vec::new();
+    // Results are tagged with the job's position in the request
+    let mut failed_jobs: Vec<(usize, JobResult)> = Vec::new();
+    let mut timed_out_jobs = Vec::new();
+    let mut cancelled_jobs = Vec::new();
 
     {
         let _enter = render_span.enter();
-        for job_request in request.jobs {
-            let job_id = Uuid::new_v4().to_string();
+        let job_count = jobs.len();
+        let mut jobs: Vec<(usize, Value)> = jobs.into_iter().enumerate().collect();
+        // Higher priority jobs first, so they are rendered before the deadline
+        // cuts the batch short; the sort is stable, so ties keep request order
+        jobs.sort_by_key(|(_, job)| std::cmp::Reverse(job_priority(job)));
+        let mut jobs = jobs.into_iter();
+        // Whether cancellation rather than the deadline stopped the loop, as
+        // seen when it stopped; SIGTERM may still arrive afterwards
+        let mut stopped_by_cancel = false;
+        for (position, (index, job)) in jobs.by_ref().enumerate() {
+            if cancel.is_cancelled() {
+                cancelled_jobs.push((index, job));
+                stopped_by_cancel = true;
+                break;
+            }
+            // Stop starting new renders once we're close to the Lambda timeout,
+            // so the jobs rendered so far can still be uploaded and returned
+            if deadline_reached(deadline, resources.deadline_safety_margin) {
+                timed_out_jobs.push((index, job));
+                break;
+            }
+
+            // Jobs are validated one by one so a malformed job only fails itself
+            let job_request = match RenderJobRequest::deserialize(&job)
+                .map_err(|e| RenderError::JobParseError(parse_error::describe(&e)))
+                .and_then(RenderJobRequest::with_selected_template)
+            {
+                Ok(job_request) => job_request,
+                Err(e) => {
+                    let template_id = job_template_id(&job);
+                    error!("Job {} is invalid: {}", index, e);
+                    failed_jobs.push((
+                        index,
+                        JobResult::failure(
+                            job_id::new_job_id(&template_id),
+                            template_id,
+                            "error",
+                            e.error_code(),
+                            e.to_string(),
+                        ),
+                    ));
+                    continue;
+                }
+            };
+
+            let job_id = job_id::new_job_id(&job_request.template_id);
+
+            // Checked before rendering so a refused bucket or key doesn't cost a render
+            let output_target = check_replicate(resources, replicate, combine)
+                .and_then(|()| check_format(&job_request, combine))
+                .and_then(|()| output_store(resources, &job_request, combine))
+                .and_then(|store| {
+                    let key_base = output_key_base(
+                        output_key_template.as_deref(),
+                        &job_id,
+                        &job_request,
+                        combine,
+                    )?;
+                    Ok((store, key_base))
+                });
+            let (output_store, output_key_base) = match output_target {
+                Ok(output_target) => output_target,
+                Err(e) => {
+                    error!("Job {} is invalid: {}", job_id, e);
+                    failed_jobs.push((
+                        index,
+                        JobResult::failure(
+                            job_id,
+                            job_request.template_id,
+                            "error",
+                            e.error_code(),
+                            e.to_string(),
+                        ),
+                    ));
+                    continue;
+                }
+            };
 
             let job_span = tracing::info_span!(
                 "render_job",
                 job_id = %job_id,
-                template_id = %job_request.template_id
+                template_id = %job_request.template_id,
+                priority = job_request.priority
             );
             let _enter = job_span.enter();
 
@@ -281,137 +2391,1483 @@ async fn function_handler(event: LambdaEvent<LambdaFunctionUrlRequest>) -> Resul
                 job_id, job_request.template_id
             );
 
-            match render_pdf(&resources, &job_id, &job_request).await {
-                Ok((s3_key, pdf_data)) => {
-                    rendered_jobs.push((job_id, job_request.template_id.clone(), s3_key, pdf_data));
+            // A timed out render keeps its blocking thread until papermake
+            // returns, but the batch moves on to the next job
+            let budget = job_time_budget(
+                deadline,
+                resources.deadline_safety_margin,
+                job_count - position,
+            );
+            let render = tokio::time::timeout(budget, render_pdf(resources, &job_id, &job_request));
+            let render_result = tokio::select! {
+                result = render => result.unwrap_or_else(|_| {
+                    Err(RenderError::RenderTimeout {
+                        budget_ms: budget.as_millis().try_into().unwrap_or(u64::MAX),
+                    })
+                }),
+                // Abandoned like a timed out render
+                () = cancel.cancelled() => Err(RenderError::Cancelled),
+            };
+            match render_result {
+                Ok(mut job_outputs) => {
+                    if job_request.store_request {
+                        let record =
+                            request_record(resources, &job, &job_id, &job_request.template_id)
+                                .await;
+                        job_outputs.request_record =
+                            Some((format!("{}{}", job_id, REQUEST_RECORD_SUFFIX), record));
+                    }
+                    let job_outputs = match &output_key_base {
+                        Some(key_base) => job_outputs.rekeyed(&job_id, key_base),
+                        None => job_outputs,
+                    };
+                    let rendered_job = RenderedJob {
+                        index,
+                        job_id,
+                        template_id: job_request.template_id,
+                        outputs: job_outputs.outputs,
+                        multi_output: job_request.split_pages
+                            || job_request.data_array.is_some()
+                            || job_request.format == OutputFormat::Svg,
+                        output_store,
+                        thumbnail: job_outputs.thumbnail,
+                        request_record: job_outputs.request_record,
+                        storage_class: job_request.storage_class,
+                        source: job_outputs.source,
+                    };
+                    match &upload_tx {
+                        // The worker only stops once upload_tx is dropped,
+                        // so sending can't fail
+                        Some(upload_tx) => {
+                            let _ = upload_tx.send(rendered_job);
+                        }
+                        None => rendered_jobs.push(rendered_job),
+                    }
                 }
                 Err(e) => {
                     error!("Job {} rendering failed: {}", job_id, e);
-                    failed_jobs.push(JobResult {
-                        job_id: job_id.clone(),
-                        template_id: job_request.template_id.clone(),
-                        status: "error".to_string(),
-                        s3_key: None,
-                        file_size: None,
-                        error: Some(e.to_string()),
-                    });
+                    let status = match e {
+                        RenderError::Cancelled => "cancelled",
+                        _ => "error",
+                    };
+                    failed_jobs.push((
+                        index,
+                        JobResult::failure(
+                            job_id,
+                            job_request.template_id,
+                            status,
+                            e.error_code(),
+                            e.to_string(),
+                        ),
+                    ));
                 }
             }
         }
+        if stopped_by_cancel {
+            cancelled_jobs.extend(jobs);
+        } else {
+            timed_out_jobs.extend(jobs);
+        }
     }
+    // Closes the channel, letting the upload worker finish
+    drop(upload_tx);
 
-    // Step 2: Upload all PDFs in parallel
-    let upload_span = tracing::info_span!("upload_phase", upload_count = rendered_jobs.len());
-    let mut upload_tasks = Vec::new();
-    let _enter = upload_span.enter();
-    {
-        for (job_id, template_id, s3_key, pdf_data) in rendered_jobs {
-            let resources = Arc::clone(&resources);
-            let task = tokio::spawn(async move {
-                match upload_pdf_to_s3(&resources, &job_id, &s3_key, pdf_data).await {
-                    Ok(file_size) => JobResult {
-                        job_id: job_id.clone(),
-                        template_id,
-                        status: "success".to_string(),
-                        s3_key: Some(s3_key),
-                        file_size: Some(file_size),
-                        error: None,
-                    },
-                    Err(e) => {
-                        error!("Job {} upload failed: {}", job_id, e);
-                        JobResult {
-                            job_id: job_id.clone(),
-                            template_id,
-                            status: "error".to_string(),
-                            s3_key: None,
-                            file_size: None,
-                            error: Some(e.to_string()),
-                        }
-                    }
-                }
-            });
-            upload_tasks.push(task);
+    if !timed_out_jobs.is_empty() {
+        warn!(
+            "Approaching Lambda deadline, {} jobs were not rendered",
+            timed_out_jobs.len()
+        );
+    }
+    if !cancelled_jobs.is_empty() {
+        warn!(
+            "Batch was cancelled, {} jobs were not rendered",
+            cancelled_jobs.len()
+        );
+    }
+    let not_started = |jobs: Vec<(usize, Value)>, status: &str, error: &str| {
+        jobs.into_iter()
+            .map(|(index, job)| {
+                let template_id = job_template_id(&job);
+                let result = JobResult::failure(
+                    job_id::new_job_id(&template_id),
+                    template_id,
+                    status,
+                    status,
+                    error.to_string(),
+                );
+                (index, result)
+            })
+            .collect::<Vec<_>>()
+    };
+    // Both count as unfinished rather than failed jobs
+    let mut timed_out_jobs = not_started(
+        timed_out_jobs,
+        "timed_out",
+        "Job was not started before the Lambda deadline",
+    );
+    timed_out_jobs.extend(not_started(
+        cancelled_jobs,
+        "cancelled",
+        "Job was not started before the batch was cancelled",
+    ));
+
+    let mut results = failed_jobs;
+    let mut combined_s3_key = None;
+
+    if let Some(upload_worker) = upload_worker {
+        match upload_worker.await {
+            Ok(uploads) => results.extend(join_uploads(uploads).await),
+            Err(e) => error!("Upload worker panicked: {}", e),
         }
+    } else if fingerprint_only {
+        results.extend(
+            rendered_jobs
+                .into_iter()
+                .map(|job| (job.index, fingerprint_rendered_job(job))),
+        );
+    } else if combine {
+        let failures: Vec<&(usize, JobResult)> =
+            results.iter().chain(timed_out_jobs.iter()).collect();
+        let (combined_results, s3_key) = combine_rendered_jobs(
+            resources,
+            results_store.as_ref(),
+            rendered_jobs,
+            &failures,
+            on_combine_error,
+            on_conflict,
+        )
+        .await;
+        results.extend(combined_results);
+        combined_s3_key = s3_key;
+    } else {
+        // Step 2: Upload all PDFs in parallel, as far as the upload permits
+        // shared with other invocations allow
+        let upload_span = tracing::info_span!("upload_phase", upload_count = rendered_jobs.len());
+        let uploads: Vec<_> = rendered_jobs
+            .into_iter()
+            .map(|rendered_job| {
+                spawn_upload(
+                    resources,
+                    &results_store,
+                    replicate,
+                    on_conflict,
+                    rendered_job,
+                )
+            })
+            .collect();
+        results.extend(join_uploads(uploads).instrument(upload_span).await);
     }
 
-    // Wait for all uploads to complete
-    let upload_results = futures::future::join_all(upload_tasks).await;
-    drop(_enter);
+    results.extend(timed_out_jobs);
 
-    let failed_count_initial = failed_jobs.len();
-    let mut results = failed_jobs;
-    let mut success_count = 0;
-    let mut failed_count = failed_count_initial;
+    if preserve_order {
+        results.sort_by_key(|(index, _)| *index);
+    }
 
-    for result in upload_results {
-        match result {
-            Ok(job_result) => {
-                if job_result.status == "success" {
-                    success_count += 1;
-                } else {
-                    failed_count += 1;
-                }
-                results.push(job_result);
-            }
-            Err(e) => {
-                failed_count += 1;
-                error!("Upload task panicked: {}", e);
+    let unsuccessful = results
+        .iter()
+        .filter(|(_, result)| result.status != "success")
+        .count();
+    let batch_failed = failure_threshold.is_some_and(|threshold| {
+        !results.is_empty() && unsuccessful as f64 / results.len() as f64 > threshold
+    });
+    if batch_failed {
+        warn!(
+            "{} of {} jobs failed, over the failure threshold",
+            unsuccessful,
+            results.len()
+        );
+        // Fingerprint-only batches have nothing uploaded to roll back
+        if rollback && !fingerprint_only {
+            roll_back_uploads(resources, results_store.as_ref(), &mut results).await;
+            if combined_s3_key.is_some()
+                && results.iter().all(|(_, result)| result.status != "success")
+            {
+                combined_s3_key = None;
             }
         }
     }
 
+    let count_status = |status: &str| {
+        results
+            .iter()
+            .filter(|(_, result)| result.status == status)
+            .count()
+    };
+    let success_count = count_status("success");
+    let timed_out_count = count_status("timed_out");
+    let cancelled_count = count_status("cancelled");
+    let rolled_back_count = count_status("rolled_back");
+    let failed_count =
+        results.len() - success_count - timed_out_count - cancelled_count - rolled_back_count;
+
     // Create response
-    let response = BatchResponse {
-        results,
+    let results_len = results.len();
+    let mut response = BatchResponse {
+        request_id,
+        results: results.into_iter().map(|(_, result)| result).collect(),
+        combined_s3_key,
+        manifest_s3_key: None,
         summary: BatchSummary {
-            total: success_count + failed_count,
+            batch_status: if results_len == 0 {
+                "empty"
+            } else if batch_failed {
+                "failed"
+            } else {
+                "success"
+            },
+            total: results_len,
             success: success_count,
             failed: failed_count,
+            timed_out: timed_out_count,
+            cancelled: cancelled_count,
+            rolled_back: rolled_back_count,
         },
     };
 
     info!(
-        "Batch processing complete: {} total, {} success, {} failed",
-        response.summary.total, response.summary.success, response.summary.failed
+        "Batch processing complete: {} total, {} success, {} failed, {} timed out",
+        response.summary.total,
+        response.summary.success,
+        response.summary.failed,
+        response.summary.timed_out
     );
 
-    Ok(json!(response))
+    // Fingerprint-only batches don't write anything to the results bucket
+    if let Some(prefix) = &resources.batch_manifest_prefix {
+        if !fingerprint_only {
+            write_batch_manifest(resources, results_store.as_ref(), prefix, &mut response).await;
+        }
+    }
+
+    response
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
-    // Initialize OpenTelemetry if OTLP_ENDPOINT is configured
-    let (telemetry_layer, tracer_provider) = match env::var("OTLP_ENDPOINT")
-        .ok()
-        .filter(|s| !s.is_empty())
-    {
-        Some(otlp_endpoint) => {
-            let exporter = opentelemetry_otlp::SpanExporter::builder()
-                .with_http()
-                .with_endpoint(otlp_endpoint)
-                .build()
-                .expect("Failed to create OTLP exporter");
-
-            let resource = Resource::builder()
-                .with_service_name("pdf-renderer-lambda")
-                .with_attribute(KeyValue::new("service.version", "0.1.0"))
-                .build();
+// A spawned upload of a rendered job, with the job's index, job_id and
+// template_id for reporting a panicked task
+type PendingUpload = ((usize, String, String), JoinHandle<JobResult>);
 
-            let provider = SdkTracerProvider::builder()
-                .with_simple_exporter(exporter)
-                .with_resource(resource)
-                .build();
+// Start uploading a rendered job once an upload permit is free
+fn spawn_upload(
+    resources: &Arc<SharedResources>,
+    results_store: &Arc<dyn ObjectStore>,
+    replicate: bool,
+    on_conflict: OnConflict,
+    rendered_job: RenderedJob,
+) -> PendingUpload {
+    let job = (
+        rendered_job.index,
+        rendered_job.job_id.clone(),
+        rendered_job.template_id.clone(),
+    );
+    let resources = Arc::clone(resources);
+    let results = rendered_job
+        .output_store
+        .clone()
+        .unwrap_or_else(|| Arc::clone(results_store));
+    let replica = resources.replica_results.clone().filter(|_| replicate);
+    let task = tokio::spawn(async move {
+        let _upload_permit = resources
+            .upload_permits
+            .acquire()
+            .instrument(tracing::info_span!("upload_permit_wait"))
+            .await;
+        upload_rendered_job(
+            &resources,
+            results.as_ref(),
+            replica.as_deref(),
+            rendered_job,
+            on_conflict,
+        )
+        .await
+    });
+    (job, task)
+}
 
-            let tracer = provider.tracer("pdf-renderer-lambda");
-            global::set_tracer_provider(provider.clone());
+// Wait for all uploads to complete
+async fn join_uploads(uploads: Vec<PendingUpload>) -> Vec<(usize, JobResult)> {
+    let (upload_jobs, upload_tasks): (Vec<_>, Vec<_>) = uploads.into_iter().unzip();
+    let upload_results = futures::future::join_all(upload_tasks).await;
+    upload_jobs
+        .into_iter()
+        .zip(upload_results)
+        .map(|((index, job_id, template_id), result)| match result {
+            Ok(job_result) => (index, job_result),
+            Err(e) => {
+                error!("Upload task panicked: {}", e);
+                (
+                    index,
+                    JobResult::failure(
+                        job_id,
+                        template_id,
+                        "error",
+                        "upload_panic",
+                        format!("Upload task failed: {}", e),
+                    ),
+                )
+            }
+        })
+        .collect()
+}
 
-            (
-                Some(tracing_opentelemetry::layer().with_tracer(tracer)),
-                Some(provider),
+// Store the batch response as {prefix}{request_id}/manifest.json, a durable
+// record of the batch in case the response never reaches the caller. Failures
+// are logged and leave manifest_s3_key unset.
+async fn write_batch_manifest(
+    resources: &SharedResources,
+    results: &dyn ObjectStore,
+    prefix: &str,
+    response: &mut BatchResponse,
+) {
+    let manifest_key = format!("{}{}/manifest.json", prefix, response.request_id);
+    if let Err(e) = validate_key(&manifest_key) {
+        error!("Not writing batch manifest: {}", e);
+        return;
+    }
+
+    response.manifest_s3_key = Some(manifest_key.clone());
+    let manifest = match serde_json::to_vec(&*response) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            error!("Failed to serialize batch manifest: {}", e);
+            response.manifest_s3_key = None;
+            return;
+        }
+    };
+
+    let manifest_span = tracing::info_span!(
+        "s3_batch_manifest_upload",
+        bucket = %results.bucket(),
+        key = %manifest_key,
+        bytes = manifest.len()
+    );
+    let opts = PutOptions {
+        content_type: Some("application/json".to_string()),
+        ..Default::default()
+    };
+    let (result, _) = retry::with_retries(
+        &resources.upload_retry,
+        |e| matches!(e, StoreError::Backend(_)),
+        || results.put(&manifest_key, manifest.clone(), opts.clone()),
+    )
+    .instrument(manifest_span)
+    .await;
+    match result {
+        Ok(()) => info!("Wrote batch manifest to {}", manifest_key),
+        Err(e) => {
+            error!("Failed to upload batch manifest {}: {}", manifest_key, e);
+            response.manifest_s3_key = None;
+        }
+    }
+}
+
+// Delete every object uploaded for the successful jobs and mark them
+// rolled_back. Jobs whose objects couldn't all be deleted keep their status.
+async fn roll_back_uploads(
+    resources: &SharedResources,
+    results_store: &dyn ObjectStore,
+    results: &mut [(usize, JobResult)],
+) {
+    // Replica copies are deleted too, so the DR bucket doesn't keep outputs
+    // of a rolled back batch
+    let uploaded_keys = |result: &JobResult| -> Vec<(String, String)> {
+        let keys: Vec<&String> = result
+            .s3_key
+            .iter()
+            .chain(result.s3_keys.iter().flatten())
+            .chain(result.thumbnail_s3_key.iter())
+            .chain(result.request_s3_key.iter())
+            .collect();
+        result
+            .bucket
+            .iter()
+            .chain(result.replica_bucket.iter())
+            .flat_map(|bucket| keys.iter().map(|key| (bucket.clone(), key.to_string())))
+            .collect()
+    };
+
+    // Jobs of a combined batch share one object, so delete each key once
+    let objects: HashSet<(String, String)> = results
+        .iter()
+        .filter(|(_, result)| result.status == "success")
+        .flat_map(|(_, result)| uploaded_keys(result))
+        .collect();
+    let deletions = objects.into_iter().map(|(bucket, key)| async move {
+        let replica = resources
+            .replica_results
+            .as_deref()
+            .filter(|replica| replica.bucket() == bucket);
+        let store = if bucket == results_store.bucket() {
+            Some(results_store)
+        } else {
+            replica.or_else(|| {
+                resources
+                    .output_buckets
+                    .get(&bucket)
+                    .map(|store| store.as_ref())
+            })
+        };
+        let deleted = match store {
+            Some(store) => match store.delete(&key).await {
+                Ok(()) => true,
+                Err(e) => {
+                    error!("Failed to roll back {} in {}: {}", key, bucket, e);
+                    false
+                }
+            },
+            None => false,
+        };
+        ((bucket, key), deleted)
+    });
+    let deleted: HashMap<(String, String), bool> = futures::future::join_all(deletions)
+        .await
+        .into_iter()
+        .collect();
+
+    for (_, result) in results.iter_mut() {
+        if result.status != "success" {
+            continue;
+        }
+        let rolled_back = uploaded_keys(result)
+            .iter()
+            .all(|object| deleted.get(object).copied().unwrap_or(false));
+        if rolled_back {
+            result.status = "rolled_back".to_string();
+            result.bucket = None;
+            result.replica_bucket = None;
+            result.s3_key = None;
+            result.s3_keys = None;
+            result.thumbnail_s3_key = None;
+            result.request_s3_key = None;
+            result.error_code = Some("batch_failed".to_string());
+            result.error =
+                Some("Upload deleted because the batch exceeded its failure threshold".to_string());
+        }
+    }
+}
+
+// Pages across all outputs, or None if any output can't be parsed
+fn total_page_count(outputs: &[(String, Vec<u8>)]) -> Option<usize> {
+    outputs
+        .iter()
+        .map(|(key, data)| {
+            // Each SVG output is a single page
+            if key.ends_with(".svg") {
+                Some(1)
+            } else {
+                pdf::page_count(data).ok()
+            }
+        })
+        .sum()
+}
+
+// Result for a rendered job that is only fingerprinted, not uploaded
+fn fingerprint_rendered_job(rendered_job: RenderedJob) -> JobResult {
+    let mut hasher = Sha256::new();
+    let mut file_size = 0;
+    for (_, data) in &rendered_job.outputs {
+        hasher.update(data);
+        file_size += data.len() as u64;
+    }
+    let page_count = total_page_count(&rendered_job.outputs);
+
+    JobResult {
+        job_id: rendered_job.job_id,
+        template_id: rendered_job.template_id,
+        status: "success".to_string(),
+        attempts: 0,
+        bucket: None,
+        replica_bucket: None,
+        s3_key: None,
+        s3_keys: None,
+        file_size: Some(file_size),
+        sha256: Some(hex::encode(hasher.finalize())),
+        page_count,
+        thumbnail_s3_key: None,
+        request_s3_key: None,
+        source: Some(rendered_job.source),
+        error_code: None,
+        error: None,
+    }
+}
+
+// Merge the rendered jobs and any error pages into one PDF and upload it.
+// Every rendered job's result points at the combined object.
+async fn combine_rendered_jobs(
+    resources: &SharedResources,
+    results: &dyn ObjectStore,
+    rendered_jobs: Vec<RenderedJob>,
+    failures: &[&(usize, JobResult)],
+    on_error: CombineErrorMode,
+    on_conflict: OnConflict,
+) -> (Vec<(usize, JobResult)>, Option<String>) {
+    let fail_all = |rendered_jobs: Vec<RenderedJob>, error: &RenderError| {
+        rendered_jobs
+            .into_iter()
+            .map(|job| {
+                let result = JobResult::failure(
+                    job.job_id,
+                    job.template_id,
+                    "error",
+                    error.error_code(),
+                    error.to_string(),
+                );
+                (job.index, result)
+            })
+            .collect()
+    };
+
+    if on_error == CombineErrorMode::Abort && !failures.is_empty() {
+        let error = RenderError::CombineAborted(format!("{} jobs failed", failures.len()));
+        return (fail_all(rendered_jobs, &error), None);
+    }
+
+    let combine_span = tracing::info_span!("pdf_combine", document_count = rendered_jobs.len());
+    let combined = {
+        let _enter = combine_span.enter();
+        let mut parts: Vec<(usize, Vec<u8>)> = Vec::new();
+        for job in &rendered_jobs {
+            parts.extend(
+                job.outputs
+                    .iter()
+                    .map(|(_, data)| (job.index, data.clone())),
+            );
+        }
+        for (index, result) in failures {
+            let title = format!("Job {} ({}) failed", index, result.template_id);
+            let message = result.error.as_deref().unwrap_or_default();
+            match pdf::error_page(&title, message) {
+                Ok(page) => parts.push((*index, page)),
+                Err(e) => {
+                    let error = RenderError::PdfProcessingError(format!(
+                        "Failed to create error page: {}",
+                        e
+                    ));
+                    return (fail_all(rendered_jobs, &error), None);
+                }
+            }
+        }
+        // Stable sort keeps a job's own outputs in order
+        parts.sort_by_key(|(index, _)| *index);
+        let parts: Vec<Vec<u8>> = parts.into_iter().map(|(_, data)| data).collect();
+        pdf::merge(&parts)
+    };
+    let combined = match combined {
+        Ok(combined) => combined,
+        Err(e) => {
+            let error = RenderError::PdfProcessingError(format!("Failed to combine PDFs: {}", e));
+            return (fail_all(rendered_jobs, &error), None);
+        }
+    };
+
+    let combined_id = job_id::new_job_id("combined");
+    let s3_key = format!("{}.pdf", combined_id);
+    // The combined PDF mixes jobs, so it keeps the default storage class
+    let (upload_result, attempts) = upload_pdf_to_s3(
+        results,
+        resources
+            .result_settings
+            .put_options("application/pdf", None),
+        &resources.upload_retry,
+        on_conflict,
+        &combined_id,
+        &s3_key,
+        combined,
+    )
+    .await;
+    let file_size = match upload_result {
+        Ok(upload) => upload.size(),
+        Err(e) => {
+            error!("Combined PDF {} upload failed: {}", combined_id, e);
+            let mut results: Vec<(usize, JobResult)> = fail_all(rendered_jobs, &e);
+            for (_, result) in &mut results {
+                result.attempts = attempts;
+            }
+            return (results, None);
+        }
+    };
+
+    let results = rendered_jobs
+        .into_iter()
+        .map(|job| {
+            let page_count = total_page_count(&job.outputs);
+            let result = JobResult {
+                job_id: job.job_id,
+                template_id: job.template_id,
+                status: "success".to_string(),
+                attempts,
+                bucket: Some(results.bucket().to_string()),
+                replica_bucket: None,
+                s3_key: Some(s3_key.clone()),
+                s3_keys: None,
+                file_size: Some(file_size),
+                sha256: None,
+                page_count,
+                thumbnail_s3_key: None,
+                request_s3_key: None,
+                source: Some(job.source),
+                error_code: None,
+                error: None,
+            };
+            (job.index, result)
+        })
+        .collect();
+    (results, Some(s3_key))
+}
+
+// Upload every output of a rendered job and build its result. Outputs are
+// copied to `replica` alongside; a failed copy is only logged.
+async fn upload_rendered_job(
+    resources: &SharedResources,
+    results: &dyn ObjectStore,
+    replica: Option<&dyn ObjectStore>,
+    rendered_job: RenderedJob,
+    on_conflict: OnConflict,
+) -> JobResult {
+    let RenderedJob {
+        job_id,
+        template_id,
+        outputs,
+        multi_output,
+        thumbnail,
+        request_record,
+        storage_class,
+        source,
+        ..
+    } = rendered_job;
+
+    let page_count = total_page_count(&outputs);
+    let mut s3_keys = Vec::with_capacity(outputs.len());
+    let mut thumbnail_s3_key = None;
+    let mut request_s3_key = None;
+    let mut total_size = 0;
+    let mut max_attempts = 1;
+    let mut replicated = replica.is_some();
+    let uploads = outputs
+        .into_iter()
+        .map(|output| (output, None))
+        .chain(thumbnail.map(|thumbnail| (thumbnail, Some(&mut thumbnail_s3_key))))
+        .chain(request_record.map(|record| (record, Some(&mut request_s3_key))));
+    // Keys this job wrote, removed again if a later output finds its key taken
+    let mut written_keys = Vec::new();
+    for ((s3_key, data), sidecar_key) in uploads {
+        let put_options = resources
+            .result_settings
+            .put_options(content_type(&s3_key), storage_class.clone());
+        let upload = |store, on_conflict, data| {
+            upload_pdf_to_s3(
+                store,
+                put_options.clone(),
+                &resources.upload_retry,
+                on_conflict,
+                &job_id,
+                &s3_key,
+                data,
             )
+        };
+        // Overwrites go to both stores at once. Otherwise the replica is only
+        // written once the primary write went through, so an object kept or
+        // refused in the results store isn't replaced in the replica either.
+        let ((result, attempts), replica_result) = match replica {
+            Some(replica) if on_conflict == OnConflict::Overwrite => {
+                let replica_upload = upload(replica, OnConflict::Overwrite, data.clone());
+                let (primary, (replica_result, _)) =
+                    tokio::join!(upload(results, on_conflict, data), replica_upload);
+                (primary, Some(replica_result))
+            }
+            Some(replica) => {
+                let primary = upload(results, on_conflict, data.clone()).await;
+                let replica_result = match primary.0 {
+                    Ok(Upload::Written(_)) => {
+                        Some(upload(replica, OnConflict::Overwrite, data).await.0)
+                    }
+                    _ => None,
+                };
+                (primary, replica_result)
+            }
+            None => (upload(results, on_conflict, data).await, None),
+        };
+        match replica_result {
+            Some(Ok(_)) => {}
+            Some(Err(e)) => {
+                warn!("Job {} replica upload of {} failed: {}", job_id, s3_key, e);
+                replicated = false;
+            }
+            None => replicated = false,
+        }
+        max_attempts = max_attempts.max(attempts);
+        match result {
+            // Sidecar objects are reported under their own key, not as outputs
+            Ok(upload) => {
+                if let Upload::Written(_) = upload {
+                    written_keys.push(s3_key.clone());
+                }
+                match sidecar_key {
+                    Some(sidecar_key) => *sidecar_key = Some(s3_key),
+                    None => {
+                        total_size += upload.size();
+                        s3_keys.push(s3_key);
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Job {} upload failed: {}", job_id, e);
+                // With on_conflict fail, a job's outputs are written all or not at all
+                if let RenderError::ResultExists(_) = e {
+                    delete_written_keys(&job_id, results, replica, &written_keys).await;
+                }
+                let mut result =
+                    JobResult::failure(job_id, template_id, "error", e.error_code(), e.to_string());
+                result.attempts = max_attempts;
+                return result;
+            }
+        }
+    }
+
+    let (s3_key, s3_keys) = if multi_output {
+        (None, Some(s3_keys))
+    } else {
+        (s3_keys.pop(), None)
+    };
+    JobResult {
+        job_id,
+        template_id,
+        status: "success".to_string(),
+        attempts: max_attempts,
+        bucket: Some(results.bucket().to_string()),
+        replica_bucket: replica
+            .filter(|_| replicated)
+            .map(|replica| replica.bucket().to_string()),
+        s3_key,
+        s3_keys,
+        file_size: Some(total_size),
+        sha256: None,
+        page_count,
+        thumbnail_s3_key,
+        request_s3_key,
+        source: Some(source),
+        error_code: None,
+        error: None,
+    }
+}
+
+// Delete the objects a failed job already wrote, from the replica too. Failures
+// are logged; the job is reported failed either way.
+async fn delete_written_keys(
+    job_id: &str,
+    results: &dyn ObjectStore,
+    replica: Option<&dyn ObjectStore>,
+    keys: &[String],
+) {
+    let stores = std::iter::once(results).chain(replica);
+    let deletions = stores.flat_map(|store| keys.iter().map(move |key| (store, key)));
+    for result in futures::future::join_all(deletions.map(|(store, key)| async move {
+        store
+            .delete(key)
+            .await
+            .map_err(|e| (store.bucket(), key, e))
+    }))
+    .await
+    {
+        if let Err((bucket, key, e)) = result {
+            warn!(
+                "Job {} failed to delete {} from {}: {}",
+                job_id, key, bucket, e
+            );
+        }
+    }
+}
+
+// Handle a render request sent through the Lambda function URL
+async fn handle_function_url(
+    resources: &Arc<SharedResources>,
+    request: LambdaFunctionUrlRequest,
+    deadline: SystemTime,
+    request_id: String,
+    cancel: &CancellationToken,
+) -> Result<Value, Error> {
+    let msgpack = accepts_msgpack(&request);
+    let gzip = accepts_gzip(&request);
+    match render_function_url_request(resources, request, deadline, request_id, cancel).await {
+        Ok(response) if msgpack || gzip => Ok(encoded_response(
+            &response,
+            msgpack,
+            gzip,
+            resources.response_gzip_min_bytes,
+        )?),
+        Ok(response) => Ok(response),
+        Err(e) => {
+            error!("Rejecting request: {}", e);
+            Ok(e.to_response())
+        }
+    }
+}
+
+// Longest X-Request-Id accepted from a caller
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// The caller's X-Request-Id, if it is a reasonable identifier to echo back.
+// The id names the batch manifest's directory, so it must be a valid key segment.
+fn client_request_id(request: &LambdaFunctionUrlRequest) -> Option<String> {
+    let request_id = request.headers.get("x-request-id")?.to_str().ok()?.trim();
+    let valid = !request_id.is_empty()
+        && request_id.len() <= MAX_REQUEST_ID_LEN
+        && request_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        && request_id != "."
+        && request_id != "..";
+    valid.then(|| request_id.to_string())
+}
+
+// Whether the Accept header asks for a MessagePack response
+fn accepts_msgpack(request: &LambdaFunctionUrlRequest) -> bool {
+    request
+        .headers
+        .get_all("accept")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|media_range| media_range.split(';').next())
+        .any(|media_type| {
+            let media_type = media_type.trim();
+            media_type.eq_ignore_ascii_case("application/msgpack")
+                || media_type.eq_ignore_ascii_case("application/x-msgpack")
+        })
+}
+
+// Whether the caller lists gzip in Accept-Encoding, without ruling it out with q=0
+fn accepts_gzip(request: &LambdaFunctionUrlRequest) -> bool {
+    request
+        .headers
+        .get_all("accept-encoding")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let refused = params.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name.eq_ignore_ascii_case("x-gzip")) && !refused
+        })
+}
+
+// Function URL response carrying the body as MessagePack instead of JSON
+// and/or gzip-compressed, either of which is sent base64-encoded
+fn encoded_response(
+    body: &Value,
+    msgpack: bool,
+    gzip: bool,
+    gzip_min_bytes: usize,
+) -> Result<Value, Error> {
+    let (content_type, body) = if msgpack {
+        ("application/msgpack", rmp_serde::to_vec_named(body)?)
+    } else {
+        ("application/json", serde_json::to_vec(body)?)
+    };
+    // Small bodies gain little from compression, so they are sent as is
+    if gzip && body.len() >= gzip_min_bytes {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&body)?;
+        return Ok(json!({
+            "statusCode": 200,
+            "headers": { "content-type": content_type, "content-encoding": "gzip" },
+            "body": BASE64.encode(encoder.finish()?),
+            "isBase64Encoded": true,
+        }));
+    }
+    Ok(json!({
+        "statusCode": 200,
+        "headers": { "content-type": content_type },
+        "body": BASE64.encode(body),
+        "isBase64Encoded": true,
+    }))
+}
+
+async fn render_function_url_request(
+    resources: &Arc<SharedResources>,
+    request: LambdaFunctionUrlRequest,
+    deadline: SystemTime,
+    request_id: String,
+    cancel: &CancellationToken,
+) -> Result<Value, RenderError> {
+    authorize(&request, resources.api_key.as_deref())?;
+
+    // Parse request body
+    let body = decode_request_body(&request, resources.max_request_body_bytes)?;
+    // Otherwise these surface as a cryptic "EOF while parsing" from serde_json
+    if body.is_empty() {
+        return Err(RenderError::InvalidRequest(
+            "Request body is empty".to_string(),
+        ));
+    }
+    if body.trim().is_empty() {
+        return Err(RenderError::InvalidRequest(
+            "Request body contains only whitespace".to_string(),
+        ));
+    }
+    let body: Value =
+        serde_json::from_str(&body).map_err(|e| RenderError::InvalidRequest(e.to_string()))?;
+
+    // Requests with an "action" are handled separately from render batches
+    if body.get("action").is_some() {
+        let action: ActionRequest = serde_json::from_value(body)
+            .map_err(|e| RenderError::InvalidRequest(parse_error::describe(&e)))?;
+        return handle_action(resources, action).await;
+    }
+
+    let request: RenderRequest = serde_json::from_value(body)
+        .map_err(|e| RenderError::InvalidRequest(parse_error::describe(&e)))?;
+    check_empty_batch(resources, &request)?;
+    let response = process_batch(resources, request, deadline, request_id, cancel).await;
+    Ok(json!(response))
+}
+
+async fn handle_action(
+    resources: &SharedResources,
+    action: ActionRequest,
+) -> Result<Value, RenderError> {
+    match action {
+        ActionRequest::ListTemplates { prefix } => {
+            let templates =
+                list_templates(resources, prefix.as_deref().unwrap_or_default()).await?;
+            Ok(json!({ "templates": templates }))
+        }
+        ActionRequest::CacheStats => {
+            let cache = resources.template_cache.read().await;
+            let stats = TemplateCacheStats {
+                entries: cache.len(),
+                source_bytes: cache.values().map(|(source, _)| source.len()).sum(),
+                hits: resources.template_cache_hits.load(Ordering::Relaxed),
+                misses: resources.template_cache_misses.load(Ordering::Relaxed),
+                evictions: resources.template_cache_evictions.load(Ordering::Relaxed),
+            };
+            Ok(json!({ "template_cache": stats }))
+        }
+        ActionRequest::Health => Ok(health_report(resources).await),
+    }
+}
+
+// Check every configured bucket at once. "overall" is "ok" only if all of them
+// are reachable; failures are reported per bucket rather than failing the request.
+async fn health_report(resources: &SharedResources) -> Value {
+    let mut stores = vec![
+        ("templates".to_string(), &resources.templates),
+        ("results".to_string(), &resources.results),
+    ];
+    if !Arc::ptr_eq(&resources.data_objects, &resources.templates) {
+        stores.push(("data".to_string(), &resources.data_objects));
+    }
+    if let Some(replica_results) = &resources.replica_results {
+        stores.push(("replica_results".to_string(), replica_results));
+    }
+    // Reported as tenant_results.{tenant} and output_buckets.{bucket}
+    for (group, named_stores) in [
+        ("tenant_results", &resources.tenant_results),
+        ("output_buckets", &resources.output_buckets),
+    ] {
+        let mut names: Vec<&String> = named_stores.keys().collect();
+        names.sort();
+        for name in names {
+            stores.push((format!("{}.{}", group, name), &named_stores[name]));
+        }
+    }
+
+    let checks = futures::future::join_all(stores.iter().map(|(_, store)| {
+        let check_span = tracing::info_span!("s3_health_check", bucket = %store.bucket());
+        store.check().instrument(check_span)
+    }))
+    .await;
+
+    let mut healthy = true;
+    let mut s3 = serde_json::Map::new();
+    for ((name, store), result) in stores.into_iter().zip(checks) {
+        let status = match result {
+            Ok(()) => json!({ "bucket": store.bucket(), "status": "ok" }),
+            Err(e) => {
+                warn!("Health check of bucket {} failed: {}", store.bucket(), e);
+                healthy = false;
+                json!({ "bucket": store.bucket(), "status": "error", "error": e.to_string() })
+            }
+        };
+        s3.insert(name, status);
+    }
+    json!({
+        "overall": if healthy { "ok" } else { "error" },
+        "s3": s3,
+    })
+}
+
+// List the templates in the templates store, leaving out their defaults and
+// transform objects
+async fn list_templates(
+    resources: &SharedResources,
+    prefix: &str,
+) -> Result<Vec<TemplateInfo>, RenderError> {
+    let list_span = tracing::info_span!(
+        "s3_template_list",
+        bucket = %resources.templates.bucket(),
+        prefix = %prefix,
+        object_count = field::Empty
+    );
+    let objects = resources
+        .templates
+        .list(prefix)
+        .instrument(list_span.clone())
+        .await
+        .map_err(|e| RenderError::S3Error(format!("Failed to list templates: {}", e)))?;
+    list_span.record("object_count", objects.len());
+
+    Ok(objects
+        .into_iter()
+        .filter(|object| {
+            !object.key.ends_with(defaults::DEFAULTS_SUFFIX)
+                && !object.key.ends_with(transform::TRANSFORM_SUFFIX)
+        })
+        .map(|object| TemplateInfo {
+            template_id: object.key,
+            size: object.size,
+            last_modified: object
+                .last_modified
+                .and_then(|t| DateTime::from(t).fmt(DateTimeFormat::DateTime).ok()),
+        })
+        .collect())
+}
+
+// Handle a scheduled EventBridge event by rendering the jobs listed in an S3 manifest
+async fn handle_manifest_event(
+    resources: &Arc<SharedResources>,
+    detail: ManifestDetail,
+    deadline: SystemTime,
+    request_id: String,
+    cancel: &CancellationToken,
+) -> Result<Value, Error> {
+    let manifest_store: Arc<dyn ObjectStore> = match &detail.bucket {
+        Some(bucket) => Arc::new(S3Store::new(resources.s3_client.clone(), bucket)),
+        None => Arc::clone(&resources.templates),
+    };
+    info!("Loading manifest {}", detail.key);
+
+    let manifest_span = tracing::info_span!(
+        "s3_manifest_fetch",
+        bucket = %manifest_store.bucket(),
+        key = %detail.key,
+        bytes = field::Empty
+    );
+    let manifest_data = manifest_store
+        .get(&detail.key)
+        .instrument(manifest_span.clone())
+        .await
+        .map_err(|e| RenderError::S3Error(format!("Failed to fetch manifest: {}", e)))?;
+    manifest_span.record("bytes", manifest_data.len());
+
+    let request: RenderRequest = serde_json::from_slice(&manifest_data).map_err(|e| {
+        error!("Error parsing manifest {}: {}", detail.key, e);
+        RenderError::JobParseError(format!(
+            "Invalid manifest format: {}",
+            parse_error::describe(&e)
+        ))
+    })?;
+
+    check_empty_batch(resources, &request)?;
+    // Checked before rendering, so a summary that can't be written doesn't
+    // leave the batch's uploads without a report
+    let summary_key = format!("{}.summary.json", detail.key.trim_end_matches(".json"));
+    validate_key(&summary_key)?;
+    let response = process_batch(resources, request, deadline, request_id, cancel).await;
+
+    // Write a summary report next to the rendered results
+    let summary = serde_json::to_vec(&response)?;
+    let opts = PutOptions {
+        content_type: Some("application/json".to_string()),
+        ..Default::default()
+    };
+    let (result, _) = retry::with_retries(
+        &resources.upload_retry,
+        |e| matches!(e, StoreError::Backend(_)),
+        || {
+            resources
+                .results
+                .put(&summary_key, summary.clone(), opts.clone())
+        },
+    )
+    .await;
+    result
+        .map_err(|e| RenderError::S3Error(format!("Failed to upload manifest summary: {}", e)))?;
+    info!("Wrote manifest summary to {}", summary_key);
+
+    Ok(json!(response))
+}
+
+// Handle S3 notifications by rendering each newly created data object. Objects
+// are read from {data_prefix}{template_id}/{name}.json and rendered to
+// {results_prefix}{template_id}/{name}.pdf in the results store.
+async fn handle_s3_event(resources: &SharedResources, event: S3Event) -> Result<Value, Error> {
+    let mut results = Vec::new();
+    for record in event.records {
+        let created = record
+            .event_name
+            .as_deref()
+            .is_some_and(|name| name.starts_with("ObjectCreated:"));
+        let (Some(bucket), Some(key)) = (record.s3.bucket.name, record.s3.object.key) else {
+            continue;
+        };
+        if !created {
+            info!("Ignoring {:?} event for {}", record.event_name, key);
+            continue;
+        }
+
+        // Keys in S3 notifications are URL-encoded, with spaces as '+'
+        let key = percent_decode_str(&key.replace('+', " "))
+            .decode_utf8_lossy()
+            .into_owned();
+        let Some((template_id, name)) = key
+            .strip_prefix(&resources.s3_trigger.data_prefix)
+            .and_then(|path| path.strip_suffix(".json"))
+            .and_then(|path| path.rsplit_once('/'))
+        else {
+            info!("Ignoring {}, which doesn't match the data key layout", key);
+            continue;
+        };
+
+        let output_key = format!(
+            "{}{}/{}",
+            resources.s3_trigger.results_prefix, template_id, name
+        );
+        let result = render_s3_object(resources, &bucket, &key, template_id, &output_key).await;
+        results.push(result);
+    }
+
+    Ok(json!({ "results": results }))
+}
+
+// Render one data object uploaded to S3 and upload its PDF under `output_key`
+async fn render_s3_object(
+    resources: &SharedResources,
+    bucket: &str,
+    key: &str,
+    template_id: &str,
+    output_key: &str,
+) -> JobResult {
+    let job_id = job_id::new_job_id(template_id);
+    let job_span = tracing::info_span!("render_job", job_id = %job_id, template_id = %template_id);
+    let failure = |e: RenderError| {
+        error!("Rendering {} failed: {}", key, e);
+        JobResult::failure(
+            job_id.clone(),
+            template_id.to_string(),
+            "error",
+            e.error_code(),
+            e.to_string(),
+        )
+    };
+
+    let data_store = S3Store::new(resources.s3_client.clone(), bucket);
+    let fetch_span = tracing::info_span!(
+        parent: &job_span,
+        "s3_data_fetch",
+        bucket = %bucket,
+        key = %key,
+        bytes = field::Empty
+    );
+    let data = match data_store.get(key).instrument(fetch_span.clone()).await {
+        Ok(data) => {
+            fetch_span.record("bytes", data.len());
+            data
+        }
+        Err(e) => {
+            return failure(RenderError::S3Error(format!(
+                "Failed to fetch data {}: {}",
+                key, e
+            )))
+        }
+    };
+    let job_request = match serde_json::from_slice(&data) {
+        Ok(data) => RenderJobRequest {
+            template_id: template_id.to_string(),
+            data,
+            ..Default::default()
+        },
+        Err(e) => return failure(RenderError::JobParseError(format!("Invalid data: {}", e))),
+    };
+
+    let job_outputs = match render_pdf(resources, &job_id, &job_request)
+        .instrument(job_span.clone())
+        .await
+    {
+        Ok(job_outputs) => job_outputs,
+        Err(e) => return failure(e),
+    };
+    // Swap the job_id prefix for the key derived from the data object
+    let job_outputs = job_outputs.rekeyed(&job_id, output_key);
+
+    let rendered_job = RenderedJob {
+        index: 0,
+        job_id: job_id.clone(),
+        template_id: job_request.template_id,
+        outputs: job_outputs.outputs,
+        multi_output: false,
+        output_store: None,
+        thumbnail: job_outputs.thumbnail,
+        request_record: None,
+        storage_class: None,
+        source: job_outputs.source,
+    };
+    upload_rendered_job(
+        resources,
+        resources.results.as_ref(),
+        None,
+        rendered_job,
+        OnConflict::Overwrite,
+    )
+    .instrument(job_span)
+    .await
+}
+
+// The shared resources, initialized on first use. Initialization runs in its
+// own task so that a panic, e.g. from invalid configuration, is reported as an
+// error instead of aborting the invocation, and is retried on the next call.
+async fn shared_resources() -> Result<&'static Arc<SharedResources>, RenderError> {
+    resources_once(&RESOURCES, initialize_resources).await
+}
+
+// The resources in `cell`, initializing them with `init` unless an earlier
+// call did. A failed `init` leaves `cell` empty.
+async fn resources_once<F, Fut>(
+    cell: &OnceCell<Arc<SharedResources>>,
+    init: F,
+) -> Result<&Arc<SharedResources>, RenderError>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Arc<SharedResources>> + Send + 'static,
+{
+    cell.get_or_try_init(|| async { tokio::spawn(init()).await.map_err(initialization_error) })
+        .await
+}
+
+// 503 for a failed initialization task, with the panic message if it panicked
+fn initialization_error(e: tokio::task::JoinError) -> RenderError {
+    match e.try_into_panic() {
+        Ok(payload) => RenderError::Unavailable(format!(
+            "Failed to initialize: {}",
+            panic_message(payload.as_ref())
+        )),
+        Err(e) => RenderError::Unavailable(format!("Failed to initialize: {}", e)),
+    }
+}
+
+async fn function_handler(event: LambdaEvent<IncomingEvent>) -> Result<Value, Error> {
+    handle_event(shared_resources(), event).await
+}
+
+// Handle an invocation with the shared resources `resources` resolves to
+async fn handle_event<'a>(
+    resources: impl Future<Output = Result<&'a Arc<SharedResources>, RenderError>>,
+    event: LambdaEvent<IncomingEvent>,
+) -> Result<Value, Error> {
+    // Function URL callers may correlate with their own id; other events use the Lambda one
+    let request_id = match &event.payload {
+        IncomingEvent::FunctionUrl(request) => {
+            client_request_id(request).unwrap_or_else(|| ulid::Ulid::new().to_string())
+        }
+        _ => event.context.request_id.clone(),
+    };
+    let handler_span = tracing::info_span!(
+        "function_handler",
+        request_id = %request_id,
+        batch_size = field::Empty
+    );
+
+    // Continue the caller's trace when it sent a traceparent header. The parent
+    // must be set before the span is first entered.
+    if let IncomingEvent::FunctionUrl(request) = &event.payload {
+        if let Some(parent) = telemetry::extract_remote_context(&request.headers) {
+            if let Err(e) = handler_span.set_parent(parent) {
+                warn!("Failed to set remote trace parent: {}", e);
+            }
+        }
+    }
+
+    let response = async move {
+        let resources = match resources.await {
+            Ok(resources) => resources,
+            Err(e) => {
+                error!("Rejecting request: {}", e);
+                return match event.payload {
+                    IncomingEvent::FunctionUrl(_) => Ok(e.to_response()),
+                    _ => Err(e.into()),
+                };
+            }
+        };
+        let deadline = event.context.deadline();
+        // Cancelled along with every other invocation on SIGTERM
+        let cancel = SHUTDOWN.child_token();
+
+        // Released when the guard is dropped, however this handler returns
+        let Some(_in_flight) = resources.in_flight.try_acquire() else {
+            let e = RenderError::Overloaded(format!(
+                "limit of {} concurrent requests reached",
+                resources.in_flight.max()
+            ));
+            warn!("Rejecting request: {}", e);
+            return match event.payload {
+                IncomingEvent::FunctionUrl(_) => Ok(e.to_response()),
+                _ => Err(e.into()),
+            };
+        };
+
+        match event.payload {
+            IncomingEvent::FunctionUrl(request) => {
+                handle_function_url(resources, *request, deadline, request_id, &cancel).await
+            }
+            IncomingEvent::EventBridge(event) => {
+                handle_manifest_event(resources, event.detail, deadline, request_id, &cancel).await
+            }
+            IncomingEvent::S3(event) => handle_s3_event(resources, *event).await,
+        }
+    }
+    .instrument(handler_span)
+    .await;
+
+    telemetry::report_dropped_spans();
+    // Lambda may freeze the environment as soon as the handler returns, before
+    // the background export runs, so the invocation's spans are flushed first
+    if let Some(export) = TRACE_EXPORT.get() {
+        flush_traces(&export.provider, export.flush_timeout).await;
+    }
+    response
+}
+
+// Export the spans queued so far, waiting at most `timeout`. A flush that
+// takes longer carries on in the background.
+async fn flush_traces(provider: &SdkTracerProvider, timeout: Duration) {
+    let provider = provider.clone();
+    let flush = tokio::task::spawn_blocking(move || provider.force_flush());
+    match tokio::time::timeout(timeout, flush).await {
+        Ok(Ok(Ok(()))) => {}
+        Ok(Ok(Err(e))) => warn!("Failed to flush spans: {}", e),
+        Ok(Err(e)) => warn!("Span flush task failed: {}", e),
+        Err(_) => warn!("Flushing spans took over {}ms", timeout.as_millis()),
+    }
+}
+
+// Flush the tracer provider and stop its export thread, waiting at most `timeout`
+async fn shutdown_traces(provider: &SdkTracerProvider, timeout: Duration) {
+    let provider = provider.clone();
+    let shutdown = tokio::task::spawn_blocking(move || provider.shutdown_with_timeout(timeout));
+    match tokio::time::timeout(timeout, shutdown).await {
+        Ok(Ok(Ok(()))) => {}
+        Ok(Ok(Err(e))) => eprintln!("Error shutting down tracer provider: {:?}", e),
+        Ok(Err(e)) => eprintln!("Tracer provider shutdown task failed: {}", e),
+        Err(_) => eprintln!(
+            "Shutting down the tracer provider took over {}ms",
+            timeout.as_millis()
+        ),
+    }
+}
+
+// Tracer provider exporting spans to the OTLP/HTTP endpoint. Spans are exported
+// in batches from a background thread and flushed at the end of each
+// invocation, so a slow or unreachable collector adds at most
+// OTLP_FLUSH_TIMEOUT_MS to a request; each export gives up after OTLP_TIMEOUT_MS.
+// Spans that don't fit in the export queue are dropped and reported after
+// each invocation.
+fn build_tracer_provider(
+    otlp_endpoint: String,
+) -> Result<SdkTracerProvider, opentelemetry_otlp::ExporterBuildError> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(otlp_endpoint)
+        .with_timeout(Duration::from_millis(env_or("OTLP_TIMEOUT_MS", 2_000)))
+        .build()?;
+
+    Ok(SdkTracerProvider::builder()
+        .with_span_processor(CountingBatchProcessor::new(
+            exporter,
+            env_or("OTLP_MAX_QUEUE_SIZE", 2_048usize).max(1),
+        ))
+        .with_resource(telemetry::resource())
+        .build())
+}
+
+// Tracer provider for OTLP_ENDPOINT, if set. A broken exporter configuration
+// only disables trace export, it doesn't stop the renderer from serving requests
+fn tracer_provider(otlp_endpoint: Option<String>) -> Option<SdkTracerProvider> {
+    let otlp_endpoint = otlp_endpoint.filter(|s| !s.is_empty())?;
+    match build_tracer_provider(otlp_endpoint) {
+        Ok(provider) => Some(provider),
+        Err(e) => {
+            eprintln!(
+                "Failed to create OTLP exporter, traces will not be exported: {}",
+                e
+            );
+            None
+        }
+    }
+}
+
+// Log filter from RUST_LOG, falling back to LOG_LEVEL and then "info". Both
+// accept per-module directives, e.g. "renderer=debug,aws_sdk_s3=warn"
+fn log_filter() -> EnvFilter {
+    log_filter_from(env::var("RUST_LOG").ok(), env::var("LOG_LEVEL").ok())
+}
+
+fn log_filter_from(rust_log: Option<String>, log_level: Option<String>) -> EnvFilter {
+    let directives = rust_log
+        .filter(|s| !s.is_empty())
+        .or(log_level.filter(|s| !s.is_empty()))
+        .unwrap_or_else(|| "info".to_string());
+
+    EnvFilter::try_new(&directives).unwrap_or_else(|e| {
+        eprintln!("Invalid log filter {:?}, using \"info\": {}", directives, e);
+        EnvFilter::new("info")
+    })
+}
+
+// Lambda sends SIGTERM before shutting down an execution environment with
+// registered extensions. Running batches are cancelled so they return what
+// they have finished instead of starting renders that can't complete.
+async fn cancel_on_sigterm() {
+    let mut sigterm = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            warn!("Failed to listen for SIGTERM: {}", e);
+            return;
         }
-        None => (None, None),
     };
+    if sigterm.recv().await.is_some() {
+        warn!("Received SIGTERM, cancelling running batches");
+        SHUTDOWN.cancel();
+        // The environment is about to shut down, so export what's left now
+        if let Some(export) = TRACE_EXPORT.get() {
+            shutdown_traces(&export.provider, export.flush_timeout).await;
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    // Initialize OpenTelemetry if OTLP_ENDPOINT is configured
+    let tracer_provider = tracer_provider(env::var("OTLP_ENDPOINT").ok());
+    let telemetry_layer = tracer_provider.map(|provider| {
+        let tracer = provider.tracer("pdf-renderer-lambda");
+        global::set_tracer_provider(provider.clone());
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let export = TraceExport {
+            provider,
+            flush_timeout: Duration::from_millis(env_or("OTLP_FLUSH_TIMEOUT_MS", 1_000)),
+        };
+        TRACE_EXPORT
+            .set(export)
+            .expect("Failed to set trace export");
+        tracing_opentelemetry::layer().with_tracer(tracer)
+    });
 
     // Option<Layer> implements Layer (no-op when None)
     let subscriber = Registry::default()
@@ -420,24 +3876,2549 @@ async fn main() -> Result<(), Error> {
                 .with_ansi(false)
                 .without_time(),
         )
-        .with(tracing_subscriber::filter::LevelFilter::INFO)
+        .with(log_filter())
         .with(telemetry_layer);
 
     tracing::subscriber::set_global_default(subscriber).expect("Failed to set subscriber");
 
-    // Initialize resources properly using the existing Tokio runtime
-    let resources = initialize_resources().await;
-    RESOURCES.set(resources).expect("Failed to set resources");
-    info!("Shared resources initialized");
+    // Warm up during the init phase through the same path as invocations, so a
+    // failure here is retried by the next invocation and reported as a 503
+    match shared_resources().await {
+        Ok(_) => info!("Shared resources initialized"),
+        Err(e) => error!("{}", e),
+    }
 
+    tokio::spawn(cancel_on_sigterm());
     let result = run(service_fn(function_handler)).await;
 
     // Shutdown the tracer to ensure all spans are exported
-    if let Some(provider) = tracer_provider {
-        if let Err(e) = provider.shutdown() {
-            eprintln!("Error shutting down tracer provider: {:?}", e);
-        }
+    if let Some(export) = TRACE_EXPORT.get() {
+        shutdown_traces(&export.provider, export.flush_timeout).await;
     }
 
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_exporter::CountingExporter;
+    use crate::mock_s3::MockS3;
+
+    // Template that renders with or without data
+    const TEMPLATE: &str =
+        "#let data = json.decode(sys.inputs.data)\nHello #data.at(\"name\", default: \"world\")";
+
+    // Resources as initialize_resources builds them without any optional
+    // setting, backed by in-memory stores
+    fn test_resources() -> SharedResources {
+        let s3_client = aws_sdk_s3::Client::from_conf(
+            aws_sdk_s3::Config::builder()
+                .behavior_version(aws_sdk_s3::config::BehaviorVersion::latest())
+                .region(aws_sdk_s3::config::Region::new("us-east-1"))
+                .build(),
+        );
+        let templates: Arc<dyn ObjectStore> = Arc::new(InMemoryStore::default());
+        SharedResources {
+            data_ref_fetcher: DataRefFetcher::new(
+                s3_client.clone(),
+                Vec::new(),
+                1024 * 1024,
+                Duration::from_secs(1),
+            )
+            .unwrap(),
+            s3_client,
+            data_objects: Arc::clone(&templates),
+            templates,
+            results: Arc::new(InMemoryStore::default()),
+            tenant_results: HashMap::new(),
+            replica_results: None,
+            output_buckets: HashMap::new(),
+            template_cache: RwLock::new(HashMap::new()),
+            template_cache_hits: AtomicU64::new(0),
+            template_cache_misses: AtomicU64::new(0),
+            template_cache_evictions: AtomicU64::new(0),
+            template_disk_cache: None,
+            defaults_cache: RwLock::new(HashMap::new()),
+            transform_cache: RwLock::new(HashMap::new()),
+            rate_limiter: TemplateRateLimiter::default(),
+            deadline_safety_margin: Duration::from_secs(1),
+            result_cache: None,
+            api_key: None,
+            max_request_body_bytes: 1024 * 1024,
+            response_gzip_min_bytes: 1024,
+            max_template_bytes: 1024 * 1024,
+            validate_output_pdf: false,
+            result_settings: ResultObjectSettings::default(),
+            upload_retry: RetryPolicy {
+                max_attempts: 1,
+                base_delay: Duration::ZERO,
+            },
+            batch_manifest_prefix: None,
+            s3_trigger: S3TriggerSettings {
+                data_prefix: "incoming/".to_string(),
+                results_prefix: "rendered/".to_string(),
+            },
+            in_flight: InFlightLimiter::new(usize::MAX),
+            render_permits: Arc::new(Semaphore::new(4)),
+            template_permits: TemplatePermits::default(),
+            upload_permits: Arc::new(Semaphore::new(4)),
+            pipeline_uploads: false,
+            allow_empty_batch: true,
+        }
+    }
+
+    async fn put(store: &dyn ObjectStore, key: &str, data: impl Into<Vec<u8>>) {
+        store
+            .put(key, data.into(), PutOptions::default())
+            .await
+            .unwrap();
+    }
+
+    // In-memory store recording the keys it was asked for, whose first
+    // `failing_puts` puts fail with a backend error
+    #[derive(Debug, Default)]
+    struct TestStore {
+        inner: InMemoryStore,
+        failing_puts: AtomicU64,
+        gets: std::sync::Mutex<Vec<String>>,
+        // Time each put takes, so concurrent puts overlap
+        put_delay: Option<Duration>,
+        // Puts in flight, and the most there were at once
+        running_puts: AtomicU64,
+        max_running_puts: AtomicU64,
+        // Gets wait for a permit of this semaphore, if set
+        get_gate: Option<Arc<Semaphore>>,
+    }
+
+    impl TestStore {
+        fn gets_of(&self, key: &str) -> usize {
+            self.gets
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|k| *k == key)
+                .count()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ObjectStore for TestStore {
+        fn bucket(&self) -> &str {
+            "test"
+        }
+
+        async fn get(&self, key: &str) -> Result<Vec<u8>, StoreError> {
+            self.gets.lock().unwrap().push(key.to_string());
+            if let Some(gate) = &self.get_gate {
+                let _permit = gate.acquire().await.unwrap();
+            }
+            self.inner.get(key).await
+        }
+
+        async fn head(&self, key: &str) -> Result<u64, StoreError> {
+            self.inner.head(key).await
+        }
+
+        async fn put(&self, key: &str, bytes: Vec<u8>, opts: PutOptions) -> Result<(), StoreError> {
+            let running = self.running_puts.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running_puts.fetch_max(running, Ordering::SeqCst);
+            if let Some(delay) = self.put_delay {
+                tokio::time::sleep(delay).await;
+            }
+            self.running_puts.fetch_sub(1, Ordering::SeqCst);
+
+            let failing = self
+                .failing_puts
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .is_ok();
+            if failing {
+                return Err(StoreError::Backend("slow down".to_string()));
+            }
+            self.inner.put(key, bytes, opts).await
+        }
+
+        async fn delete(&self, key: &str) -> Result<(), StoreError> {
+            self.inner.delete(key).await
+        }
+
+        async fn list(&self, prefix: &str) -> Result<Vec<storage::ObjectInfo>, StoreError> {
+            self.inner.list(prefix).await
+        }
+
+        async fn check(&self) -> Result<(), StoreError> {
+            self.inner.check().await
+        }
+    }
+
+    // Resources with TEMPLATE stored under each of the template ids
+    async fn resources_with_templates(template_ids: &[&str]) -> SharedResources {
+        let resources = test_resources();
+        for template_id in template_ids {
+            put(resources.templates.as_ref(), template_id, TEMPLATE).await;
+        }
+        resources
+    }
+
+    async fn run_batch_until(
+        resources: &Arc<SharedResources>,
+        request: Value,
+        deadline: SystemTime,
+    ) -> BatchResponse {
+        let request: RenderRequest = serde_json::from_value(request).unwrap();
+        process_batch(
+            resources,
+            request,
+            deadline,
+            "test-request".to_string(),
+            &CancellationToken::new(),
+        )
+        .await
+    }
+
+    async fn run_batch(resources: &Arc<SharedResources>, request: Value) -> BatchResponse {
+        let deadline = SystemTime::now() + Duration::from_secs(600);
+        run_batch_until(resources, request, deadline).await
+    }
+
+    fn statuses(response: &BatchResponse) -> Vec<&str> {
+        response
+            .results
+            .iter()
+            .map(|result| result.status.as_str())
+            .collect()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn function_url_request(
+        body: &str,
+        is_base64_encoded: bool,
+        headers: &[(&'static str, &str)],
+    ) -> LambdaFunctionUrlRequest {
+        let mut request = LambdaFunctionUrlRequest::default();
+        request.body = Some(body.to_string());
+        request.is_base64_encoded = is_base64_encoded;
+        for (name, value) in headers {
+            request.headers.insert(*name, value.parse().unwrap());
+        }
+        request
+    }
+
+    #[test]
+    fn decodes_uncompressed_body() {
+        let request = function_url_request(r#"{"jobs": []}"#, false, &[]);
+        assert_eq!(
+            decode_request_body(&request, 1024).unwrap(),
+            r#"{"jobs": []}"#
+        );
+
+        let request = function_url_request("{}", false, &[("content-encoding", "identity")]);
+        assert_eq!(decode_request_body(&request, 1024).unwrap(), "{}");
+    }
+
+    #[test]
+    fn decodes_gzip_body() {
+        let body = BASE64.encode(gzip(br#"{"jobs": []}"#));
+        for encoding in ["gzip", "x-gzip", " GZIP "] {
+            let request = function_url_request(&body, true, &[("content-encoding", encoding)]);
+            assert_eq!(
+                decode_request_body(&request, 1024).unwrap(),
+                r#"{"jobs": []}"#
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_zstd_body() {
+        let compressed = zstd::encode_all(&br#"{"jobs": []}"#[..], 3).unwrap();
+        let request = function_url_request(
+            &BASE64.encode(compressed),
+            true,
+            &[("content-encoding", "zstd")],
+        );
+        assert_eq!(
+            decode_request_body(&request, 1024).unwrap(),
+            r#"{"jobs": []}"#
+        );
+    }
+
+    #[test]
+    fn rejects_bodies_inflating_past_the_limit() {
+        let body = BASE64.encode(gzip(&[b' '; 4096]));
+        let request = function_url_request(&body, true, &[("content-encoding", "gzip")]);
+        assert!(matches!(
+            decode_request_body(&request, 1024),
+            Err(RenderError::PayloadTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_and_corrupt_encodings() {
+        let request = function_url_request("{}", false, &[("content-encoding", "br")]);
+        assert!(matches!(
+            decode_request_body(&request, 1024),
+            Err(RenderError::UnsupportedEncoding(encoding)) if encoding == "br"
+        ));
+
+        let request = function_url_request(
+            &BASE64.encode(b"not gzip"),
+            true,
+            &[("content-encoding", "gzip")],
+        );
+        assert!(matches!(
+            decode_request_body(&request, 1024),
+            Err(RenderError::BodyDecodeError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reports_jobs_past_the_deadline_as_timed_out() {
+        let resources = Arc::new(resources_with_templates(&["invoice.typ"]).await);
+        let deadline = SystemTime::now() + resources.deadline_safety_margin / 2;
+        let response = run_batch_until(
+            &resources,
+            json!({"jobs": [{"template_id": "invoice.typ"}, {"template_id": "invoice.typ"}]}),
+            deadline,
+        )
+        .await;
+
+        assert_eq!(statuses(&response), ["timed_out", "timed_out"]);
+        assert_eq!(response.results[0].error_code.as_deref(), Some("timed_out"));
+        assert_eq!(response.summary.timed_out, 2);
+        assert_eq!(response.summary.failed, 0);
+        assert_eq!(response.summary.batch_status, "success");
+    }
+
+    #[tokio::test]
+    async fn renders_jobs_before_the_deadline() {
+        let resources = Arc::new(resources_with_templates(&["invoice.typ"]).await);
+        let response = run_batch(
+            &resources,
+            json!({"jobs": [{"template_id": "invoice.typ", "data": {"name": "Ada"}}]}),
+        )
+        .await;
+
+        assert_eq!(statuses(&response), ["success"]);
+        assert_eq!(response.summary.timed_out, 0);
+    }
+
+    #[test]
+    fn deadline_is_reached_within_the_safety_margin() {
+        let deadline = SystemTime::now() + Duration::from_secs(100);
+        assert!(!deadline_reached(deadline, Duration::from_secs(10)));
+        assert!(deadline_reached(deadline, Duration::from_secs(100)));
+        assert!(deadline_reached(
+            SystemTime::now() - Duration::from_secs(1),
+            Duration::ZERO
+        ));
+    }
+
+    #[tokio::test]
+    async fn renders_manifest_events_and_writes_a_summary() {
+        let resources = resources_with_templates(&["invoice.typ"]).await;
+        let manifest = json!({"jobs": [{"template_id": "invoice.typ"}], "preserve_order": true});
+        put(
+            resources.templates.as_ref(),
+            "manifests/nightly.json",
+            manifest.to_string(),
+        )
+        .await;
+        let resources = Arc::new(resources);
+
+        let detail = ManifestDetail {
+            bucket: None,
+            key: "manifests/nightly.json".to_string(),
+        };
+        let deadline = SystemTime::now() + Duration::from_secs(600);
+        let response = handle_manifest_event(
+            &resources,
+            detail,
+            deadline,
+            "event-id".to_string(),
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response["summary"]["success"], 1);
+        let summary = resources
+            .results
+            .get("manifests/nightly.summary.json")
+            .await
+            .unwrap();
+        let summary: Value = serde_json::from_slice(&summary).unwrap();
+        assert_eq!(summary["request_id"], "event-id");
+        assert_eq!(summary["results"][0]["status"], "success");
+    }
+
+    #[tokio::test]
+    async fn manifest_summaries_are_retried_and_their_keys_checked_first() {
+        let results = Arc::new(TestStore {
+            failing_puts: AtomicU64::new(1),
+            ..Default::default()
+        });
+        let mut resources = resources_with_templates(&["invoice.typ"]).await;
+        resources.results = Arc::clone(&results) as Arc<dyn ObjectStore>;
+        resources.upload_retry.max_attempts = 2;
+        let manifest = json!({"jobs": [{"template_id": "invoice.typ"}]}).to_string();
+        for key in ["manifests/nightly.json", "manifests/night ly.json"] {
+            put(resources.templates.as_ref(), key, manifest.as_str()).await;
+        }
+        let resources = Arc::new(resources);
+        let cancel = CancellationToken::new();
+        let run = |key: &str| {
+            let detail = ManifestDetail {
+                bucket: None,
+                key: key.to_string(),
+            };
+            handle_manifest_event(
+                &resources,
+                detail,
+                SystemTime::now() + Duration::from_secs(600),
+                "event-id".to_string(),
+                &cancel,
+            )
+        };
+
+        // The failed summary upload is retried
+        let response = run("manifests/nightly.json").await.unwrap();
+        assert_eq!(response["summary"]["success"], 1);
+        assert!(results.get("manifests/nightly.summary.json").await.is_ok());
+
+        // A summary key that can't be written refuses the manifest before rendering
+        let objects = results.list("").await.unwrap().len();
+        assert!(run("manifests/night ly.json").await.is_err());
+        assert_eq!(results.list("").await.unwrap().len(), objects);
+    }
+
+    #[tokio::test]
+    async fn fails_manifest_events_with_missing_or_invalid_manifests() {
+        let resources = test_resources();
+        put(resources.templates.as_ref(), "bad.json", "{\"jobs\": 1}").await;
+        let resources = Arc::new(resources);
+        let deadline = SystemTime::now() + Duration::from_secs(600);
+        for key in ["missing.json", "bad.json"] {
+            let detail = ManifestDetail {
+                bucket: None,
+                key: key.to_string(),
+            };
+            let result = handle_manifest_event(
+                &resources,
+                detail,
+                deadline,
+                "event-id".to_string(),
+                &CancellationToken::new(),
+            )
+            .await;
+            assert!(result.is_err(), "{} was accepted", key);
+        }
+    }
+
+    #[test]
+    fn parses_scheduled_events_with_a_manifest_detail() {
+        let event = json!({
+            "version": "0",
+            "id": "53dc4d37-cffa-4f76-80c9-8b7d4a4d2eaa",
+            "detail-type": "Scheduled Event",
+            "source": "aws.events",
+            "account": "123456789012",
+            "time": "2024-01-01T00:00:00Z",
+            "region": "eu-central-1",
+            "resources": [],
+            "detail": {"key": "manifests/nightly.json"}
+        });
+        match serde_json::from_value(event).unwrap() {
+            IncomingEvent::EventBridge(event) => {
+                assert_eq!(event.detail.key, "manifests/nightly.json");
+                assert_eq!(event.detail.bucket, None);
+            }
+            other => panic!("parsed as {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn uploads_one_pdf_per_page_for_split_jobs() {
+        let resources = test_resources();
+        put(
+            resources.templates.as_ref(),
+            "report.typ",
+            format!("{}\n#pagebreak()\nSecond page", TEMPLATE),
+        )
+        .await;
+        let resources = Arc::new(resources);
+        let response = run_batch(
+            &resources,
+            json!({"jobs": [{"template_id": "report.typ", "split_pages": true}]}),
+        )
+        .await;
+
+        let result = &response.results[0];
+        assert_eq!(result.status, "success");
+        assert_eq!(result.s3_key, None);
+        let s3_keys = result.s3_keys.clone().unwrap();
+        assert_eq!(
+            s3_keys,
+            [
+                format!("{}/page-1.pdf", result.job_id),
+                format!("{}/page-2.pdf", result.job_id)
+            ]
+        );
+        for s3_key in &s3_keys {
+            let page = resources.results.get(s3_key).await.unwrap();
+            assert_eq!(pdf::page_count(&page).unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn authorizes_requests_with_the_configured_api_key() {
+        let request = function_url_request("{}", false, &[("x-api-key", "secret")]);
+        assert!(authorize(&request, Some("secret")).is_ok());
+        assert!(authorize(&request, None).is_ok());
+
+        for request in [
+            function_url_request("{}", false, &[("x-api-key", "secre")]),
+            function_url_request("{}", false, &[("x-api-key", "secret2")]),
+            function_url_request("{}", false, &[]),
+        ] {
+            let e = authorize(&request, Some("secret")).unwrap_err();
+            assert_eq!(e.status_code(), 401);
+            assert_eq!(e.error_code(), "unauthorized");
+        }
+    }
+
+    #[test]
+    fn error_responses_carry_status_and_error_code() {
+        let e = RenderError::TemplateNotFound {
+            template_id: "missing.typ".to_string(),
+        };
+        let response = e.to_response();
+        assert_eq!(response["statusCode"], 404);
+        assert_eq!(response["headers"]["content-type"], "application/json");
+        let body: Value = serde_json::from_str(response["body"].as_str().unwrap()).unwrap();
+        assert_eq!(body["error_code"], "template_not_found");
+        assert_eq!(body["message"], "Template not found: missing.typ");
+    }
+
+    #[tokio::test]
+    async fn rejects_function_url_requests_with_an_error_response() {
+        let mut resources = test_resources();
+        resources.api_key = Some("secret".to_string());
+        let resources = Arc::new(resources);
+        let deadline = SystemTime::now() + Duration::from_secs(600);
+
+        let response = handle_function_url(
+            &resources,
+            function_url_request(r#"{"jobs": []}"#, false, &[]),
+            deadline,
+            "test-request".to_string(),
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response["statusCode"], 401);
+
+        let response = handle_function_url(
+            &resources,
+            function_url_request("{", false, &[("x-api-key", "secret")]),
+            deadline,
+            "test-request".to_string(),
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response["statusCode"], 400);
+        let body: Value = serde_json::from_str(response["body"].as_str().unwrap()).unwrap();
+        assert_eq!(body["error_code"], "invalid_request");
+    }
+
+    #[test]
+    fn decodes_base64_encoded_bodies() {
+        let request = function_url_request(&BASE64.encode(r#"{"jobs": []}"#), true, &[]);
+        assert_eq!(
+            decode_request_body(&request, 1024).unwrap(),
+            r#"{"jobs": []}"#
+        );
+
+        let request = function_url_request("not base64!", true, &[]);
+        assert!(matches!(
+            decode_request_body(&request, 1024),
+            Err(RenderError::BodyDecodeError(message)) if message.starts_with("Invalid base64")
+        ));
+    }
+
+    #[tokio::test]
+    async fn reports_compile_errors_with_their_location() {
+        let resources = test_resources();
+        put(
+            resources.templates.as_ref(),
+            "broken.typ",
+            "Hello\n#panic(\"broken\")",
+        )
+        .await;
+        let resources = Arc::new(resources);
+        let response =
+            run_batch(&resources, json!({"jobs": [{"template_id": "broken.typ"}]})).await;
+
+        let result = &response.results[0];
+        assert_eq!(result.status, "error");
+        assert_eq!(result.error_code.as_deref(), Some("compile_error"));
+        let error = result.error.as_deref().unwrap();
+        assert!(
+            error.starts_with("Template broken.typ failed to compile at 2:"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn describes_the_first_of_several_diagnostics() {
+        let diagnostic = |line| Diagnostic {
+            severity: "error",
+            line,
+            column: 1,
+            message: "oops".to_string(),
+        };
+        assert_eq!(describe_diagnostics(&[]), "unknown location");
+        assert_eq!(describe_diagnostics(&[diagnostic(3)]), "3:1: oops");
+        assert_eq!(
+            describe_diagnostics(&[diagnostic(3), diagnostic(4), diagnostic(5)]),
+            "3:1: oops (and 2 more errors)"
+        );
+    }
+
+    #[test]
+    fn result_put_options_carry_cache_headers() {
+        let settings = ResultObjectSettings {
+            cache_control: Some("public, max-age=60".to_string()),
+            expires_after: Some(Duration::from_secs(3600)),
+        };
+        let before = SystemTime::now();
+        let opts = settings.put_options("application/pdf", None);
+        assert_eq!(opts.content_type.as_deref(), Some("application/pdf"));
+        assert_eq!(opts.cache_control.as_deref(), Some("public, max-age=60"));
+        let expires = opts.expires.unwrap();
+        assert!(expires >= before + Duration::from_secs(3600));
+        assert!(expires <= SystemTime::now() + Duration::from_secs(3600));
+
+        let opts = ResultObjectSettings::default().put_options("image/png", None);
+        assert_eq!(opts.cache_control, None);
+        assert_eq!(opts.expires, None);
+    }
+
+    #[tokio::test]
+    async fn evicts_templates_from_memory_and_disk_caches() {
+        let dir = std::env::temp_dir().join(format!("renderer-evict-{}", std::process::id()));
+        let mut resources = resources_with_templates(&["invoice.typ", "other.typ"]).await;
+        resources.template_disk_cache =
+            Some(DiskCache::new(&dir, 1024 * 1024, Duration::from_secs(3600)));
+        get_cached_template(&resources, "invoice.typ")
+            .await
+            .unwrap();
+        get_cached_template(&resources, "other.typ").await.unwrap();
+
+        evict_template(&resources, "invoice.typ").await;
+
+        let cache = resources.template_cache.read().await;
+        assert!(!cache.contains_key("invoice.typ"));
+        assert!(cache.contains_key("other.typ"));
+        drop(cache);
+        let disk_cache = resources.template_disk_cache.as_ref().unwrap();
+        assert!(disk_cache.get("invoice.typ").await.is_none());
+        assert!(disk_cache.get("other.typ").await.is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn extracts_panic_messages() {
+        let payload = std::panic::catch_unwind(|| panic!("static message")).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "static message");
+        let payload = std::panic::catch_unwind(|| panic!("formatted {}", 42)).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "formatted 42");
+        let payload = std::panic::catch_unwind(|| std::panic::panic_any(42)).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "unknown panic payload");
+    }
+
+    #[tokio::test]
+    async fn retries_failed_uploads_and_reports_attempts() {
+        let mut resources = resources_with_templates(&["invoice.typ"]).await;
+        resources.upload_retry.max_attempts = 3;
+        let results = Arc::new(TestStore::default());
+        results.failing_puts.store(2, Ordering::Relaxed);
+        resources.results = results.clone();
+        let resources = Arc::new(resources);
+
+        let response = run_batch(
+            &resources,
+            json!({"jobs": [{"template_id": "invoice.typ"}]}),
+        )
+        .await;
+        let result = &response.results[0];
+        assert_eq!(result.status, "success");
+        assert_eq!(result.attempts, 3);
+        assert!(results.get(result.s3_key.as_ref().unwrap()).await.is_ok());
+
+        results.failing_puts.store(3, Ordering::Relaxed);
+        let response = run_batch(
+            &resources,
+            json!({"jobs": [{"template_id": "invoice.typ"}]}),
+        )
+        .await;
+        let result = &response.results[0];
+        assert_eq!(result.status, "error");
+        assert_eq!(result.error_code.as_deref(), Some("s3_error"));
+        assert_eq!(result.attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn preserve_order_returns_results_in_job_order() {
+        let resources = Arc::new(resources_with_templates(&["a.typ", "b.typ", "c.typ"]).await);
+        let response = run_batch(
+            &resources,
+            json!({
+                "preserve_order": true,
+                "jobs": [
+                    {"template_id": "a.typ"},
+                    {"template_id": "missing.typ"},
+                    {"template_id": "b.typ"},
+                    {"template_id": "c.typ"},
+                ]
+            }),
+        )
+        .await;
+        let template_ids: Vec<&str> = response
+            .results
+            .iter()
+            .map(|result| result.template_id.as_str())
+            .collect();
+        assert_eq!(template_ids, ["a.typ", "missing.typ", "b.typ", "c.typ"]);
+        assert_eq!(
+            statuses(&response),
+            ["success", "error", "success", "success"]
+        );
+    }
+
+    #[test]
+    fn template_fetch_errors_have_distinct_codes() {
+        let code = |e| template_fetch_error("invoice.typ", e).error_code();
+        assert_eq!(
+            code(StoreError::NotFound("invoice.typ".to_string())),
+            "template_not_found"
+        );
+        assert_eq!(
+            code(StoreError::AccessDenied("invoice.typ".to_string())),
+            "template_access_denied"
+        );
+        assert_eq!(code(StoreError::Backend("timeout".to_string())), "s3_error");
+    }
+
+    #[tokio::test]
+    async fn missing_templates_fail_with_template_not_found() {
+        let resources = Arc::new(test_resources());
+        let response = run_batch(
+            &resources,
+            json!({"jobs": [{"template_id": "missing.typ"}]}),
+        )
+        .await;
+        let result = &response.results[0];
+        assert_eq!(result.status, "error");
+        assert_eq!(result.error_code.as_deref(), Some("template_not_found"));
+    }
+
+    #[test]
+    fn log_filter_prefers_rust_log_then_log_level() {
+        let filter = |rust_log: Option<&str>, log_level: Option<&str>| {
+            log_filter_from(rust_log.map(String::from), log_level.map(String::from)).to_string()
+        };
+        assert_eq!(filter(None, None), "info");
+        assert_eq!(filter(Some(""), Some("debug")), "debug");
+        assert_eq!(
+            filter(Some("renderer=debug,aws_sdk_s3=warn"), Some("error")),
+            "aws_sdk_s3=warn,renderer=debug"
+        );
+        assert_eq!(filter(Some("renderer=[bad"), None), "info");
+    }
+
+    #[tokio::test]
+    async fn merge_defaults_applies_template_defaults() {
+        let resources = resources_with_templates(&["invoice.typ"]).await;
+        put(
+            resources.templates.as_ref(),
+            "invoice.typ.defaults.json",
+            r#"{"currency": "EUR", "company": {"name": "Acme", "country": "DE"}}"#,
+        )
+        .await;
+        let data = json!({"company": {"name": "Globex"}});
+
+        let job: RenderJobRequest =
+            serde_json::from_value(json!({"template_id": "invoice.typ", "merge_defaults": true}))
+                .unwrap();
+        assert_eq!(
+            prepare_data(&resources, &job, &data).await.unwrap(),
+            json!({"currency": "EUR", "company": {"name": "Globex", "country": "DE"}})
+        );
+
+        let job: RenderJobRequest =
+            serde_json::from_value(json!({"template_id": "invoice.typ"})).unwrap();
+        assert_eq!(prepare_data(&resources, &job, &data).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn data_array_renders_once_per_element() {
+        let resources = Arc::new(resources_with_templates(&["invoice.typ"]).await);
+        let response = run_batch(
+            &resources,
+            json!({"preserve_order": true, "jobs": [
+                {"template_id": "invoice.typ", "data_array": [{"name": "a"}, {"name": "b"}]},
+                {"template_id": "invoice.typ", "data": {"name": "a"}, "data_array": [{}]},
+            ]}),
+        )
+        .await;
+
+        let result = &response.results[0];
+        assert_eq!(result.status, "success");
+        let keys = result.s3_keys.as_ref().unwrap();
+        assert_eq!(
+            keys,
+            &[
+                format!("{}/0.pdf", result.job_id),
+                format!("{}/1.pdf", result.job_id)
+            ]
+        );
+        for key in keys {
+            assert!(resources
+                .results
+                .get(key)
+                .await
+                .unwrap()
+                .starts_with(b"%PDF"));
+        }
+
+        let result = &response.results[1];
+        assert_eq!(result.status, "error");
+        assert_eq!(result.error_code.as_deref(), Some("invalid_request"));
+    }
+
+    #[tokio::test]
+    async fn combine_uploads_one_merged_pdf() {
+        let resources = Arc::new(resources_with_templates(&["a.typ", "b.typ"]).await);
+        let response = run_batch(
+            &resources,
+            json!({"combine": true, "jobs": [{"template_id": "a.typ"}, {"template_id": "b.typ"}]}),
+        )
+        .await;
+        assert_eq!(statuses(&response), ["success", "success"]);
+        let combined_key = response.combined_s3_key.clone().unwrap();
+        for result in &response.results {
+            assert_eq!(result.s3_key.as_ref(), Some(&combined_key));
+        }
+        let combined = resources.results.get(&combined_key).await.unwrap();
+        assert_eq!(pdf::page_count(&combined).unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn combine_aborts_or_inserts_error_pages_for_failed_jobs() {
+        let resources = Arc::new(resources_with_templates(&["a.typ"]).await);
+        let jobs = json!([{"template_id": "a.typ"}, {"template_id": "missing.typ"}]);
+
+        let response = run_batch(&resources, json!({"combine": true, "jobs": jobs})).await;
+        assert!(response.combined_s3_key.is_none());
+        let codes: Vec<_> = response
+            .results
+            .iter()
+            .map(|result| result.error_code.as_deref())
+            .collect();
+        assert!(codes.contains(&Some("combine_aborted")));
+        assert!(codes.contains(&Some("template_not_found")));
+
+        let response = run_batch(
+            &resources,
+            json!({"combine": true, "on_combine_error": "error_page", "jobs": jobs}),
+        )
+        .await;
+        let combined = resources
+            .results
+            .get(response.combined_s3_key.as_ref().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(pdf::page_count(&combined).unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn endpoint_overrides_use_path_style_addressing() {
+        let s3 = MockS3::default();
+        let store = S3Store::new(build_s3_client(&s3.sdk_config()), "templates");
+        store.get("invoice.typ").await.unwrap();
+
+        let config = s3
+            .sdk_config()
+            .to_builder()
+            .endpoint_url("http://localhost:4566")
+            .build();
+        let store = S3Store::new(build_s3_client(&config), "templates");
+        store.get("invoice.typ").await.unwrap();
+
+        let requests = s3.requests();
+        assert!(requests[0]
+            .uri
+            .starts_with("https://templates.s3.us-east-1.amazonaws.com/invoice.typ"));
+        assert!(requests[1]
+            .uri
+            .starts_with("http://localhost:4566/templates/invoice.typ"));
+    }
+
+    #[test]
+    fn rejects_unknown_request_fields() {
+        let error = serde_json::from_value::<RenderRequest>(json!({
+            "jobs": [],
+            "preserve_ordr": true,
+        }))
+        .unwrap_err();
+        assert!(error.to_string().contains("unknown field `preserve_ordr`"));
+
+        let error = serde_json::from_value::<RenderJobRequest>(json!({
+            "template_id": "invoice.typ",
+            "split_page": true,
+        }))
+        .unwrap_err();
+        assert!(error.to_string().contains("unknown field `split_page`"));
+    }
+
+    #[tokio::test]
+    async fn on_conflict_fails_or_skips_existing_results() {
+        let store = InMemoryStore::default();
+        put(&store, "job-1.pdf", "existing").await;
+        let retry = RetryPolicy {
+            max_attempts: 1,
+            base_delay: Duration::ZERO,
+        };
+        let upload = |on_conflict| {
+            upload_pdf_to_s3(
+                &store,
+                PutOptions::default(),
+                &retry,
+                on_conflict,
+                "job-1",
+                "job-1.pdf",
+                b"%PDF-new".to_vec(),
+            )
+        };
+
+        // The conditional put is refused, it isn't retried
+        let (result, attempts) = upload(OnConflict::Fail).await;
+        assert!(matches!(result, Err(RenderError::ResultExists(key)) if key == "job-1.pdf"));
+        assert_eq!(attempts, 1);
+        assert_eq!(store.get("job-1.pdf").await.unwrap(), b"existing");
+
+        let (result, attempts) = upload(OnConflict::Skip).await;
+        assert_eq!(result.unwrap(), Upload::Kept(8));
+        assert_eq!(attempts, 1);
+        assert_eq!(store.get("job-1.pdf").await.unwrap(), b"existing");
+
+        let (result, _) = upload(OnConflict::Overwrite).await;
+        assert_eq!(result.unwrap(), Upload::Written(8));
+        assert_eq!(store.get("job-1.pdf").await.unwrap(), b"%PDF-new");
+
+        store.delete("job-1.pdf").await.unwrap();
+        let (result, attempts) = upload(OnConflict::Fail).await;
+        assert_eq!(result.unwrap(), Upload::Written(8));
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn list_templates_leaves_out_defaults_and_transforms() {
+        let resources =
+            resources_with_templates(&["invoices/a.typ", "invoices/b.typ", "c.typ"]).await;
+        put(
+            resources.templates.as_ref(),
+            "invoices/a.typ.defaults.json",
+            "{}",
+        )
+        .await;
+        put(
+            resources.templates.as_ref(),
+            "invoices/a.typ.transform.json",
+            "{}",
+        )
+        .await;
+
+        let response = handle_action(
+            &resources,
+            serde_json::from_value(json!({"action": "list_templates", "prefix": "invoices/"}))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        let mut template_ids: Vec<&str> = response["templates"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|template| template["template_id"].as_str().unwrap())
+            .collect();
+        template_ids.sort();
+        assert_eq!(template_ids, ["invoices/a.typ", "invoices/b.typ"]);
+        assert_eq!(response["templates"][0]["size"], json!(TEMPLATE.len()));
+    }
+
+    fn s3_record(event_name: &str, key: &str) -> Value {
+        json!({
+            "eventName": event_name,
+            "eventTime": "2024-01-01T00:00:00.000Z",
+            "userIdentity": {},
+            "requestParameters": {},
+            "s3": {"bucket": {"name": "uploads"}, "object": {"key": key, "size": 16}},
+        })
+    }
+
+    #[tokio::test]
+    async fn s3_events_render_created_data_objects() {
+        let s3 = MockS3::default();
+        s3.respond(200, r#"{"name": "Acme"}"#);
+        let mut resources = resources_with_templates(&["invoice.typ"]).await;
+        resources.s3_client = s3.client();
+
+        let event: S3Event = serde_json::from_value(json!({"Records": [
+            s3_record("ObjectRemoved:Delete", "incoming/invoice.typ/old.json"),
+            s3_record("ObjectCreated:Put", "elsewhere/invoice.typ/a.json"),
+            s3_record("ObjectCreated:Put", "incoming/invoice.typ/acme%2D2024.json"),
+        ]}))
+        .unwrap();
+        let response = handle_s3_event(&resources, event).await.unwrap();
+
+        let results = response["results"].as_array().unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["status"], "success");
+        assert_eq!(results[0]["s3_key"], "rendered/invoice.typ/acme-2024.pdf");
+        assert!(resources
+            .results
+            .get("rendered/invoice.typ/acme-2024.pdf")
+            .await
+            .is_ok());
+
+        let requests = s3.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].uri.contains("uploads"));
+        assert!(requests[0]
+            .uri
+            .contains("/incoming/invoice.typ/acme-2024.json"));
+    }
+
+    #[tokio::test]
+    async fn malformed_jobs_only_fail_themselves() {
+        let resources = Arc::new(resources_with_templates(&["invoice.typ"]).await);
+        let response = run_batch(
+            &resources,
+            json!({"preserve_order": true, "jobs": [
+                {"template_id": "invoice.typ"},
+                {"template_id": "invoice.typ", "split_pages": "yes"},
+                42,
+                {"template_id": "invoice.typ"},
+            ]}),
+        )
+        .await;
+        assert_eq!(
+            statuses(&response),
+            ["success", "error", "error", "success"]
+        );
+        for result in &response.results[1..3] {
+            assert_eq!(result.error_code.as_deref(), Some("job_parse_error"));
+        }
+    }
+
+    #[test]
+    fn overloaded_requests_are_rejected_with_429() {
+        let response =
+            RenderError::Overloaded("limit of 2 concurrent requests reached".to_string())
+                .to_response();
+        assert_eq!(response["statusCode"], 429);
+        let body: Value = serde_json::from_str(response["body"].as_str().unwrap()).unwrap();
+        assert_eq!(body["error_code"], "overloaded");
+    }
+
+    // Name and fields of a recorded span
+    type RecordedSpan = (String, HashMap<String, String>);
+
+    // Layer keeping the fields of every span created while it is installed
+    #[derive(Clone, Default)]
+    struct SpanRecorder {
+        spans: Arc<std::sync::Mutex<HashMap<u64, RecordedSpan>>>,
+    }
+
+    struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+    impl tracing::field::Visit for FieldVisitor<'_> {
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanRecorder {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            id: &tracing::span::Id,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = HashMap::new();
+            attrs.record(&mut FieldVisitor(&mut fields));
+            let name = attrs.metadata().name().to_string();
+            self.spans
+                .lock()
+                .unwrap()
+                .insert(id.into_u64(), (name, fields));
+        }
+
+        fn on_record(
+            &self,
+            id: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            if let Some((_, fields)) = self.spans.lock().unwrap().get_mut(&id.into_u64()) {
+                values.record(&mut FieldVisitor(fields));
+            }
+        }
+    }
+
+    impl SpanRecorder {
+        // Fields of the first recorded span with the given name
+        fn fields(&self, name: &str) -> HashMap<String, String> {
+            self.spans
+                .lock()
+                .unwrap()
+                .values()
+                .find(|(span_name, _)| span_name == name)
+                .map(|(_, fields)| fields.clone())
+                .unwrap_or_else(|| panic!("no {} span recorded", name))
+        }
+    }
+
+    #[tokio::test]
+    async fn s3_spans_record_bucket_key_and_bytes() {
+        let recorder = SpanRecorder::default();
+        let _subscriber =
+            tracing::subscriber::set_default(Registry::default().with(recorder.clone()));
+
+        let resources = resources_with_templates(&["invoice.typ"]).await;
+        get_cached_template(&resources, "invoice.typ")
+            .await
+            .unwrap();
+        let fetch = recorder.fields("s3_template_fetch");
+        assert_eq!(fetch["bucket"], "memory");
+        assert_eq!(fetch["key"], "invoice.typ");
+        assert_eq!(fetch["bytes"], TEMPLATE.len().to_string());
+        assert_eq!(
+            recorder.fields("template_compile")["source_bytes"],
+            TEMPLATE.len().to_string()
+        );
+
+        let (result, _) = upload_pdf_to_s3(
+            resources.results.as_ref(),
+            PutOptions::default(),
+            &resources.upload_retry,
+            OnConflict::Overwrite,
+            "job-1",
+            "job-1.pdf",
+            b"%PDF-1.7".to_vec(),
+        )
+        .await;
+        result.unwrap();
+        let upload = recorder.fields("s3_pdf_upload");
+        assert_eq!(upload["job_id"], "job-1");
+        assert_eq!(upload["key"], "job-1.pdf");
+        assert_eq!(upload["bytes"], "8");
+    }
+
+    #[test]
+    fn detects_msgpack_accept_headers() {
+        let accepts =
+            |accept: &str| accepts_msgpack(&function_url_request("", false, &[("accept", accept)]));
+        assert!(accepts("application/msgpack"));
+        assert!(accepts("application/json;q=0.5, Application/X-MsgPack;q=1"));
+        assert!(!accepts("application/json"));
+        assert!(!accepts_msgpack(&function_url_request("", false, &[])));
+    }
+
+    #[tokio::test]
+    async fn returns_msgpack_batch_responses() {
+        let resources = Arc::new(resources_with_templates(&["invoice.typ"]).await);
+        let response = handle_function_url(
+            &resources,
+            function_url_request(
+                r#"{"jobs": [{"template_id": "invoice.typ"}]}"#,
+                false,
+                &[("accept", "application/msgpack")],
+            ),
+            SystemTime::now() + Duration::from_secs(600),
+            "test-request".to_string(),
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response["headers"]["content-type"], "application/msgpack");
+        assert_eq!(response["isBase64Encoded"], true);
+        let body = BASE64.decode(response["body"].as_str().unwrap()).unwrap();
+        let body: Value = rmp_serde::from_slice(&body).unwrap();
+        assert_eq!(body["request_id"], "test-request");
+        assert_eq!(body["results"][0]["status"], "success");
+    }
+
+    fn provider_with(exporter: CountingExporter) -> SdkTracerProvider {
+        SdkTracerProvider::builder()
+            .with_span_processor(CountingBatchProcessor::new(exporter, 16))
+            .build()
+    }
+
+    fn end_span(provider: &SdkTracerProvider) {
+        use opentelemetry::trace::{Span as _, Tracer as _};
+        provider.tracer("test").start("render_job").end();
+    }
+
+    #[tokio::test]
+    async fn flush_exports_queued_spans() {
+        let exporter = CountingExporter::default();
+        let provider = provider_with(exporter.clone());
+        end_span(&provider);
+
+        flush_traces(&provider, Duration::from_secs(5)).await;
+        assert_eq!(exporter.exported(), 1);
+
+        end_span(&provider);
+        shutdown_traces(&provider, Duration::from_secs(5)).await;
+        assert_eq!(exporter.exported(), 2);
+    }
+
+    #[tokio::test]
+    async fn flush_gives_up_after_its_timeout() {
+        let exporter = CountingExporter::with_delay(Duration::from_millis(500));
+        let provider = provider_with(exporter);
+        end_span(&provider);
+
+        let started = std::time::Instant::now();
+        flush_traces(&provider, Duration::from_millis(50)).await;
+        assert!(started.elapsed() < Duration::from_millis(400));
+    }
+
+    #[test]
+    fn invalid_otlp_endpoints_disable_trace_export() {
+        assert!(tracer_provider(None).is_none());
+        assert!(tracer_provider(Some(String::new())).is_none());
+        assert!(tracer_provider(Some("not a url".to_string())).is_none());
+    }
+
+    #[tokio::test]
+    async fn unreachable_collectors_dont_fail_renders() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        // Nothing listens on the discard port, so every export fails
+        let provider = tracer_provider(Some("http://127.0.0.1:9/v1/traces".to_string()))
+            .expect("a well-formed endpoint builds a provider");
+        let subscriber = Registry::default()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let resources = Arc::new(resources_with_templates(&["invoice.typ"]).await);
+        let response = run_batch(
+            &resources,
+            json!({"jobs": [{"template_id": "invoice.typ"}]}),
+        )
+        .await;
+        assert_eq!(statuses(&response), ["success"]);
+
+        let started = std::time::Instant::now();
+        flush_traces(&provider, Duration::from_millis(200)).await;
+        assert!(started.elapsed() < Duration::from_secs(2));
+        shutdown_traces(&provider, Duration::from_millis(200)).await;
+    }
+
+    #[tokio::test]
+    async fn svg_jobs_upload_one_svg_per_page() {
+        let s3 = MockS3::default();
+        let mut resources = resources_with_templates(&["invoice.typ"]).await;
+        resources.results = Arc::new(S3Store::new(s3.client(), "results"));
+        let resources = Arc::new(resources);
+
+        let response = run_batch(
+            &resources,
+            json!({"preserve_order": true, "jobs": [
+                {"template_id": "invoice.typ", "format": "svg"},
+                {"template_id": "invoice.typ", "format": "svg", "split_pages": true},
+            ]}),
+        )
+        .await;
+
+        let result = &response.results[0];
+        assert_eq!(result.status, "success");
+        assert_eq!(
+            result.s3_keys.as_deref(),
+            Some(&[format!("{}/page-1.svg", result.job_id)][..])
+        );
+        assert_eq!(result.page_count, Some(1));
+        let requests = s3.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0]
+            .uri
+            .contains(&format!("/{}/page-1.svg", result.job_id)));
+        assert_eq!(requests[0].header("content-type"), Some("image/svg+xml"));
+        assert!(requests[0].body.starts_with(b"<svg"));
+
+        assert_eq!(
+            response.results[1].error_code.as_deref(),
+            Some("invalid_request")
+        );
+    }
+
+    #[tokio::test]
+    async fn batches_fetch_each_template_once() {
+        let templates = Arc::new(TestStore::default());
+        put(templates.as_ref(), "a.typ", TEMPLATE).await;
+        put(templates.as_ref(), "b.typ", TEMPLATE).await;
+        let mut resources = test_resources();
+        resources.templates = templates.clone();
+        let resources = Arc::new(resources);
+
+        let jobs: Vec<Value> = (0..10)
+            .map(|i| json!({"template_id": if i % 2 == 0 { "a.typ" } else { "b.typ" }}))
+            .collect();
+        let response = run_batch(&resources, json!({ "jobs": jobs })).await;
+
+        assert_eq!(statuses(&response), ["success"; 10]);
+        assert_eq!(templates.gets_of("a.typ"), 1);
+        assert_eq!(templates.gets_of("b.typ"), 1);
+    }
+
+    #[tokio::test]
+    async fn metadata_is_written_into_the_pdf() {
+        let resources = Arc::new(resources_with_templates(&["invoice.typ"]).await);
+        let response = run_batch(
+            &resources,
+            json!({"jobs": [{
+                "template_id": "invoice.typ",
+                "metadata": {"title": "Invoice 42", "author": "Acme"},
+            }]}),
+        )
+        .await;
+        let result = &response.results[0];
+        let pdf = resources
+            .results
+            .get(result.s3_key.as_ref().unwrap())
+            .await
+            .unwrap();
+
+        let document = lopdf::Document::load_mem(&pdf).unwrap();
+        let info_id = document
+            .trailer
+            .get(b"Info")
+            .unwrap()
+            .as_reference()
+            .unwrap();
+        let info = document.get_dictionary(info_id).unwrap();
+        let entry = |key: &[u8]| info.get(key).unwrap().as_str().unwrap().to_vec();
+        assert_eq!(entry(b"Title"), b"Invoice 42");
+        assert_eq!(entry(b"Author"), b"Acme");
+        assert_eq!(entry(b"X-Job-Id"), result.job_id.as_bytes());
+        assert_eq!(entry(b"X-Template-Id"), b"invoice.typ");
+    }
+
+    #[tokio::test]
+    async fn fingerprint_only_batches_hash_without_uploading() {
+        let resources = Arc::new(resources_with_templates(&["invoice.typ"]).await);
+        let response = run_batch(
+            &resources,
+            json!({"fingerprint_only": true, "jobs": [{"template_id": "invoice.typ"}]}),
+        )
+        .await;
+
+        let result = &response.results[0];
+        assert_eq!(result.status, "success");
+        assert_eq!(result.attempts, 0);
+        assert!(result.s3_key.is_none() && result.bucket.is_none());
+        let sha256 = result.sha256.as_ref().unwrap();
+        assert_eq!(sha256.len(), 64);
+        assert!(sha256.chars().all(|c| c.is_ascii_hexdigit()));
+        assert!(result.file_size.unwrap() > 0);
+        assert_eq!(result.page_count, Some(1));
+        assert!(resources.results.list("").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn tenant_jobs_upload_to_the_tenant_bucket() {
+        let mut resources = resources_with_templates(&["invoice.typ"]).await;
+        let tenant_store: Arc<dyn ObjectStore> = Arc::new(InMemoryStore::default());
+        resources
+            .tenant_results
+            .insert("acme".to_string(), Arc::clone(&tenant_store));
+        let resources = Arc::new(resources);
+        let request = |tenant_id: Option<&str>| json!({"tenant_id": tenant_id, "jobs": [{"template_id": "invoice.typ"}]});
+
+        let response = run_batch(&resources, request(Some("acme"))).await;
+        assert_eq!(statuses(&response), ["success"]);
+        assert_eq!(tenant_store.list("").await.unwrap().len(), 1);
+        assert!(resources.results.list("").await.unwrap().is_empty());
+
+        // Unmapped tenants and requests without one use RESULTS_BUCKET
+        run_batch(&resources, request(Some("other"))).await;
+        run_batch(&resources, request(None)).await;
+        assert_eq!(tenant_store.list("").await.unwrap().len(), 1);
+        assert_eq!(resources.results.list("").await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn oversized_templates_are_refused_before_download() {
+        let store = Arc::new(TestStore::default());
+        let mut resources = test_resources();
+        resources.templates = Arc::clone(&store) as Arc<dyn ObjectStore>;
+        resources.max_template_bytes = 64;
+        put(store.as_ref(), "big.typ", vec![b'a'; 65]).await;
+        put(store.as_ref(), "small.typ", TEMPLATE).await;
+
+        let error = get_cached_template(&resources, "big.typ")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            RenderError::TemplateTooLarge {
+                size: 65,
+                max_bytes: 64,
+                ..
+            }
+        ));
+        assert_eq!(error.error_code(), "template_too_large");
+        assert_eq!(store.gets_of("big.typ"), 0);
+
+        // Exactly at the limit is fine
+        resources.max_template_bytes = TEMPLATE.len() as u64;
+        assert!(get_cached_template(&resources, "small.typ").await.is_ok());
+    }
+
+    #[test]
+    fn echoes_reasonable_client_request_ids_only() {
+        let request_id = |value: &str| {
+            client_request_id(&function_url_request("", false, &[("x-request-id", value)]))
+        };
+        assert_eq!(request_id(" abc-123 "), Some("abc-123".to_string()));
+        assert_eq!(
+            request_id(&"a".repeat(MAX_REQUEST_ID_LEN)).unwrap().len(),
+            128
+        );
+        assert_eq!(request_id(&"a".repeat(MAX_REQUEST_ID_LEN + 1)), None);
+        assert_eq!(request_id("two words"), None);
+        assert_eq!(request_id("   "), None);
+        // Ids name the batch manifest's directory
+        assert_eq!(request_id("req_1.2"), Some("req_1.2".to_string()));
+        for unsafe_id in ["a/b", "..", ".", "a:b", "a%2F"] {
+            assert_eq!(request_id(unsafe_id), None, "{}", unsafe_id);
+        }
+        assert_eq!(
+            client_request_id(&function_url_request("", false, &[])),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn binary_uploads_are_not_templates() {
+        assert_eq!(sniff_binary_type(b"%PDF-1.7\n"), Some("PDF"));
+        assert_eq!(sniff_binary_type(b"\x89PNG\r\n\x1a\n...."), Some("PNG"));
+        assert_eq!(sniff_binary_type(b"PK\x03\x04"), Some("ZIP"));
+        assert_eq!(sniff_binary_type(TEMPLATE.as_bytes()), None);
+        assert_eq!(sniff_binary_type(b""), None);
+
+        let resources = test_resources();
+        put(
+            resources.templates.as_ref(),
+            "logo.typ",
+            b"\xff\xd8\xff\xe0JFIF".to_vec(),
+        )
+        .await;
+        let error = get_cached_template(&resources, "logo.typ")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            RenderError::NotATemplate {
+                detected_type: "JPEG",
+                ..
+            }
+        ));
+        assert_eq!(error.error_code(), "not_a_template");
+    }
+
+    #[tokio::test]
+    async fn jobs_without_data_render_with_an_empty_object() {
+        let job: RenderJobRequest =
+            serde_json::from_value(json!({"template_id": "static.typ"})).unwrap();
+        assert_eq!(job.data, None);
+        let job: RenderJobRequest =
+            serde_json::from_value(json!({"template_id": "static.typ", "data": null})).unwrap();
+        assert_eq!(job.data, None);
+
+        let resources = Arc::new(resources_with_templates(&["static.typ"]).await);
+        let response = run_batch(
+            &resources,
+            json!({"jobs": [
+                {"template_id": "static.typ"},
+                {"template_id": "static.typ", "data": {}, "data_array": [{}]},
+            ], "preserve_order": true}),
+        )
+        .await;
+        assert_eq!(statuses(&response), ["success", "error"]);
+        assert!(response.results[1]
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("Only one of data and data_array"));
+    }
+
+    #[tokio::test]
+    async fn invalid_keys_are_rejected_before_calling_s3() {
+        assert!(validate_key("invoices/2024/inv-1_a.pdf").is_ok());
+        for key in [
+            "",
+            "/abs.typ",
+            "a//b.typ",
+            "a/./b.typ",
+            "../secrets.typ",
+            "a/..",
+            "trailing/",
+            "with space.typ",
+            "ünïcode.typ",
+        ] {
+            let error = validate_key(key).unwrap_err();
+            assert_eq!(error.error_code(), "invalid_key", "{:?}", key);
+        }
+        assert!(validate_key(&"a".repeat(MAX_KEY_LEN)).is_ok());
+        assert!(validate_key(&"a".repeat(MAX_KEY_LEN + 1)).is_err());
+
+        let store = Arc::new(TestStore::default());
+        let mut resources = test_resources();
+        resources.templates = Arc::clone(&store) as Arc<dyn ObjectStore>;
+        assert!(get_cached_template(&resources, "../secrets.typ")
+            .await
+            .is_err());
+        assert!(store.gets.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn page_count_covers_every_output_of_a_job() {
+        let resources = test_resources();
+        put(
+            resources.templates.as_ref(),
+            "long.typ",
+            "= Long\n#pagebreak()\nTwo\n#pagebreak()\nThree",
+        )
+        .await;
+        put(resources.templates.as_ref(), "short.typ", TEMPLATE).await;
+        let resources = Arc::new(resources);
+
+        let response = run_batch(
+            &resources,
+            json!({"preserve_order": true, "jobs": [
+                {"template_id": "long.typ"},
+                {"template_id": "short.typ", "data_array": [{}, {}]},
+            ]}),
+        )
+        .await;
+        let page_counts: Vec<_> = response.results.iter().map(|r| r.page_count).collect();
+        assert_eq!(page_counts, [Some(3), Some(2)]);
+
+        // Combined jobs report their own pages
+        let response = run_batch(
+            &resources,
+            json!({"combine": true, "preserve_order": true, "jobs": [
+                {"template_id": "long.typ"},
+                {"template_id": "short.typ"},
+            ]}),
+        )
+        .await;
+        let page_counts: Vec<_> = response.results.iter().map(|r| r.page_count).collect();
+        assert_eq!(page_counts, [Some(3), Some(1)]);
+        let combined = resources
+            .results
+            .get(response.combined_s3_key.as_ref().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(pdf::page_count(&combined).unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn empty_and_whitespace_bodies_are_reported_explicitly() {
+        let resources = Arc::new(test_resources());
+        let deadline = SystemTime::now() + Duration::from_secs(600);
+        for (body, message) in [
+            ("", "Request body is empty"),
+            (" \n\t", "Request body contains only whitespace"),
+        ] {
+            let error = render_function_url_request(
+                &resources,
+                function_url_request(body, false, &[]),
+                deadline,
+                "test-request".to_string(),
+                &CancellationToken::new(),
+            )
+            .await
+            .unwrap_err();
+            assert!(
+                matches!(&error, RenderError::InvalidRequest(m) if m == message),
+                "{:?}",
+                error
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn renders_wait_for_a_global_render_permit() {
+        let mut resources = resources_with_templates(&["invoice.typ"]).await;
+        resources.render_permits = Arc::new(Semaphore::new(1));
+        let resources = Arc::new(resources);
+        let held = Arc::clone(&resources.render_permits)
+            .acquire_owned()
+            .await
+            .unwrap();
+
+        let batch = tokio::spawn({
+            let resources = Arc::clone(&resources);
+            async move {
+                run_batch(
+                    &resources,
+                    json!({"jobs": [{"template_id": "invoice.typ"}]}),
+                )
+                .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!batch.is_finished());
+        assert!(resources.results.list("").await.unwrap().is_empty());
+
+        drop(held);
+        assert_eq!(statuses(&batch.await.unwrap()), ["success"]);
+        assert_eq!(resources.render_permits.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn batch_manifests_record_the_response() {
+        let store = Arc::new(TestStore::default());
+        let mut resources = resources_with_templates(&["invoice.typ"]).await;
+        resources.results = Arc::clone(&store) as Arc<dyn ObjectStore>;
+        resources.batch_manifest_prefix = Some("manifests/".to_string());
+        let resources = Arc::new(resources);
+
+        let response = run_batch(
+            &resources,
+            json!({"jobs": [{"template_id": "invoice.typ"}]}),
+        )
+        .await;
+        let manifest_key = response.manifest_s3_key.clone().unwrap();
+        assert_eq!(manifest_key, "manifests/test-request/manifest.json");
+        let manifest: Value =
+            serde_json::from_slice(&store.inner.get(&manifest_key).await.unwrap()).unwrap();
+        assert_eq!(manifest, serde_json::to_value(&response).unwrap());
+
+        // A failed manifest upload leaves the key unset, without failing the batch
+        store.failing_puts.store(1, Ordering::Relaxed);
+        let response = run_batch(&resources, json!({"jobs": []})).await;
+        assert_eq!(response.manifest_s3_key, None);
+
+        // Fingerprint-only batches write nothing
+        let response = run_batch(
+            &resources,
+            json!({"fingerprint_only": true, "jobs": [{"template_id": "invoice.typ"}]}),
+        )
+        .await;
+        assert_eq!(response.manifest_s3_key, None);
+    }
+
+    #[tokio::test]
+    async fn gzip_compressed_templates_are_inflated() {
+        assert_eq!(
+            decompress_template("a.typ", gzip(TEMPLATE.as_bytes()), 1024).unwrap(),
+            TEMPLATE.as_bytes()
+        );
+        assert_eq!(
+            decompress_template("a.typ", TEMPLATE.as_bytes().to_vec(), 1024).unwrap(),
+            TEMPLATE.as_bytes()
+        );
+
+        // The inflated size is bounded too, so small gzip bombs are refused
+        let bomb = gzip(&vec![b'a'; 4096]);
+        assert!(bomb.len() < 1024);
+        assert!(matches!(
+            decompress_template("bomb.typ", bomb, 1024),
+            Err(RenderError::TemplateTooLarge {
+                size: 1025,
+                max_bytes: 1024,
+                ..
+            })
+        ));
+
+        let mut truncated = gzip(TEMPLATE.as_bytes());
+        truncated.truncate(truncated.len() / 2);
+        assert!(matches!(
+            decompress_template("a.typ", truncated, 1024),
+            Err(RenderError::RenderingError(_))
+        ));
+
+        let resources = Arc::new(test_resources());
+        put(
+            resources.templates.as_ref(),
+            "zipped.typ",
+            gzip(TEMPLATE.as_bytes()),
+        )
+        .await;
+        let response =
+            run_batch(&resources, json!({"jobs": [{"template_id": "zipped.typ"}]})).await;
+        assert_eq!(statuses(&response), ["success"]);
+    }
+
+    #[tokio::test]
+    async fn jobs_upload_to_allowed_output_buckets_only() {
+        let mut resources = resources_with_templates(&["invoice.typ"]).await;
+        let archive: Arc<dyn ObjectStore> = Arc::new(InMemoryStore::default());
+        resources
+            .output_buckets
+            .insert("archive".to_string(), Arc::clone(&archive));
+        let resources = Arc::new(resources);
+
+        let response = run_batch(
+            &resources,
+            json!({"preserve_order": true, "jobs": [
+                {"template_id": "invoice.typ", "output_bucket": "archive"},
+                {"template_id": "invoice.typ", "output_bucket": "elsewhere"},
+                {"template_id": "invoice.typ"},
+            ]}),
+        )
+        .await;
+        assert_eq!(statuses(&response), ["success", "error", "success"]);
+        assert!(response.results[1]
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("not in OUTPUT_BUCKET_ALLOWLIST"));
+        let archived = archive.list("").await.unwrap();
+        assert_eq!(archived.len(), 1);
+        assert_eq!(Some(&archived[0].key), response.results[0].s3_key.as_ref());
+        assert_eq!(resources.results.list("").await.unwrap().len(), 1);
+
+        let response = run_batch(
+            &resources,
+            json!({"combine": true, "jobs": [{"template_id": "invoice.typ", "output_bucket": "archive"}]}),
+        )
+        .await;
+        assert!(response.results[0]
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("can't be used in combined batches"));
+    }
+
+    #[test]
+    fn job_time_budget_splits_the_remaining_time() {
+        let margin = Duration::from_secs(1);
+        let deadline = SystemTime::now() + Duration::from_secs(11);
+        let budget = job_time_budget(deadline, margin, 4);
+        assert!(budget <= Duration::from_millis(2500));
+        assert!(budget > Duration::from_millis(2400));
+
+        // No jobs left counts as one; a passed deadline leaves nothing
+        let budget = job_time_budget(deadline, margin, 0);
+        assert!(budget > Duration::from_millis(9900));
+        let past = SystemTime::now() - Duration::from_secs(1);
+        assert_eq!(job_time_budget(past, margin, 3), Duration::ZERO);
+        let close = SystemTime::now() + Duration::from_millis(500);
+        assert_eq!(job_time_budget(close, margin, 1), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn rollback_deletes_the_uploads_of_a_failed_batch() {
+        let resources = Arc::new(resources_with_templates(&["invoice.typ"]).await);
+        let request = |rollback: bool| {
+            json!({"failure_threshold": 0, "rollback": rollback, "preserve_order": true, "jobs": [
+                {"template_id": "invoice.typ", "data_array": [{}, {}]},
+                {"template_id": "invoice.typ"},
+                {"template_id": "missing.typ"},
+            ]})
+        };
+
+        let response = run_batch(&resources, request(true)).await;
+        assert_eq!(statuses(&response), ["rolled_back", "rolled_back", "error"]);
+        assert_eq!(response.summary.rolled_back, 2);
+        for result in &response.results[..2] {
+            assert_eq!(result.error_code.as_deref(), Some("batch_failed"));
+            assert!(result.s3_key.is_none() && result.s3_keys.is_none());
+        }
+        assert!(resources.results.list("").await.unwrap().is_empty());
+
+        // Without rollback the uploads stay, only the batch is reported failed
+        let response = run_batch(&resources, request(false)).await;
+        assert_eq!(statuses(&response), ["success", "success", "error"]);
+        assert_eq!(resources.results.list("").await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn cache_stats_count_hits_and_misses() {
+        let resources = resources_with_templates(&["invoice.typ"]).await;
+        let job = RenderJobRequest {
+            template_id: "invoice.typ".to_string(),
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            let outputs = render_outputs(&resources, &job, json!({})).await.unwrap();
+            assert_eq!(outputs.len(), 1);
+        }
+
+        let stats = handle_action(&resources, ActionRequest::CacheStats)
+            .await
+            .unwrap();
+        assert_eq!(stats["template_cache"]["entries"], 1);
+        assert_eq!(stats["template_cache"]["hits"], 1);
+        assert_eq!(stats["template_cache"]["misses"], 1);
+        assert_eq!(stats["template_cache"]["evictions"], 0);
+    }
+
+    #[tokio::test]
+    async fn cache_stats_count_evicted_templates() {
+        let resources = resources_with_templates(&["invoice.typ", "other.typ"]).await;
+        get_cached_template(&resources, "invoice.typ")
+            .await
+            .unwrap();
+        get_cached_template(&resources, "other.typ").await.unwrap();
+
+        evict_template(&resources, "invoice.typ").await;
+        // Evicting a template that isn't cached doesn't count
+        evict_template(&resources, "missing.typ").await;
+
+        let stats = handle_action(&resources, ActionRequest::CacheStats)
+            .await
+            .unwrap();
+        assert_eq!(stats["template_cache"]["entries"], 1);
+        assert_eq!(stats["template_cache"]["misses"], 2);
+        assert_eq!(stats["template_cache"]["evictions"], 1);
+    }
+
+    #[test]
+    fn template_selector_picks_the_template_from_data() {
+        let job = |value: Value| {
+            serde_json::from_value::<RenderJobRequest>(value)
+                .unwrap()
+                .with_selected_template()
+        };
+        let selector = json!({
+            "field": "address.country",
+            "templates": {"DE": "invoice-de.typ", "true": "flagged.typ", "7": "seven.typ"},
+            "default": "invoice.typ",
+        });
+
+        let selected = |data: Value| {
+            job(json!({"template_selector": selector, "data": data}))
+                .unwrap()
+                .template_id
+        };
+        assert_eq!(
+            selected(json!({"address": {"country": "DE"}})),
+            "invoice-de.typ"
+        );
+        assert_eq!(
+            selected(json!({"address": {"country": "FR"}})),
+            "invoice.typ"
+        );
+        assert_eq!(selected(json!({})), "invoice.typ");
+        assert_eq!(
+            selected(json!({"address": {"country": true}})),
+            "flagged.typ"
+        );
+        assert_eq!(selected(json!({"address": {"country": 7}})), "seven.typ");
+
+        // Without a default, unmatched jobs fail
+        let error = job(json!({
+            "template_selector": {"field": "/address/country", "templates": {"DE": "invoice-de.typ"}},
+            "data": {"address": {"country": "FR"}},
+        }))
+        .unwrap_err();
+        assert_eq!(error.error_code(), "invalid_request");
+
+        for invalid in [
+            json!({}),
+            json!({"template_id": "a.typ", "template_selector": selector}),
+            json!({"template_selector": selector, "data_array": [{}]}),
+        ] {
+            let error = job(invalid).unwrap_err();
+            assert_eq!(error.error_code(), "job_parse_error");
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_initialization_is_reported_as_unavailable() {
+        let task = tokio::spawn(async {
+            panic!("TEMPLATES_BUCKET environment variable must be set");
+        });
+        let error = initialization_error(task.await.unwrap_err());
+        assert_eq!(error.status_code(), 503);
+        assert!(matches!(
+            error,
+            RenderError::Unavailable(ref message)
+                if message == "Failed to initialize: TEMPLATES_BUCKET environment variable must be set"
+        ));
+
+        let task = tokio::spawn(std::future::pending::<()>());
+        task.abort();
+        let error = initialization_error(task.await.unwrap_err());
+        assert!(matches!(error, RenderError::Unavailable(_)));
+    }
+
+    #[tokio::test]
+    async fn handlers_initialize_resources_once_and_retry_failures() {
+        let event = || {
+            let mut context = lambda_runtime::Context::default();
+            let deadline = SystemTime::now() + Duration::from_secs(60);
+            context.deadline = deadline
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64;
+            let request =
+                function_url_request(r#"{"jobs": [{"template_id": "invoice.typ"}]}"#, false, &[]);
+            LambdaEvent::new(IncomingEvent::FunctionUrl(Box::new(request)), context)
+        };
+        let cell = OnceCell::new();
+        let inits = AtomicU64::new(0);
+        let init = || {
+            inits.fetch_add(1, Ordering::Relaxed);
+            async { Arc::new(resources_with_templates(&["invoice.typ"]).await) }
+        };
+
+        // A failed initialization is a 503 and leaves nothing behind
+        let failing = resources_once(&cell, || async {
+            panic!("TEMPLATES_BUCKET environment variable must be set")
+        });
+        let response = handle_event(failing, event()).await.unwrap();
+        assert_eq!(response["statusCode"], 503);
+        assert!(response["body"]
+            .as_str()
+            .unwrap()
+            .contains("TEMPLATES_BUCKET environment variable must be set"));
+        assert!(cell.get().is_none());
+
+        // The next invocation initializes, later ones reuse the resources
+        for _ in 0..2 {
+            let response = handle_event(resources_once(&cell, init), event())
+                .await
+                .unwrap();
+            assert_eq!(response["summary"]["success"], 1);
+        }
+        assert_eq!(inits.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn output_key_template_names_job_outputs() {
+        let resources = Arc::new(resources_with_templates(&["invoice.typ"]).await);
+        let response = run_batch(
+            &resources,
+            json!({"output_key_template": "invoices/{data.no}.pdf", "preserve_order": true, "jobs": [
+                {"template_id": "invoice.typ", "data": {"no": 7}},
+                {"template_id": "invoice.typ", "data": {"no": "../x"}},
+                {"template_id": "invoice.typ"},
+            ]}),
+        )
+        .await;
+        assert_eq!(statuses(&response), ["success", "error", "error"]);
+        assert_eq!(
+            response.results[0].s3_key.as_deref(),
+            Some("invoices/7.pdf")
+        );
+        assert_eq!(
+            response.results[1].error_code.as_deref(),
+            Some("invalid_key")
+        );
+        assert_eq!(
+            response.results[2].error_code.as_deref(),
+            Some("invalid_request")
+        );
+        assert!(resources.results.get("invoices/7.pdf").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn replicated_jobs_are_copied_to_the_dr_bucket() {
+        let request = json!({"replicate": true, "jobs": [{"template_id": "invoice.typ"}]});
+        let resources = Arc::new(resources_with_templates(&["invoice.typ"]).await);
+        let response = run_batch(&resources, request.clone()).await;
+        assert!(response.results[0]
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("requires DR_RESULTS_BUCKET"));
+
+        let replica = Arc::new(TestStore::default());
+        let mut resources = resources_with_templates(&["invoice.typ"]).await;
+        resources.replica_results = Some(Arc::clone(&replica) as Arc<dyn ObjectStore>);
+        let resources = Arc::new(resources);
+
+        let response = run_batch(&resources, request.clone()).await;
+        let result = &response.results[0];
+        assert_eq!(result.status, "success");
+        assert_eq!(result.replica_bucket.as_deref(), Some("test"));
+        let key = result.s3_key.as_ref().unwrap();
+        assert_eq!(
+            replica.inner.get(key).await.unwrap(),
+            resources.results.get(key).await.unwrap()
+        );
+
+        // A failed copy doesn't fail the job, it's only not reported as replicated
+        replica.failing_puts.store(1, Ordering::Relaxed);
+        let response = run_batch(&resources, request).await;
+        assert_eq!(statuses(&response), ["success"]);
+        assert_eq!(response.results[0].replica_bucket, None);
+
+        let response = run_batch(
+            &resources,
+            json!({"replicate": true, "combine": true, "jobs": [{"template_id": "invoice.typ"}]}),
+        )
+        .await;
+        assert!(response.results[0]
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("can't be used in combined batches"));
+    }
+
+    #[tokio::test]
+    async fn thumbnails_are_uploaded_next_to_the_pdf() {
+        let resources = Arc::new(resources_with_templates(&["invoice.typ"]).await);
+        let response = run_batch(
+            &resources,
+            json!({"preserve_order": true, "jobs": [
+                {"template_id": "invoice.typ", "thumbnail": true},
+                {"template_id": "invoice.typ", "thumbnail": true, "thumbnail_dpi": 301},
+            ]}),
+        )
+        .await;
+        assert_eq!(statuses(&response), ["success", "error"]);
+        let result = &response.results[0];
+        let thumbnail_key = result.thumbnail_s3_key.as_ref().unwrap();
+        assert_eq!(
+            thumbnail_key,
+            &format!("{}{}", result.job_id, THUMBNAIL_SUFFIX)
+        );
+        let png = resources.results.get(thumbnail_key).await.unwrap();
+        assert!(png.starts_with(b"\x89PNG"));
+        assert_eq!(
+            response.results[1].error_code.as_deref(),
+            Some("invalid_request")
+        );
+    }
+
+    #[test]
+    fn gzips_large_responses_for_callers_accepting_it() {
+        let accepts = |value: &str| {
+            accepts_gzip(&function_url_request(
+                "",
+                false,
+                &[("accept-encoding", value)],
+            ))
+        };
+        assert!(accepts("gzip"));
+        assert!(accepts("br;q=1.0, GZIP;q=0.5"));
+        assert!(accepts("x-gzip"));
+        assert!(!accepts("gzip;q=0"));
+        assert!(!accepts("br, deflate"));
+        assert!(!accepts_gzip(&function_url_request("", false, &[])));
+
+        let body = json!({"results": vec!["padding"; 200]});
+        let response = encoded_response(&body, false, true, 1024).unwrap();
+        assert_eq!(response["headers"]["content-encoding"], "gzip");
+        assert_eq!(response["headers"]["content-type"], "application/json");
+        let compressed = BASE64.decode(response["body"].as_str().unwrap()).unwrap();
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&decompressed).unwrap(),
+            body
+        );
+
+        // Bodies under the minimum are sent uncompressed
+        let response = encoded_response(&json!({"jobs": []}), false, true, 1024).unwrap();
+        assert!(response["headers"].get("content-encoding").is_none());
+        let decoded = BASE64.decode(response["body"].as_str().unwrap()).unwrap();
+        assert_eq!(decoded, br#"{"jobs":[]}"#);
+    }
+
+    #[tokio::test]
+    async fn storage_classes_are_checked_when_parsing_jobs() {
+        let job = |storage_class: &str| {
+            serde_json::from_value::<RenderJobRequest>(
+                json!({"template_id": "a.typ", "storage_class": storage_class}),
+            )
+        };
+        assert_eq!(
+            job("STANDARD_IA").unwrap().storage_class,
+            Some(StorageClass::StandardIa)
+        );
+        let error = job("COLD").unwrap_err().to_string();
+        assert!(error.contains("unknown storage class COLD, expected one of"));
+        assert!(error.contains("GLACIER_IR"));
+
+        let resources = Arc::new(resources_with_templates(&["invoice.typ"]).await);
+        let response = run_batch(
+            &resources,
+            json!({"jobs": [{"template_id": "invoice.typ", "storage_class": "cold"}]}),
+        )
+        .await;
+        assert_eq!(
+            response.results[0].error_code.as_deref(),
+            Some("job_parse_error")
+        );
+    }
+
+    #[tokio::test]
+    async fn fetches_job_data_from_data_objects() {
+        let mut resources = resources_with_templates(&["invoice.typ"]).await;
+        resources.max_request_body_bytes = 64;
+        put(
+            resources.data_objects.as_ref(),
+            "data/ok.json",
+            r#"{"name": "Ada"}"#,
+        )
+        .await;
+        put(resources.data_objects.as_ref(), "data/bad.json", "{").await;
+        put(
+            resources.data_objects.as_ref(),
+            "data/big.json",
+            vec![b' '; 65],
+        )
+        .await;
+
+        assert_eq!(
+            fetch_data_object(&resources, "data/ok.json").await.unwrap(),
+            json!({"name": "Ada"})
+        );
+        let error_code = |result: Result<Value, RenderError>| result.unwrap_err().error_code();
+        assert_eq!(
+            error_code(fetch_data_object(&resources, "data/missing.json").await),
+            "data_not_found"
+        );
+        assert_eq!(
+            error_code(fetch_data_object(&resources, "data/bad.json").await),
+            "invalid_request"
+        );
+        assert!(matches!(
+            fetch_data_object(&resources, "data/big.json").await,
+            Err(RenderError::PayloadTooLarge(_))
+        ));
+
+        let resources = Arc::new(resources);
+        let response = run_batch(
+            &resources,
+            json!({"preserve_order": true, "jobs": [
+                {"template_id": "invoice.typ", "data_key": "data/ok.json"},
+                {"template_id": "invoice.typ", "data_key": "data/ok.json", "data": {}},
+            ]}),
+        )
+        .await;
+        assert_eq!(statuses(&response), ["success", "error"]);
+    }
+
+    #[tokio::test]
+    async fn uploads_are_limited_by_upload_permits() {
+        let store = Arc::new(TestStore {
+            put_delay: Some(Duration::from_millis(20)),
+            ..Default::default()
+        });
+        let mut resources = resources_with_templates(&["invoice.typ"]).await;
+        resources.results = Arc::clone(&store) as Arc<dyn ObjectStore>;
+        resources.upload_permits = Arc::new(Semaphore::new(2));
+        let resources = Arc::new(resources);
+
+        let jobs: Vec<Value> = (0..6)
+            .map(|_| json!({"template_id": "invoice.typ"}))
+            .collect();
+        let response = run_batch(&resources, json!({ "jobs": jobs })).await;
+        assert_eq!(response.summary.success, 6);
+        assert_eq!(store.max_running_puts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn higher_priority_jobs_render_first() {
+        assert_eq!(job_priority(&json!({"priority": 5})), 5);
+        assert_eq!(job_priority(&json!({"priority": "high"})), 0);
+        assert_eq!(job_priority(&json!({})), 0);
+
+        let data = Arc::new(TestStore::default());
+        let mut resources = resources_with_templates(&["invoice.typ"]).await;
+        resources.data_objects = Arc::clone(&data) as Arc<dyn ObjectStore>;
+        for key in ["low", "default-a", "high", "default-b"] {
+            put(data.as_ref(), key, "{}").await;
+        }
+        let resources = Arc::new(resources);
+
+        let response = run_batch(
+            &resources,
+            json!({"jobs": [
+                {"template_id": "invoice.typ", "data_key": "low", "priority": -1},
+                {"template_id": "invoice.typ", "data_key": "default-a"},
+                {"template_id": "invoice.typ", "data_key": "high", "priority": 10},
+                {"template_id": "invoice.typ", "data_key": "default-b"},
+            ]}),
+        )
+        .await;
+        assert_eq!(response.summary.success, 4);
+        // Ties keep their request order
+        assert_eq!(
+            *data.gets.lock().unwrap(),
+            ["high", "default-a", "default-b", "low"]
+        );
+    }
+
+    #[tokio::test]
+    async fn store_request_uploads_a_record_of_the_job() {
+        let resources = Arc::new(resources_with_templates(&["invoice.typ"]).await);
+        let job =
+            json!({"template_id": "invoice.typ", "data": {"name": "Ada"}, "store_request": true});
+        let response = run_batch(&resources, json!({ "jobs": [job.clone()] })).await;
+
+        let result = &response.results[0];
+        let record_key = result.request_s3_key.as_ref().unwrap();
+        assert_eq!(
+            record_key,
+            &format!("{}{}", result.job_id, REQUEST_RECORD_SUFFIX)
+        );
+        assert_ne!(result.s3_key.as_ref(), Some(record_key));
+        let record: Value =
+            serde_json::from_slice(&resources.results.get(record_key).await.unwrap()).unwrap();
+        assert_eq!(
+            record,
+            json!({
+                "job_id": result.job_id,
+                "template_id": "invoice.typ",
+                "template_sha256": hex::encode(Sha256::digest(TEMPLATE)),
+                "request": job,
+            })
+        );
+
+        // Fingerprint-only batches don't write records
+        let response =
+            run_batch(&resources, json!({"fingerprint_only": true, "jobs": [job]})).await;
+        assert_eq!(response.results[0].request_s3_key, None);
+    }
+
+    #[tokio::test]
+    async fn pipelined_uploads_report_like_batched_ones() {
+        let request = json!({"preserve_order": true, "jobs": [
+            {"template_id": "invoice.typ"},
+            {"template_id": "missing.typ"},
+            {"template_id": "invoice.typ", "data_array": [{}, {}]},
+            {"template_id": "invoice.typ", "thumbnail": true},
+        ]});
+        let mut reports = Vec::new();
+        for pipeline_uploads in [false, true] {
+            let mut resources = resources_with_templates(&["invoice.typ"]).await;
+            resources.pipeline_uploads = pipeline_uploads;
+            let resources = Arc::new(resources);
+            let response = run_batch(&resources, request.clone()).await;
+            assert_eq!(resources.results.list("").await.unwrap().len(), 5);
+            let report: Vec<_> = response
+                .results
+                .iter()
+                .map(|r| {
+                    (
+                        r.status.clone(),
+                        r.s3_keys.as_ref().map(Vec::len),
+                        r.thumbnail_s3_key.is_some(),
+                        r.page_count,
+                    )
+                })
+                .collect();
+            reports.push((report, response.summary.success));
+        }
+        assert_eq!(reports[0], reports[1]);
+        assert_eq!(reports[1].1, 3);
+    }
+
+    #[tokio::test]
+    async fn pipelined_uploads_overlap_later_renders() {
+        // The second job can't render until its data object is released
+        let gate = Arc::new(Semaphore::new(0));
+        let data = Arc::new(TestStore {
+            get_gate: Some(Arc::clone(&gate)),
+            ..Default::default()
+        });
+        put(data.as_ref(), "second.json", "{}").await;
+        let mut resources = resources_with_templates(&["invoice.typ"]).await;
+        resources.data_objects = Arc::clone(&data) as Arc<dyn ObjectStore>;
+        resources.pipeline_uploads = true;
+        let resources = Arc::new(resources);
+
+        let batch = tokio::spawn({
+            let resources = Arc::clone(&resources);
+            async move {
+                run_batch(
+                    &resources,
+                    json!({"preserve_order": true, "jobs": [
+                        {"template_id": "invoice.typ"},
+                        {"template_id": "invoice.typ", "data_key": "second.json"},
+                    ]}),
+                )
+                .await
+            }
+        });
+
+        // The first job's output is uploaded while the second is still rendering
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while resources.results.list("").await.unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("first upload didn't start before the second job rendered");
+        assert_eq!(data.gets_of("second.json"), 1);
+
+        gate.add_permits(1);
+        let response = batch.await.unwrap();
+        assert_eq!(statuses(&response), ["success", "success"]);
+        assert_eq!(resources.results.list("").await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn empty_batches_follow_allow_empty_batch() {
+        let render_empty = |resources: Arc<SharedResources>| async move {
+            render_function_url_request(
+                &resources,
+                function_url_request(r#"{"jobs": []}"#, false, &[]),
+                SystemTime::now() + Duration::from_secs(600),
+                "test-request".to_string(),
+                &CancellationToken::new(),
+            )
+            .await
+        };
+
+        let response = render_empty(Arc::new(test_resources())).await.unwrap();
+        assert_eq!(response["summary"]["total"], 0);
+        assert_eq!(response["results"], json!([]));
+
+        let mut resources = test_resources();
+        resources.allow_empty_batch = false;
+        let error = render_empty(Arc::new(resources)).await.unwrap_err();
+        assert!(matches!(error, RenderError::InvalidRequest(ref m) if m == "Request has no jobs"));
+        assert_eq!(error.status_code(), 400);
+    }
+
+    #[tokio::test]
+    async fn data_array_jobs_are_cached_only_when_every_element_was() {
+        let mut resources = resources_with_templates(&["invoice.typ"]).await;
+        resources.result_cache = Some(ResultCache::new(1024 * 1024, Duration::from_secs(300)));
+        let resources = Arc::new(resources);
+        let source = |data_array: Value| {
+            let resources = Arc::clone(&resources);
+            async move {
+                let response = run_batch(
+                    &resources,
+                    json!({"jobs": [{"template_id": "invoice.typ", "data_array": data_array}]}),
+                )
+                .await;
+                assert_eq!(statuses(&response), ["success"]);
+                response.results[0].source.unwrap()
+            }
+        };
+
+        let both = json!([{"name": "a"}, {"name": "b"}]);
+        assert_eq!(source(both.clone()).await, ResultSource::Rendered);
+        assert_eq!(source(both).await, ResultSource::Cache);
+        assert_eq!(
+            source(json!([{"name": "a"}, {"name": "c"}])).await,
+            ResultSource::Rendered
+        );
+        assert_eq!(source(json!([])).await, ResultSource::Rendered);
+    }
+
+    #[tokio::test]
+    async fn cancelled_batches_abandon_the_render_in_progress_and_the_rest() {
+        let mut resources = resources_with_templates(&["invoice.typ"]).await;
+        resources.render_permits = Arc::new(Semaphore::new(1));
+        let resources = Arc::new(resources);
+        let _held = Arc::clone(&resources.render_permits)
+            .acquire_owned()
+            .await
+            .unwrap();
+
+        let cancel = CancellationToken::new();
+        let batch = tokio::spawn({
+            let resources = Arc::clone(&resources);
+            let cancel = cancel.clone();
+            async move {
+                let request: RenderRequest = serde_json::from_value(json!({"jobs": [
+                    {"template_id": "invoice.typ"},
+                    {"template_id": "invoice.typ"},
+                    {"template_id": "invoice.typ"},
+                ]}))
+                .unwrap();
+                process_batch(
+                    &resources,
+                    request,
+                    SystemTime::now() + Duration::from_secs(600),
+                    "test-request".to_string(),
+                    &cancel,
+                )
+                .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        cancel.cancel();
+
+        let response = tokio::time::timeout(Duration::from_secs(5), batch)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(statuses(&response), ["cancelled", "cancelled", "cancelled"]);
+        assert_eq!(response.summary.cancelled, 3);
+        for result in &response.results {
+            assert_eq!(result.error_code.as_deref(), Some("cancelled"));
+        }
+        assert!(resources.results.list("").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn health_reports_each_bucket() {
+        let report = health_report(&test_resources()).await;
+        assert_eq!(report["overall"], "ok");
+        assert_eq!(report["s3"]["templates"]["status"], "ok");
+        assert_eq!(report["s3"]["results"]["status"], "ok");
+        // The data store is the templates store unless DATA_BUCKET is set
+        assert!(report["s3"].get("data").is_none());
+
+        let s3 = MockS3::default();
+        s3.respond(403, "");
+        let mut resources = test_resources();
+        resources.replica_results = Some(Arc::new(storage::S3Store::new(s3.client(), "dr")));
+        let tenant_store: Arc<dyn ObjectStore> = Arc::new(TestStore::default());
+        resources
+            .tenant_results
+            .insert("acme".to_string(), Arc::clone(&tenant_store));
+        resources
+            .output_buckets
+            .insert("exports".to_string(), tenant_store);
+        let report = health_report(&resources).await;
+        assert_eq!(report["overall"], "error");
+        assert_eq!(report["s3"]["tenant_results.acme"]["bucket"], "test");
+        assert_eq!(report["s3"]["tenant_results.acme"]["status"], "ok");
+        assert_eq!(report["s3"]["output_buckets.exports"]["status"], "ok");
+        assert_eq!(report["s3"]["results"]["status"], "ok");
+        assert_eq!(report["s3"]["replica_results"]["bucket"], "dr");
+        assert_eq!(report["s3"]["replica_results"]["status"], "error");
+        assert!(report["s3"]["replica_results"]["error"]
+            .as_str()
+            .unwrap()
+            .contains("s3:ListBucket"));
+    }
+
+    #[tokio::test]
+    async fn corrupt_templates_are_refused_and_not_cached() {
+        let dir = std::env::temp_dir().join(format!("renderer-corrupt-{}", std::process::id()));
+        let source = "= Invoice";
+        let mut crc32 = crc32fast::hash(source.as_bytes()).to_be_bytes();
+        crc32[0] ^= 1;
+        let s3 = MockS3::default();
+        s3.respond_with_headers(200, &[("content-length", "9")], "")
+            .respond_with_headers(
+                200,
+                &[("x-amz-checksum-crc32", &BASE64.encode(crc32))],
+                source,
+            );
+        let mut resources = test_resources();
+        resources.templates = Arc::new(S3Store::new(s3.client(), "templates"));
+        resources.template_disk_cache =
+            Some(DiskCache::new(&dir, 1024 * 1024, Duration::from_secs(3600)));
+
+        let error = get_cached_template(&resources, "invoice.typ")
+            .await
+            .unwrap_err();
+        assert_eq!(error.error_code(), "template_corrupt");
+        assert!(resources.template_cache.read().await.is_empty());
+        let disk_cache = resources.template_disk_cache.as_ref().unwrap();
+        assert!(disk_cache.get("invoice.typ").await.is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn unsafe_request_ids_get_a_generated_manifest_key() {
+        let cell = OnceCell::new();
+        let mut resources = resources_with_templates(&["invoice.typ"]).await;
+        resources.batch_manifest_prefix = Some("manifests/".to_string());
+        cell.set(Arc::new(resources)).unwrap();
+
+        let mut context = lambda_runtime::Context::default();
+        let deadline = SystemTime::now() + Duration::from_secs(60);
+        context.deadline = deadline
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let request = function_url_request(
+            r#"{"jobs": [{"template_id": "invoice.typ"}]}"#,
+            false,
+            &[("x-request-id", "../../templates/invoice")],
+        );
+        let event = LambdaEvent::new(IncomingEvent::FunctionUrl(Box::new(request)), context);
+        let response = handle_event(async { Ok(cell.get().unwrap()) }, event)
+            .await
+            .unwrap();
+
+        let request_id = response["request_id"].as_str().unwrap();
+        assert!(ulid::Ulid::from_string(request_id).is_ok());
+        assert_eq!(
+            response["manifest_s3_key"],
+            format!("manifests/{}/manifest.json", request_id)
+        );
+    }
+
+    #[tokio::test]
+    async fn fail_mode_deletes_outputs_written_before_a_conflict() {
+        let replica = Arc::new(TestStore::default());
+        let mut resources = resources_with_templates(&["invoice.typ"]).await;
+        put(resources.results.as_ref(), "inv/1.pdf", "existing").await;
+        resources.replica_results = Some(Arc::clone(&replica) as Arc<dyn ObjectStore>);
+        let resources = Arc::new(resources);
+
+        let response = run_batch(
+            &resources,
+            json!({"on_conflict": "fail", "replicate": true, "output_key_template": "inv",
+                "jobs": [{"template_id": "invoice.typ", "data_array": [{}, {}]}]}),
+        )
+        .await;
+        let result = &response.results[0];
+        assert_eq!(result.error_code.as_deref(), Some("result_exists"));
+        assert!(resources.results.get("inv/0.pdf").await.is_err());
+        assert!(replica.inner.get("inv/0.pdf").await.is_err());
+        assert_eq!(
+            resources.results.get("inv/1.pdf").await.unwrap(),
+            b"existing"
+        );
+    }
+
+    #[tokio::test]
+    async fn skipped_results_are_not_copied_to_the_replica() {
+        let replica = Arc::new(TestStore::default());
+        let mut resources = resources_with_templates(&["invoice.typ"]).await;
+        put(resources.results.as_ref(), "inv.pdf", "existing").await;
+        resources.replica_results = Some(Arc::clone(&replica) as Arc<dyn ObjectStore>);
+        let resources = Arc::new(resources);
+
+        let response = run_batch(
+            &resources,
+            json!({"on_conflict": "skip", "replicate": true, "output_key_template": "inv",
+                "jobs": [{"template_id": "invoice.typ"}]}),
+        )
+        .await;
+        let result = &response.results[0];
+        assert_eq!(result.status, "success");
+        assert_eq!(result.s3_key.as_deref(), Some("inv.pdf"));
+        assert_eq!(result.replica_bucket, None);
+        assert!(replica.inner.get("inv.pdf").await.is_err());
+    }
+}
@@ -0,0 +1,35 @@
+// Span exporter counting the spans it was handed instead of sending them, for tests
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::{SpanData, SpanExporter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Default)]
+pub struct CountingExporter {
+    exported: Arc<AtomicUsize>,
+    // Time each export takes, to back up the export queue
+    delay: Duration,
+}
+
+impl CountingExporter {
+    pub fn with_delay(delay: Duration) -> Self {
+        Self {
+            delay,
+            ..Default::default()
+        }
+    }
+
+    // Spans exported so far, by this exporter and its clones
+    pub fn exported(&self) -> usize {
+        self.exported.load(Ordering::Relaxed)
+    }
+}
+
+impl SpanExporter for CountingExporter {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        std::thread::sleep(self.delay);
+        self.exported.fetch_add(batch.len(), Ordering::Relaxed);
+        Ok(())
+    }
+}
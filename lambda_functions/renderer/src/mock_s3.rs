@@ -0,0 +1,131 @@
+// S3 client answering from canned responses instead of the network, for tests
+use aws_sdk_s3::config::http::{HttpRequest, HttpResponse};
+use aws_sdk_s3::config::retry::RetryConfig;
+use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region, SharedCredentialsProvider};
+use aws_sdk_s3::primitives::SdkBody;
+use aws_smithy_runtime_api::client::http::{
+    http_client_fn, HttpConnector, HttpConnectorFuture, SharedHttpClient, SharedHttpConnector,
+};
+use aws_smithy_runtime_api::http::StatusCode;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+// A request the client sent
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+// Answers requests in order with the queued responses, then with empty 200s.
+// Clones share the queue and the recorded requests.
+#[derive(Debug, Clone, Default)]
+pub struct MockS3 {
+    responses: Arc<Mutex<VecDeque<Response>>>,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl MockS3 {
+    pub fn respond(&self, status: u16, body: &str) -> &Self {
+        self.respond_with_headers(status, &[], body)
+    }
+
+    pub fn respond_with_headers(&self, status: u16, headers: &[(&str, &str)], body: &str) -> &Self {
+        self.responses.lock().unwrap().push_back(Response {
+            status,
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: body.to_string(),
+        });
+        self
+    }
+
+    // S3 error document with the given code
+    pub fn respond_error(&self, status: u16, code: &str) -> &Self {
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Error><Code>{}</Code><Message>{}</Message><RequestId>REQ123</RequestId><HostId>HOST456</HostId></Error>",
+            code, code
+        );
+        self.respond_with_headers(
+            status,
+            &[("x-amz-request-id", "REQ123"), ("x-amz-id-2", "HOST456")],
+            &body,
+        )
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn http_client(&self) -> SharedHttpClient {
+        let connector = SharedHttpConnector::new(self.clone());
+        http_client_fn(move |_, _| connector.clone())
+    }
+
+    // Shared config for building clients against this mock
+    pub fn sdk_config(&self) -> aws_config::SdkConfig {
+        aws_config::SdkConfig::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(SharedCredentialsProvider::new(Credentials::new(
+                "akid", "secret", None, None, "test",
+            )))
+            .retry_config(RetryConfig::disabled())
+            .http_client(self.http_client())
+            .build()
+    }
+
+    pub fn client(&self) -> aws_sdk_s3::Client {
+        aws_sdk_s3::Client::new(&self.sdk_config())
+    }
+}
+
+impl HttpConnector for MockS3 {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        self.requests.lock().unwrap().push(Request {
+            method: request.method().to_string(),
+            uri: request.uri().to_string(),
+            headers: request
+                .headers()
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: request.body().bytes().unwrap_or_default().to_vec(),
+        });
+
+        let response = self.responses.lock().unwrap().pop_front();
+        let response = response.unwrap_or(Response {
+            status: 200,
+            headers: Vec::new(),
+            body: String::new(),
+        });
+        let mut http_response = HttpResponse::new(
+            StatusCode::try_from(response.status).unwrap(),
+            SdkBody::from(response.body),
+        );
+        for (name, value) in response.headers {
+            http_response.headers_mut().insert(name, value);
+        }
+        HttpConnectorFuture::ready(Ok(http_response))
+    }
+}
@@ -0,0 +1,88 @@
+// Longest edit distance between an unknown field and an expected one for the
+// expected field to be suggested
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+
+// Describe a request parse error. serde reports unknown fields as
+// "unknown field `datas`, expected one of `data`, ..."; for those, the closest
+// expected field is appended as a suggestion, so typos are easy to spot.
+pub fn describe(e: &serde_json::Error) -> String {
+    let message = e.to_string();
+    match suggestion(&message) {
+        Some(field) => format!("{} (did you mean `{}`?)", message, field),
+        None => message,
+    }
+}
+
+fn suggestion(message: &str) -> Option<&str> {
+    let rest = message.strip_prefix("unknown field `")?;
+    let (unknown, expected) = rest.split_once('`')?;
+    // Expected fields are the backquoted names after the unknown one
+    expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(|field| (edit_distance(unknown, field), field))
+        .filter(|(distance, _)| *distance <= MAX_SUGGESTION_DISTANCE)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, field)| field)
+}
+
+// Levenshtein distance between two strings, in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    #[allow(dead_code)]
+    struct Job {
+        template_id: String,
+        data: Option<u32>,
+        thumbnail: Option<bool>,
+    }
+
+    fn describe_json(json: &str) -> String {
+        describe(&serde_json::from_str::<Job>(json).unwrap_err())
+    }
+
+    #[test]
+    fn suggests_the_closest_expected_field() {
+        assert!(describe_json(r#"{"template_id": "a", "datas": 1}"#)
+            .ends_with("(did you mean `data`?)"));
+        assert!(describe_json(r#"{"templat_ids": "a"}"#).ends_with("(did you mean `template_id`?)"));
+    }
+
+    #[test]
+    fn leaves_other_errors_alone() {
+        let message = describe_json(r#"{"template_id": "a", "colour": 1}"#);
+        assert!(message.starts_with("unknown field `colour`"));
+        assert!(!message.contains("did you mean"));
+
+        let message = describe_json(r#"{"template_id": 1}"#);
+        assert!(message.starts_with("invalid type"));
+        assert!(!message.contains("did you mean"));
+    }
+
+    #[test]
+    fn measures_edit_distance_in_characters() {
+        assert_eq!(edit_distance("data", "data"), 0);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("größe", "grösse"), 2);
+    }
+}
@@ -0,0 +1,383 @@
+use lopdf::content::{Content, Operation};
+use lopdf::{dictionary, Dictionary, Document, Object, ObjectId, Stream, StringFormat};
+
+// Split a PDF into one single-page PDF per page, in page order
+pub fn split_pages(pdf: &[u8]) -> Result<Vec<Vec<u8>>, lopdf::Error> {
+    let document = Document::load_mem(pdf)?;
+    let page_numbers: Vec<u32> = document.get_pages().keys().copied().collect();
+
+    page_numbers
+        .iter()
+        .map(|&page_number| {
+            let mut page_document = document.clone();
+            let other_pages: Vec<u32> = page_numbers
+                .iter()
+                .copied()
+                .filter(|&n| n != page_number)
+                .collect();
+            page_document.delete_pages(&other_pages);
+            page_document.prune_objects();
+
+            let mut output = Vec::new();
+            page_document.save_to(&mut output)?;
+            Ok(output)
+        })
+        .collect()
+}
+
+// Page attributes a page may inherit from its ancestors in the page tree
+const INHERITABLE_ATTRIBUTES: [&[u8]; 4] = [b"Resources", b"MediaBox", b"CropBox", b"Rotate"];
+
+// Concatenate PDFs into one document, keeping the pages in the given order
+pub fn merge(pdfs: &[Vec<u8>]) -> Result<Vec<u8>, lopdf::Error> {
+    let mut merged = Document::with_version("1.7");
+    let mut page_ids = Vec::new();
+
+    for pdf in pdfs {
+        let mut document = Document::load_mem(pdf)?;
+        document.renumber_objects_with(merged.max_id + 1);
+
+        let pages: Vec<ObjectId> = document.get_pages().into_values().collect();
+        for &page_id in &pages {
+            // Pages are re-parented below, so copy anything they inherit first
+            let inherited = inherited_attributes(&document, page_id)?;
+            let page = document.get_object_mut(page_id)?.as_dict_mut()?;
+            for (key, value) in inherited {
+                page.set(key, value);
+            }
+        }
+
+        merged.max_id = document.max_id;
+        merged.objects.extend(document.objects);
+        page_ids.extend(pages);
+    }
+
+    let pages_id = merged.new_object_id();
+    for &page_id in &page_ids {
+        merged
+            .get_object_mut(page_id)?
+            .as_dict_mut()?
+            .set("Parent", pages_id);
+    }
+    merged.objects.insert(
+        pages_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Count" => page_ids.len() as i64,
+            "Kids" => page_ids.into_iter().map(Object::Reference).collect::<Vec<_>>(),
+        }),
+    );
+    let catalog_id = merged.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    });
+    merged.trailer.set("Root", catalog_id);
+
+    // Drop the source documents' catalogs and page tree nodes
+    merged.prune_objects();
+    merged.renumber_objects();
+
+    let mut output = Vec::new();
+    merged.save_to(&mut output)?;
+    Ok(output)
+}
+
+// Inheritable attributes the page doesn't set itself, taken from the nearest ancestor
+fn inherited_attributes(
+    document: &Document,
+    page_id: ObjectId,
+) -> Result<Vec<(Vec<u8>, Object)>, lopdf::Error> {
+    let page = document.get_dictionary(page_id)?;
+    let mut missing: Vec<&[u8]> = INHERITABLE_ATTRIBUTES
+        .into_iter()
+        .filter(|key| !page.has(key))
+        .collect();
+
+    let mut inherited = Vec::new();
+    let mut parent = page.get(b"Parent").and_then(Object::as_reference).ok();
+    while let Some(parent_id) = parent {
+        if missing.is_empty() {
+            break;
+        }
+        let node = document.get_dictionary(parent_id)?;
+        missing.retain(|key| match node.get(key) {
+            Ok(value) => {
+                inherited.push((key.to_vec(), value.clone()));
+                false
+            }
+            Err(_) => true,
+        });
+        parent = node.get(b"Parent").and_then(Object::as_reference).ok();
+    }
+    Ok(inherited)
+}
+
+// A single A4 page with a title and message, used in place of a failed document
+pub fn error_page(title: &str, message: &str) -> Result<Vec<u8>, lopdf::Error> {
+    const LINE_WIDTH: usize = 90;
+
+    let mut document = Document::with_version("1.7");
+    let font_id = document.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type1",
+        "BaseFont" => "Helvetica",
+    });
+
+    let mut operations = vec![
+        Operation::new("BT", vec![]),
+        Operation::new("Tf", vec!["F1".into(), 14.into()]),
+        Operation::new("TL", vec![16.into()]),
+        Operation::new("Td", vec![50.into(), 790.into()]),
+        Operation::new("Tj", vec![Object::string_literal(to_ascii(title))]),
+        Operation::new("Tf", vec!["F1".into(), 10.into()]),
+        Operation::new("T*", vec![]),
+    ];
+    let message: Vec<char> = to_ascii(message).chars().collect();
+    for line in message.chunks(LINE_WIDTH) {
+        let line: String = line.iter().collect();
+        operations.push(Operation::new("T*", vec![]));
+        operations.push(Operation::new("Tj", vec![Object::string_literal(line)]));
+    }
+    operations.push(Operation::new("ET", vec![]));
+
+    let content = Content { operations }.encode()?;
+    let content_id = document.add_object(Stream::new(dictionary! {}, content));
+    let pages_id = document.new_object_id();
+    let page_id = document.add_object(dictionary! {
+        "Type" => "Page",
+        "Parent" => pages_id,
+        "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+        "Contents" => content_id,
+        "Resources" => dictionary! {
+            "Font" => dictionary! { "F1" => font_id },
+        },
+    });
+    document.objects.insert(
+        pages_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Count" => 1,
+            "Kids" => vec![page_id.into()],
+        }),
+    );
+    let catalog_id = document.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    });
+    document.trailer.set("Root", catalog_id);
+
+    let mut output = Vec::new();
+    document.save_to(&mut output)?;
+    Ok(output)
+}
+
+// The standard 14 fonts can't render arbitrary Unicode, so keep printable ASCII only
+fn to_ascii(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() {
+                c
+            } else {
+                '?'
+            }
+        })
+        .collect()
+}
+
+// Set entries in the document information dictionary (Title, Author, ...)
+pub fn set_info(pdf: &[u8], entries: &[(&str, &str)]) -> Result<Vec<u8>, lopdf::Error> {
+    let mut document = Document::load_mem(pdf)?;
+
+    let info_id = match document.trailer.get(b"Info").and_then(Object::as_reference) {
+        Ok(info_id) => info_id,
+        Err(_) => {
+            let info_id = document.add_object(Dictionary::new());
+            document.trailer.set("Info", info_id);
+            info_id
+        }
+    };
+    let info = document.get_object_mut(info_id)?.as_dict_mut()?;
+    for (key, value) in entries {
+        info.set(key.as_bytes(), text_string(value));
+    }
+
+    let mut output = Vec::new();
+    document.save_to(&mut output)?;
+    Ok(output)
+}
+
+// PDF text string: PDFDocEncoding for ASCII, otherwise UTF-16BE with a byte order mark
+fn text_string(text: &str) -> Object {
+    if text.is_ascii() {
+        return Object::string_literal(text);
+    }
+    let mut bytes = vec![0xFE, 0xFF];
+    for unit in text.encode_utf16() {
+        bytes.extend_from_slice(&unit.to_be_bytes());
+    }
+    Object::String(bytes, StringFormat::Hexadecimal)
+}
+
+// Number of pages in a PDF
+pub fn page_count(pdf: &[u8]) -> Result<usize, lopdf::Error> {
+    Ok(Document::load_mem(pdf)?.get_pages().len())
+}
+
+// Bytes at the end of a PDF searched for the startxref and %%EOF markers
+const TRAILER_WINDOW: usize = 1024;
+
+// Check that a PDF looks complete: a %PDF- header, the trailer markers at its
+// end and at least one page
+pub fn check_structure(pdf: &[u8]) -> Result<(), String> {
+    if !pdf.starts_with(b"%PDF-") {
+        return Err("missing %PDF- header".to_string());
+    }
+    let tail = &pdf[pdf.len().saturating_sub(TRAILER_WINDOW)..];
+    let contains = |marker: &[u8]| tail.windows(marker.len()).any(|window| window == marker);
+    if !contains(b"startxref") {
+        return Err("missing startxref".to_string());
+    }
+    if !contains(b"%%EOF") {
+        return Err("missing %%EOF marker".to_string());
+    }
+    match page_count(pdf) {
+        Ok(0) => Err("document has no pages".to_string()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("unreadable document: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Document whose pages inherit their MediaBox from the page tree and each
+    // draw their own number
+    fn document(pages: usize) -> Vec<u8> {
+        let mut document = Document::with_version("1.7");
+        let pages_id = document.new_object_id();
+        let kids: Vec<Object> = (1..=pages)
+            .map(|number| {
+                let content = format!("BT /F1 12 Tf 50 800 Td (page {}) Tj ET", number);
+                let content_id = document.add_object(Stream::new(dictionary! {}, content.into()));
+                document
+                    .add_object(dictionary! {
+                        "Type" => "Page",
+                        "Parent" => pages_id,
+                        "Contents" => content_id,
+                    })
+                    .into()
+            })
+            .collect();
+        document.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Count" => pages as i64,
+                "Kids" => kids,
+                "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            }),
+        );
+        let catalog_id = document.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        document.trailer.set("Root", catalog_id);
+
+        let mut output = Vec::new();
+        document.save_to(&mut output).unwrap();
+        output
+    }
+
+    // Text drawn on each page, in page order
+    fn page_texts(pdf: &[u8]) -> Vec<String> {
+        let document = Document::load_mem(pdf).unwrap();
+        document
+            .get_pages()
+            .into_values()
+            .map(|page_id| {
+                String::from_utf8_lossy(&document.get_page_content(page_id)).into_owned()
+            })
+            .map(|content| {
+                let start = content.find('(').unwrap() + 1;
+                content[start..content.find(')').unwrap()].to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_documents_into_single_pages_in_order() {
+        let pages = split_pages(&document(3)).unwrap();
+        assert_eq!(pages.len(), 3);
+        for (index, page) in pages.iter().enumerate() {
+            assert_eq!(page_texts(page), [format!("page {}", index + 1)]);
+        }
+    }
+
+    #[test]
+    fn fails_to_split_invalid_documents() {
+        assert!(split_pages(b"not a pdf").is_err());
+    }
+
+    #[test]
+    fn merges_documents_in_order() {
+        let merged = merge(&[document(2), document(1)]).unwrap();
+        assert_eq!(page_texts(&merged), ["page 1", "page 2", "page 1"]);
+    }
+
+    #[test]
+    fn error_pages_show_the_title_in_ascii() {
+        let page = error_page("Job 2 failed – ✗", "Template not found").unwrap();
+        assert_eq!(page_texts(&page), ["Job 2 failed ? ?"]);
+    }
+
+    fn info(pdf: &[u8]) -> Dictionary {
+        let document = Document::load_mem(pdf).unwrap();
+        let info_id = document
+            .trailer
+            .get(b"Info")
+            .unwrap()
+            .as_reference()
+            .unwrap();
+        document.get_dictionary(info_id).unwrap().clone()
+    }
+
+    #[test]
+    fn sets_info_entries_as_text_strings() {
+        let pdf = set_info(&document(1), &[("Title", "Invoice"), ("Author", "Zoë")]).unwrap();
+        let pdf = set_info(&pdf, &[("Title", "Invoice 42")]).unwrap();
+
+        let info = info(&pdf);
+        assert_eq!(info.get(b"Title").unwrap().as_str().unwrap(), b"Invoice 42");
+        let author = info.get(b"Author").unwrap().as_str().unwrap();
+        let units: Vec<u16> = author[2..]
+            .chunks(2)
+            .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+            .collect();
+        assert_eq!(&author[..2], [0xFE, 0xFF]);
+        assert_eq!(String::from_utf16(&units).unwrap(), "Zoë");
+        assert_eq!(page_texts(&pdf), ["page 1"]);
+    }
+
+    #[test]
+    fn checks_pdf_structure() {
+        let pdf = document(2);
+        assert_eq!(check_structure(&pdf), Ok(()));
+
+        assert_eq!(
+            check_structure(b"<html>"),
+            Err("missing %PDF- header".to_string())
+        );
+        let truncated = &pdf[..pdf.len() - 40];
+        assert!(check_structure(truncated).is_err());
+        let without_eof = String::from_utf8_lossy(&pdf).replace("%%EOF", "");
+        assert_eq!(
+            check_structure(without_eof.as_bytes()),
+            Err("missing %%EOF marker".to_string())
+        );
+        assert_eq!(
+            check_structure(&document(0)),
+            Err("document has no pages".to_string())
+        );
+    }
+}
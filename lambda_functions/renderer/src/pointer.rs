@@ -0,0 +1,87 @@
+use serde_json::Value;
+use std::borrow::Cow;
+
+// String form of the value at `pointer` in `data`, for naming and selecting by
+// data fields. `pointer` is a JSON Pointer ("/invoice/number", "/items/0/sku")
+// or a dot-separated path ("invoice.number", "items.0.sku"). Strings are
+// returned as is and numbers and booleans as JSON; missing fields, null,
+// arrays and objects give None.
+pub fn extract_pointer(data: &Value, pointer: &str) -> Option<String> {
+    match data.pointer(&to_json_pointer(pointer))? {
+        Value::String(s) => Some(s.clone()),
+        value @ (Value::Number(_) | Value::Bool(_)) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+// JSON Pointer for a dot-separated path, escaping '~' and '/' in its keys
+pub fn to_json_pointer(path: &str) -> Cow<'_, str> {
+    if path.is_empty() || path.starts_with('/') {
+        return Cow::Borrowed(path);
+    }
+    Cow::Owned(
+        path.split('.')
+            .map(|key| format!("/{}", key.replace('~', "~0").replace('/', "~1")))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn converts_dot_paths_to_json_pointers() {
+        assert_eq!(to_json_pointer("invoice.number"), "/invoice/number");
+        assert_eq!(to_json_pointer("items.0.sku"), "/items/0/sku");
+        assert_eq!(to_json_pointer("/already/a/pointer"), "/already/a/pointer");
+        assert_eq!(to_json_pointer(""), "");
+        assert_eq!(to_json_pointer("a~b.c/d"), "/a~0b/c~1d");
+        assert_eq!(to_json_pointer("a..b"), "/a//b");
+    }
+
+    #[test]
+    fn extracts_scalars_only() {
+        let data = json!({
+            "invoice": {"number": "INV-1", "total": 12.5, "paid": false, "note": null},
+            "items": [{"sku": "A-1"}],
+            "a/b": {"c~d": "escaped"},
+            "": "empty key",
+        });
+        assert_eq!(
+            extract_pointer(&data, "invoice.number").as_deref(),
+            Some("INV-1")
+        );
+        assert_eq!(
+            extract_pointer(&data, "/invoice/total").as_deref(),
+            Some("12.5")
+        );
+        assert_eq!(
+            extract_pointer(&data, "invoice.paid").as_deref(),
+            Some("false")
+        );
+        assert_eq!(
+            extract_pointer(&data, "items.0.sku").as_deref(),
+            Some("A-1")
+        );
+        assert_eq!(
+            extract_pointer(&data, "/a~1b/c~0d").as_deref(),
+            Some("escaped")
+        );
+        assert_eq!(
+            extract_pointer(&data, "a/b.c~d").as_deref(),
+            Some("escaped")
+        );
+        assert_eq!(extract_pointer(&data, "/").as_deref(), Some("empty key"));
+
+        assert_eq!(extract_pointer(&data, "invoice.note"), None);
+        assert_eq!(extract_pointer(&data, "invoice"), None);
+        assert_eq!(extract_pointer(&data, "items"), None);
+        assert_eq!(extract_pointer(&data, "items.1.sku"), None);
+        assert_eq!(extract_pointer(&data, "invoice.missing"), None);
+        // The empty pointer is the whole document, an object
+        assert_eq!(extract_pointer(&data, ""), None);
+        assert_eq!(extract_pointer(&json!("root"), "").as_deref(), Some("root"));
+    }
+}
@@ -0,0 +1,169 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Limit for a single template, as configured in TEMPLATE_RATE_LIMITS
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct RateLimit {
+    // Maximum number of renders that can be started in a burst
+    pub burst: u32,
+    // Tokens added back per second
+    pub per_second: f64,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64);
+        self.last_refill = now;
+    }
+
+    // Take one token, or return how long until one becomes available
+    fn try_acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        if self.limit.per_second <= 0.0 {
+            return Err(Duration::MAX);
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - self.tokens) / self.limit.per_second,
+        ))
+    }
+}
+
+// Token-bucket rate limiter keyed by template_id. Templates without a
+// configured limit are never throttled.
+#[derive(Debug, Default)]
+pub struct TemplateRateLimiter {
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl TemplateRateLimiter {
+    pub fn new(limits: HashMap<String, RateLimit>) -> Self {
+        let now = Instant::now();
+        let buckets = limits
+            .into_iter()
+            .map(|(template_id, limit)| (template_id, TokenBucket::new(limit, now)))
+            .collect();
+        Self {
+            buckets: Mutex::new(buckets),
+        }
+    }
+
+    // Parse limits from a JSON object, e.g. {"report.typ": {"burst": 5, "per_second": 0.5}}
+    pub fn from_json(config: &str) -> Result<Self, serde_json::Error> {
+        Ok(Self::new(serde_json::from_str(config)?))
+    }
+
+    pub fn try_acquire(&self, template_id: &str) -> Result<(), Duration> {
+        self.try_acquire_at(template_id, Instant::now())
+    }
+
+    fn try_acquire_at(&self, template_id: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        match buckets.get_mut(template_id) {
+            Some(bucket) => bucket.try_acquire(now),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(burst: u32, per_second: f64) -> TemplateRateLimiter {
+        TemplateRateLimiter::new(HashMap::from([(
+            "report.typ".to_string(),
+            RateLimit { burst, per_second },
+        )]))
+    }
+
+    #[test]
+    fn allows_a_burst_then_throttles() {
+        let limiter = limiter(2, 1.0);
+        let now = Instant::now();
+        assert!(limiter.try_acquire_at("report.typ", now).is_ok());
+        assert!(limiter.try_acquire_at("report.typ", now).is_ok());
+        assert_eq!(
+            limiter.try_acquire_at("report.typ", now),
+            Err(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn refills_over_time_up_to_the_burst() {
+        let limiter = limiter(2, 2.0);
+        let start = Instant::now();
+        for _ in 0..2 {
+            limiter.try_acquire_at("report.typ", start).unwrap();
+        }
+        assert_eq!(
+            limiter.try_acquire_at("report.typ", start + Duration::from_millis(250)),
+            Err(Duration::from_millis(250))
+        );
+        assert!(limiter
+            .try_acquire_at("report.typ", start + Duration::from_millis(500))
+            .is_ok());
+
+        // A long pause doesn't bank more than `burst` tokens
+        let later = start + Duration::from_secs(60);
+        for _ in 0..2 {
+            limiter.try_acquire_at("report.typ", later).unwrap();
+        }
+        assert!(limiter.try_acquire_at("report.typ", later).is_err());
+    }
+
+    #[test]
+    fn never_refills_without_a_rate() {
+        let limiter = limiter(1, 0.0);
+        let now = Instant::now();
+        limiter.try_acquire_at("report.typ", now).unwrap();
+        assert_eq!(
+            limiter.try_acquire_at("report.typ", now + Duration::from_secs(3600)),
+            Err(Duration::MAX)
+        );
+    }
+
+    #[test]
+    fn leaves_unlisted_templates_unthrottled() {
+        let limiter = limiter(0, 0.0);
+        assert!(limiter.try_acquire("other.typ").is_ok());
+        assert!(limiter.try_acquire("report.typ").is_err());
+    }
+
+    #[test]
+    fn parses_limits_from_json() {
+        let limiter =
+            TemplateRateLimiter::from_json(r#"{"report.typ": {"burst": 1, "per_second": 0.5}}"#)
+                .unwrap();
+        let now = Instant::now();
+        limiter.try_acquire_at("report.typ", now).unwrap();
+        assert_eq!(
+            limiter.try_acquire_at("report.typ", now),
+            Err(Duration::from_secs(2))
+        );
+        assert!(TemplateRateLimiter::from_json(r#"{"report.typ": 5}"#).is_err());
+    }
+}
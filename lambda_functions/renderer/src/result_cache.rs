@@ -0,0 +1,157 @@
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Rendered outputs as (key suffix, data) pairs; the suffix is appended to the job_id
+pub type CachedOutputs = Vec<(String, Vec<u8>)>;
+
+#[derive(Debug)]
+struct Entry {
+    outputs: CachedOutputs,
+    size: usize,
+    inserted_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<String, Entry>,
+    // Least recently used first
+    order: VecDeque<String>,
+    total_bytes: usize,
+}
+
+impl Inner {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.total_bytes -= entry.size;
+            self.order.retain(|k| k != key);
+        }
+    }
+}
+
+// In-memory cache of recently rendered outputs keyed by a content hash of the
+// job. Bounded by the total size of the cached bytes, not the entry count.
+#[derive(Debug)]
+pub struct ResultCache {
+    max_bytes: usize,
+    ttl: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl ResultCache {
+    pub fn new(max_bytes: usize, ttl: Duration) -> Self {
+        Self {
+            max_bytes,
+            ttl,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedOutputs> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let expired = inner.entries.get(key)?.inserted_at.elapsed() > self.ttl;
+        if expired {
+            inner.remove(key);
+            return None;
+        }
+
+        inner.order.retain(|k| k != key);
+        inner.order.push_back(key.to_string());
+        inner.entries.get(key).map(|entry| entry.outputs.clone())
+    }
+
+    pub fn insert(&self, key: String, outputs: CachedOutputs) {
+        let size = outputs.iter().map(|(_, data)| data.len()).sum();
+        if size > self.max_bytes {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.remove(&key);
+        while inner.total_bytes + size > self.max_bytes {
+            match inner.order.pop_front() {
+                Some(oldest) => inner.remove(&oldest),
+                None => break,
+            }
+        }
+
+        inner.total_bytes += size;
+        inner.order.push_back(key.clone());
+        inner.entries.insert(
+            key,
+            Entry {
+                outputs,
+                size,
+                inserted_at: Instant::now(),
+            },
+        );
+    }
+}
+
+// Hash the parts that determine a render's output
+pub fn cache_key(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outputs(size: usize) -> CachedOutputs {
+        vec![(".pdf".to_string(), vec![0; size])]
+    }
+
+    #[test]
+    fn returns_inserted_outputs() {
+        let cache = ResultCache::new(100, Duration::from_secs(60));
+        assert!(cache.get("a").is_none());
+        cache.insert("a".to_string(), outputs(10));
+        assert_eq!(cache.get("a"), Some(outputs(10)));
+    }
+
+    #[test]
+    fn evicts_least_recently_used_entries_to_stay_under_max_bytes() {
+        let cache = ResultCache::new(100, Duration::from_secs(60));
+        cache.insert("a".to_string(), outputs(40));
+        cache.insert("b".to_string(), outputs(40));
+        // Reading "a" makes "b" the least recently used
+        cache.get("a").unwrap();
+        cache.insert("c".to_string(), outputs(40));
+
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+    }
+
+    #[test]
+    fn skips_outputs_larger_than_the_cache() {
+        let cache = ResultCache::new(100, Duration::from_secs(60));
+        cache.insert("a".to_string(), outputs(40));
+        cache.insert("big".to_string(), outputs(101));
+        assert!(cache.get("big").is_none());
+        assert!(cache.get("a").is_some());
+    }
+
+    #[test]
+    fn expires_entries_after_the_ttl() {
+        let cache = ResultCache::new(100, Duration::from_millis(1));
+        cache.insert("a".to_string(), outputs(10));
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(cache.get("a").is_none());
+        // The expired entry no longer counts towards the size bound
+        cache.insert("b".to_string(), outputs(100));
+        assert!(cache.get("b").is_some());
+    }
+
+    #[test]
+    fn cache_keys_separate_their_parts() {
+        assert_eq!(cache_key(&[b"ab", b"c"]), cache_key(&[b"ab", b"c"]));
+        assert_ne!(cache_key(&[b"ab", b"c"]), cache_key(&[b"a", b"bc"]));
+    }
+}
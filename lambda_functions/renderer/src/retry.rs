@@ -0,0 +1,113 @@
+use std::future::Future;
+use std::time::Duration;
+use tracing::warn;
+
+// How often and how patiently to retry a failing operation
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    // Exponential backoff: base, 2x base, 4x base, ...
+    fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+    }
+}
+
+// Run `op` until it succeeds, fails with a non-retryable error, or runs out of
+// attempts. Returns the final result together with the number of attempts made.
+pub async fn with_retries<T, E, F, Fut>(
+    policy: &RetryPolicy,
+    is_retryable: impl Fn(&E) -> bool,
+    mut op: F,
+) -> (Result<T, E>, u32)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: std::fmt::Display,
+{
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Err(e) if attempt < policy.max_attempts && is_retryable(&e) => {
+                let delay = policy.delay(attempt);
+                warn!("Attempt {} failed, retrying in {:?}: {}", attempt, delay, e);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return (result, attempt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(1),
+    };
+
+    #[test]
+    fn backs_off_exponentially() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let calls = Cell::new(0);
+        let (result, attempts) = with_retries(
+            &POLICY,
+            |_: &String| true,
+            || {
+                calls.set(calls.get() + 1);
+                let call = calls.get();
+                async move {
+                    if call < 3 {
+                        Err(format!("failure {}", call))
+                    } else {
+                        Ok(call)
+                    }
+                }
+            },
+        )
+        .await;
+        assert_eq!(result, Ok(3));
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let (result, attempts) = with_retries(
+            &POLICY,
+            |_: &String| true,
+            || async { Err::<(), _>("down".to_string()) },
+        )
+        .await;
+        assert_eq!(result, Err("down".to_string()));
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_permanent_errors() {
+        let (result, attempts) = with_retries(
+            &POLICY,
+            |e: &String| e != "permanent",
+            || async { Err::<(), _>("permanent".to_string()) },
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
@@ -0,0 +1,981 @@
+use async_trait::async_trait;
+use aws_sdk_s3::error::{ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::operation::{RequestId, RequestIdExt};
+use aws_sdk_s3::primitives::DateTime;
+use aws_sdk_s3::types::{ChecksumMode, CompletedMultipartUpload, CompletedPart, StorageClass};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use md5::{Digest, Md5};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("Object not found: {0}")]
+    NotFound(String),
+    #[error("Access denied to object: {0}")]
+    AccessDenied(String),
+    #[error("Checksum mismatch for object {0}")]
+    ChecksumMismatch(String),
+    #[error("Object already exists: {0}")]
+    AlreadyExists(String),
+    #[error("{0}")]
+    Backend(String),
+}
+
+// Options applied when writing an object
+#[derive(Debug, Clone, Default)]
+pub struct PutOptions {
+    pub content_type: Option<String>,
+    pub cache_control: Option<String>,
+    pub expires: Option<SystemTime>,
+    // S3 storage class; unset uses STANDARD
+    pub storage_class: Option<StorageClass>,
+    // Fail with AlreadyExists instead of replacing an existing object
+    pub if_absent: bool,
+}
+
+// An object returned by ObjectStore::list
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: Option<SystemTime>,
+}
+
+// Key-value object storage the renderer reads templates from and writes results to
+#[async_trait]
+pub trait ObjectStore: Send + Sync + Debug {
+    // Bucket name, for tracing
+    fn bucket(&self) -> &str;
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StoreError>;
+    // Size of an existing object, or NotFound
+    async fn head(&self, key: &str) -> Result<u64, StoreError>;
+    async fn put(&self, key: &str, bytes: Vec<u8>, opts: PutOptions) -> Result<(), StoreError>;
+    // Remove an object; succeeds if it doesn't exist
+    async fn delete(&self, key: &str) -> Result<(), StoreError>;
+    // All objects whose key starts with `prefix`
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StoreError>;
+    // Cheap check that the bucket exists and is reachable with our credentials
+    async fn check(&self) -> Result<(), StoreError>;
+}
+
+// Backend error for a failed S3 call, keeping the request ids AWS support asks
+// for (x-amz-request-id and x-amz-id-2)
+fn backend_error<E: std::error::Error>(e: SdkError<E>) -> StoreError {
+    let request_id = e.request_id().unwrap_or("none");
+    let extended_request_id = e.extended_request_id().unwrap_or("none");
+    tracing::warn!(
+        s3_request_id = request_id,
+        s3_extended_request_id = extended_request_id,
+        "S3 request failed: {}",
+        e
+    );
+    StoreError::Backend(format!(
+        "{} (request id: {}, extended request id: {})",
+        e, request_id, extended_request_id
+    ))
+}
+
+// Error for a write made with PutOptions::if_absent, which S3 refuses with
+// 412 Precondition Failed when the key already exists
+fn write_error<E: std::error::Error>(key: &str, e: SdkError<E>) -> StoreError {
+    if e.raw_response()
+        .is_some_and(|response| response.status().as_u16() == 412)
+    {
+        StoreError::AlreadyExists(key.to_string())
+    } else {
+        backend_error(e)
+    }
+}
+
+// Error for an object body that failed to download. With checksum mode
+// enabled the SDK validates the body against the checksum S3 stored for the
+// object (composite checksums of multipart uploads excepted) while it is read,
+// so a corrupted body surfaces here rather than as a bad template.
+fn body_error(key: &str, e: aws_sdk_s3::primitives::ByteStreamError) -> StoreError {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&e);
+    while let Some(error) = source {
+        if let Some(mismatch) = error.downcast_ref::<aws_smithy_checksums::body::validate::Error>()
+        {
+            return StoreError::ChecksumMismatch(format!("{}: {}", key, mismatch));
+        }
+        source = error.source();
+    }
+    StoreError::Backend(format!("Failed to read object body: {}", e))
+}
+
+// Base64 MD5 of a body, sent as Content-MD5 so S3 rejects bodies corrupted in transit
+fn content_md5(body: &[u8]) -> String {
+    BASE64.encode(Md5::digest(body))
+}
+
+// S3 rejects multipart parts smaller than this, except for the last one
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+// Object store backed by a single S3 bucket
+#[derive(Debug, Clone)]
+pub struct S3Store {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    // Objects larger than this are written with a multipart upload
+    multipart_threshold: Option<usize>,
+}
+
+impl S3Store {
+    pub fn new(client: aws_sdk_s3::Client, bucket: impl Into<String>) -> Self {
+        Self {
+            client,
+            bucket: bucket.into(),
+            multipart_threshold: None,
+        }
+    }
+
+    // Upload objects over `threshold` bytes in parts, each at least `threshold` bytes
+    pub fn with_multipart_threshold(mut self, threshold: usize) -> Self {
+        self.multipart_threshold = Some(threshold);
+        self
+    }
+
+    // Abort multipart uploads started more than `max_age` ago, such as those
+    // left behind by invocations that crashed mid-upload. Returns how many
+    // were aborted.
+    pub async fn abort_stale_multipart_uploads(
+        &self,
+        max_age: Duration,
+    ) -> Result<usize, StoreError> {
+        let cutoff = SystemTime::now() - max_age;
+        let mut aborted = 0;
+        let mut key_marker = None;
+        let mut upload_id_marker = None;
+        loop {
+            let page = self
+                .client
+                .list_multipart_uploads()
+                .bucket(&self.bucket)
+                .set_key_marker(key_marker)
+                .set_upload_id_marker(upload_id_marker)
+                .send()
+                .await
+                .map_err(backend_error)?;
+
+            for upload in page.uploads() {
+                let (Some(key), Some(upload_id)) = (upload.key(), upload.upload_id()) else {
+                    continue;
+                };
+                let stale = upload
+                    .initiated()
+                    .and_then(|t| SystemTime::try_from(*t).ok())
+                    .is_some_and(|initiated| initiated < cutoff);
+                if !stale {
+                    continue;
+                }
+
+                self.client
+                    .abort_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .send()
+                    .await
+                    .map_err(backend_error)?;
+                tracing::info!("Aborted stale multipart upload {} for {}", upload_id, key);
+                aborted += 1;
+            }
+
+            if !page.is_truncated().unwrap_or_default() {
+                return Ok(aborted);
+            }
+            key_marker = page.next_key_marker().map(str::to_string);
+            upload_id_marker = page.next_upload_id_marker().map(str::to_string);
+        }
+    }
+
+    async fn put_multipart(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        opts: PutOptions,
+        part_size: usize,
+    ) -> Result<(), StoreError> {
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .set_content_type(opts.content_type)
+            .set_cache_control(opts.cache_control)
+            .set_expires(opts.expires.map(DateTime::from))
+            .set_storage_class(opts.storage_class)
+            .send()
+            .await
+            .map_err(backend_error)?;
+        let upload_id = upload
+            .upload_id()
+            .ok_or_else(|| StoreError::Backend("Multipart upload has no upload id".to_string()))?;
+
+        match self
+            .upload_parts(key, upload_id, bytes, part_size, opts.if_absent)
+            .await
+        {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                // Parts of an unfinished upload are stored (and billed) until aborted
+                if let Err(abort_error) = self
+                    .client
+                    .abort_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .send()
+                    .await
+                {
+                    tracing::error!(
+                        "Failed to abort multipart upload {} for {}: {}",
+                        upload_id,
+                        key,
+                        abort_error
+                    );
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // Upload all parts concurrently and complete the upload, only creating the
+    // object if `if_absent`
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        bytes: Vec<u8>,
+        part_size: usize,
+        if_absent: bool,
+    ) -> Result<(), StoreError> {
+        let bytes = Bytes::from(bytes);
+        let part_uploads = (0..bytes.len())
+            .step_by(part_size)
+            .enumerate()
+            .map(|(index, start)| {
+                let part_number = index as i32 + 1;
+                let body = bytes.slice(start..(start + part_size).min(bytes.len()));
+                async move {
+                    let part = self
+                        .client
+                        .upload_part()
+                        .bucket(&self.bucket)
+                        .key(key)
+                        .upload_id(upload_id)
+                        .part_number(part_number)
+                        .content_md5(content_md5(&body))
+                        .body(body.into())
+                        .send()
+                        .await
+                        .map_err(backend_error)?;
+                    Ok::<_, StoreError>(
+                        CompletedPart::builder()
+                            .part_number(part_number)
+                            .set_e_tag(part.e_tag)
+                            .build(),
+                    )
+                }
+            });
+        let parts = futures::future::try_join_all(part_uploads).await?;
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .set_if_none_match(if_absent.then(|| "*".to_string()))
+            .send()
+            .await
+            .map_err(|e| write_error(key, e))?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ObjectStore for S3Store {
+    fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StoreError> {
+        let object = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .checksum_mode(ChecksumMode::Enabled)
+            .send()
+            .await
+            .map_err(|e| match e.as_service_error() {
+                Some(service_error) if service_error.is_no_such_key() => {
+                    StoreError::NotFound(key.to_string())
+                }
+                _ if e.code() == Some("AccessDenied") => StoreError::AccessDenied(key.to_string()),
+                _ => backend_error(e),
+            })?;
+
+        let bytes = object
+            .body
+            .collect()
+            .await
+            .map_err(|e| body_error(key, e))?
+            .to_vec();
+        Ok(bytes)
+    }
+
+    // HeadBucket is authorized by s3:ListBucket, not by any object permission
+    async fn check(&self) -> Result<(), StoreError> {
+        self.client
+            .head_bucket()
+            .bucket(&self.bucket)
+            .send()
+            .await
+            .map_err(|e| {
+                if e.raw_response()
+                    .is_some_and(|response| response.status().as_u16() == 403)
+                {
+                    StoreError::Backend(format!(
+                        "Access denied to bucket {}; health checks need s3:ListBucket on it",
+                        self.bucket
+                    ))
+                } else {
+                    backend_error(e)
+                }
+            })?;
+        Ok(())
+    }
+
+    async fn head(&self, key: &str) -> Result<u64, StoreError> {
+        let object = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| match e.as_service_error() {
+                Some(service_error) if service_error.is_not_found() => {
+                    StoreError::NotFound(key.to_string())
+                }
+                // HEAD responses have no body, so a denial only surfaces as a 403 status
+                _ if e
+                    .raw_response()
+                    .is_some_and(|response| response.status().as_u16() == 403) =>
+                {
+                    StoreError::AccessDenied(key.to_string())
+                }
+                _ => backend_error(e),
+            })?;
+        Ok(object.content_length().unwrap_or_default().max(0) as u64)
+    }
+
+    async fn put(&self, key: &str, bytes: Vec<u8>, opts: PutOptions) -> Result<(), StoreError> {
+        if let Some(threshold) = self.multipart_threshold {
+            if bytes.len() > threshold {
+                let part_size = threshold.max(MIN_PART_SIZE);
+                return self.put_multipart(key, bytes, opts, part_size).await;
+            }
+        }
+
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .set_content_type(opts.content_type)
+            .set_cache_control(opts.cache_control)
+            .set_expires(opts.expires.map(DateTime::from))
+            .set_storage_class(opts.storage_class)
+            .set_if_none_match(opts.if_absent.then(|| "*".to_string()))
+            .content_md5(content_md5(&bytes))
+            .body(bytes.into())
+            .send()
+            .await
+            .map_err(|e| write_error(key, e))?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(backend_error)?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StoreError> {
+        // A single ListObjectsV2 call returns at most 1000 keys
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(prefix)
+            .into_paginator()
+            .send();
+
+        let mut objects = Vec::new();
+        while let Some(page) = pages.try_next().await.map_err(backend_error)? {
+            objects.extend(
+                page.contents
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|object| {
+                        Some(ObjectInfo {
+                            key: object.key?,
+                            size: object.size.unwrap_or_default().max(0) as u64,
+                            last_modified: object
+                                .last_modified
+                                .and_then(|t| SystemTime::try_from(t).ok()),
+                        })
+                    }),
+            );
+        }
+        Ok(objects)
+    }
+}
+
+// Object store held in process memory, for local development without AWS
+#[derive(Debug, Default)]
+pub struct InMemoryStore {
+    objects: Mutex<HashMap<String, (Vec<u8>, PutOptions)>>,
+}
+
+impl InMemoryStore {
+    // Preload every file in `dir` under its file name
+    pub fn from_dir(dir: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut objects = HashMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                let key = entry.file_name().to_string_lossy().into_owned();
+                objects.insert(key, (std::fs::read(entry.path())?, PutOptions::default()));
+            }
+        }
+        Ok(Self {
+            objects: Mutex::new(objects),
+        })
+    }
+}
+
+#[async_trait]
+impl ObjectStore for InMemoryStore {
+    fn bucket(&self) -> &str {
+        "memory"
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StoreError> {
+        let objects = self.objects.lock().unwrap_or_else(|e| e.into_inner());
+        objects
+            .get(key)
+            .map(|(bytes, _)| bytes.clone())
+            .ok_or_else(|| StoreError::NotFound(key.to_string()))
+    }
+
+    async fn check(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    async fn head(&self, key: &str) -> Result<u64, StoreError> {
+        let objects = self.objects.lock().unwrap_or_else(|e| e.into_inner());
+        objects
+            .get(key)
+            .map(|(bytes, _)| bytes.len() as u64)
+            .ok_or_else(|| StoreError::NotFound(key.to_string()))
+    }
+
+    async fn put(&self, key: &str, bytes: Vec<u8>, opts: PutOptions) -> Result<(), StoreError> {
+        let mut objects = self.objects.lock().unwrap_or_else(|e| e.into_inner());
+        if opts.if_absent && objects.contains_key(key) {
+            return Err(StoreError::AlreadyExists(key.to_string()));
+        }
+        objects.insert(key.to_string(), (bytes, opts));
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+        let mut objects = self.objects.lock().unwrap_or_else(|e| e.into_inner());
+        objects.remove(key);
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StoreError> {
+        let objects = self.objects.lock().unwrap_or_else(|e| e.into_inner());
+        let mut listed: Vec<ObjectInfo> = objects
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, (bytes, _))| ObjectInfo {
+                key: key.clone(),
+                size: bytes.len() as u64,
+                last_modified: None,
+            })
+            .collect();
+        // Match S3, which lists keys in lexicographic order
+        listed.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(listed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_s3::MockS3;
+
+    #[tokio::test]
+    async fn in_memory_store_round_trips_objects() {
+        let store = InMemoryStore::default();
+        assert!(matches!(
+            store.get("a.pdf").await,
+            Err(StoreError::NotFound(_))
+        ));
+        assert!(matches!(
+            store.head("a.pdf").await,
+            Err(StoreError::NotFound(_))
+        ));
+
+        store
+            .put("a.pdf", b"pdf".to_vec(), PutOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(store.get("a.pdf").await.unwrap(), b"pdf");
+        assert_eq!(store.head("a.pdf").await.unwrap(), 3);
+
+        store.delete("a.pdf").await.unwrap();
+        store.delete("a.pdf").await.unwrap();
+        assert!(store.get("a.pdf").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn in_memory_store_lists_by_prefix_in_key_order() {
+        let store = InMemoryStore::default();
+        for key in ["t/b.typ", "t/a.typ", "other.typ"] {
+            store
+                .put(key, key.as_bytes().to_vec(), PutOptions::default())
+                .await
+                .unwrap();
+        }
+        let keys: Vec<String> = store
+            .list("t/")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|object| object.key)
+            .collect();
+        assert_eq!(keys, ["t/a.typ", "t/b.typ"]);
+    }
+
+    #[tokio::test]
+    async fn in_memory_store_loads_files_from_a_directory() {
+        let dir = std::env::temp_dir().join(format!("renderer-store-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(dir.join("invoice.typ"), "Hello").unwrap();
+
+        let store = InMemoryStore::from_dir(&dir).unwrap();
+        assert_eq!(store.get("invoice.typ").await.unwrap(), b"Hello");
+        assert_eq!(store.list("").await.unwrap().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn s3_store_reads_and_writes_objects_in_its_bucket() {
+        let s3 = MockS3::default();
+        s3.respond(200, "hello").respond(200, "").respond(204, "");
+        let store = S3Store::new(s3.client(), "templates");
+
+        assert_eq!(store.get("invoice.typ").await.unwrap(), b"hello");
+        store
+            .put(
+                "out/a.pdf",
+                b"pdf".to_vec(),
+                PutOptions {
+                    content_type: Some("application/pdf".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        store.delete("out/a.pdf").await.unwrap();
+
+        let requests = s3.requests();
+        assert_eq!(requests[0].method, "GET");
+        assert!(requests[0].uri.contains("templates"));
+        assert!(requests[0].uri.contains("/invoice.typ"));
+        assert_eq!(requests[1].method, "PUT");
+        assert_eq!(requests[1].header("content-type"), Some("application/pdf"));
+        assert_eq!(requests[1].body, b"pdf");
+        assert_eq!(requests[2].method, "DELETE");
+    }
+
+    #[tokio::test]
+    async fn s3_store_reports_missing_objects() {
+        let s3 = MockS3::default();
+        s3.respond_error(404, "NoSuchKey");
+        let store = S3Store::new(s3.client(), "templates");
+        assert!(matches!(
+            store.get("missing.typ").await,
+            Err(StoreError::NotFound(key)) if key == "missing.typ"
+        ));
+    }
+
+    #[tokio::test]
+    async fn s3_store_reports_denied_reads() {
+        let s3 = MockS3::default();
+        s3.respond_error(403, "AccessDenied");
+        let store = S3Store::new(s3.client(), "templates");
+        assert!(matches!(
+            store.get("secret.typ").await,
+            Err(StoreError::AccessDenied(key)) if key == "secret.typ"
+        ));
+    }
+
+    #[tokio::test]
+    async fn s3_store_lists_objects() {
+        let s3 = MockS3::default();
+        s3.respond(
+            200,
+            "<ListBucketResult><Name>templates</Name><Prefix>t/</Prefix><KeyCount>1</KeyCount>\
+             <IsTruncated>false</IsTruncated><Contents><Key>t/a.typ</Key><Size>12</Size>\
+             <LastModified>2024-01-01T00:00:00.000Z</LastModified></Contents></ListBucketResult>",
+        );
+        let store = S3Store::new(s3.client(), "templates");
+
+        let objects = store.list("t/").await.unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].key, "t/a.typ");
+        assert_eq!(objects[0].size, 12);
+        assert!(objects[0].last_modified.is_some());
+    }
+
+    #[tokio::test]
+    async fn s3_store_sends_cache_headers() {
+        let s3 = MockS3::default();
+        let store = S3Store::new(s3.client(), "results");
+        let opts = PutOptions {
+            cache_control: Some("no-store".to_string()),
+            expires: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            ..Default::default()
+        };
+        store.put("a.pdf", b"pdf".to_vec(), opts).await.unwrap();
+
+        let request = &s3.requests()[0];
+        assert_eq!(request.header("cache-control"), Some("no-store"));
+        assert_eq!(
+            request.header("expires"),
+            Some("Tue, 14 Nov 2023 22:13:20 GMT")
+        );
+    }
+
+    #[tokio::test]
+    async fn s3_store_reports_refused_conditional_writes() {
+        let s3 = MockS3::default();
+        s3.respond_error(412, "PreconditionFailed");
+        let store = S3Store::new(s3.client(), "results");
+        let opts = PutOptions {
+            if_absent: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            store.put("a.pdf", b"pdf".to_vec(), opts).await,
+            Err(StoreError::AlreadyExists(key)) if key == "a.pdf"
+        ));
+        assert_eq!(s3.requests()[0].header("if-none-match"), Some("*"));
+    }
+
+    const UPLOAD_CREATED: &str = "<InitiateMultipartUploadResult><Bucket>results</Bucket>\
+        <Key>big.pdf</Key><UploadId>UP1</UploadId></InitiateMultipartUploadResult>";
+
+    #[tokio::test]
+    async fn s3_store_uploads_large_objects_in_parts() {
+        let s3 = MockS3::default();
+        s3.respond(200, UPLOAD_CREATED)
+            .respond_with_headers(200, &[("etag", "\"p1\"")], "")
+            .respond_with_headers(200, &[("etag", "\"p2\"")], "")
+            .respond(
+                200,
+                "<CompleteMultipartUploadResult><Bucket>results</Bucket><Key>big.pdf</Key>\
+                 <ETag>\"big\"</ETag></CompleteMultipartUploadResult>",
+            );
+        let store = S3Store::new(s3.client(), "results").with_multipart_threshold(1024);
+
+        let bytes = vec![7u8; MIN_PART_SIZE + 1024];
+        store
+            .put("big.pdf", bytes, PutOptions::default())
+            .await
+            .unwrap();
+
+        let requests = s3.requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[0].method, "POST");
+        assert!(requests[0].uri.contains("uploads"));
+        assert!(requests[1].uri.contains("partNumber=1"));
+        assert_eq!(requests[1].body.len(), MIN_PART_SIZE);
+        assert!(requests[2].uri.contains("partNumber=2"));
+        assert_eq!(requests[2].body.len(), 1024);
+        assert_eq!(requests[3].method, "POST");
+        assert!(requests[3].uri.contains("uploadId=UP1"));
+        let completion = String::from_utf8_lossy(&requests[3].body);
+        assert!(completion.contains("<PartNumber>1</PartNumber>"));
+        assert!(completion.contains("p1"));
+        assert!(completion.contains("<PartNumber>2</PartNumber>"));
+        assert!(completion.contains("p2"));
+    }
+
+    #[tokio::test]
+    async fn s3_store_aborts_failed_multipart_uploads() {
+        let s3 = MockS3::default();
+        s3.respond(200, UPLOAD_CREATED)
+            .respond_error(500, "InternalError");
+        let store = S3Store::new(s3.client(), "results");
+
+        let result = store
+            .put_multipart("big.pdf", vec![7u8; 10], PutOptions::default(), 4)
+            .await;
+        assert!(matches!(result, Err(StoreError::Backend(_))));
+
+        let requests = s3.requests();
+        let abort = requests.last().unwrap();
+        assert_eq!(abort.method, "DELETE");
+        assert!(abort.uri.contains("uploadId=UP1"));
+        assert!(!requests
+            .iter()
+            .any(|request| request.method == "POST" && request.uri.contains("uploadId=")));
+    }
+
+    #[tokio::test]
+    async fn s3_store_heads_objects() {
+        let s3 = MockS3::default();
+        s3.respond_with_headers(200, &[("content-length", "42")], "")
+            .respond(404, "")
+            .respond(403, "");
+        let store = S3Store::new(s3.client(), "results");
+
+        assert_eq!(store.head("a.pdf").await.unwrap(), 42);
+        assert!(matches!(
+            store.head("b.pdf").await,
+            Err(StoreError::NotFound(_))
+        ));
+        // Without s3:ListBucket, S3 answers a HEAD of a missing key with 403
+        assert!(matches!(
+            store.head("c.pdf").await,
+            Err(StoreError::AccessDenied(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn s3_store_follows_list_continuation_pages() {
+        let s3 = MockS3::default();
+        s3.respond(
+            200,
+            "<ListBucketResult><Name>templates</Name><KeyCount>1</KeyCount>\
+             <IsTruncated>true</IsTruncated><NextContinuationToken>page2</NextContinuationToken>\
+             <Contents><Key>a.typ</Key><Size>1</Size></Contents></ListBucketResult>",
+        )
+        .respond(
+            200,
+            "<ListBucketResult><Name>templates</Name><KeyCount>1</KeyCount>\
+             <IsTruncated>false</IsTruncated>\
+             <Contents><Key>b.typ</Key><Size>2</Size></Contents></ListBucketResult>",
+        );
+        let store = S3Store::new(s3.client(), "templates");
+
+        let keys: Vec<String> = store
+            .list("")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|object| object.key)
+            .collect();
+        assert_eq!(keys, ["a.typ", "b.typ"]);
+        assert!(s3.requests()[1].uri.contains("continuation-token=page2"));
+    }
+
+    #[tokio::test]
+    async fn s3_store_aborts_stale_multipart_uploads_across_pages() {
+        let upload = |key: &str, upload_id: &str, initiated: &str| {
+            format!(
+                "<Upload><Key>{}</Key><UploadId>{}</UploadId><Initiated>{}</Initiated></Upload>",
+                key, upload_id, initiated
+            )
+        };
+        let s3 = MockS3::default();
+        s3.respond(
+            200,
+            &format!(
+                "<ListMultipartUploadsResult><Bucket>results</Bucket><IsTruncated>true</IsTruncated>\
+                 <NextKeyMarker>b.pdf</NextKeyMarker><NextUploadIdMarker>u2</NextUploadIdMarker>{}{}\
+                 </ListMultipartUploadsResult>",
+                upload("a.pdf", "u1", "2020-01-01T00:00:00.000Z"),
+                upload("b.pdf", "u2", "2999-01-01T00:00:00.000Z"),
+            ),
+        )
+        .respond(204, "")
+        .respond(
+            200,
+            &format!(
+                "<ListMultipartUploadsResult><Bucket>results</Bucket><IsTruncated>false</IsTruncated>{}\
+                 </ListMultipartUploadsResult>",
+                upload("c.pdf", "u3", "2020-01-01T00:00:00.000Z"),
+            ),
+        )
+        .respond(204, "");
+        let store = S3Store::new(s3.client(), "results");
+
+        let aborted = store
+            .abort_stale_multipart_uploads(Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(aborted, 2);
+
+        let requests = s3.requests();
+        let methods: Vec<&str> = requests.iter().map(|r| r.method.as_str()).collect();
+        assert_eq!(methods, ["GET", "DELETE", "GET", "DELETE"]);
+        assert!(requests[1]
+            .uri
+            .contains("/a.pdf?x-id=AbortMultipartUpload&uploadId=u1"));
+        assert!(requests[2].uri.contains("key-marker=b.pdf"));
+        assert!(requests[2].uri.contains("upload-id-marker=u2"));
+        assert!(requests[3]
+            .uri
+            .contains("/c.pdf?x-id=AbortMultipartUpload&uploadId=u3"));
+    }
+
+    #[tokio::test]
+    async fn s3_store_backend_errors_carry_request_ids() {
+        let s3 = MockS3::default();
+        s3.respond_error(500, "InternalError")
+            .respond_error(503, "SlowDown");
+        let store = S3Store::new(s3.client(), "results");
+
+        for error in [
+            store.get("a.pdf").await.unwrap_err(),
+            store
+                .put("a.pdf", b"pdf".to_vec(), PutOptions::default())
+                .await
+                .unwrap_err(),
+        ] {
+            let StoreError::Backend(message) = error else {
+                panic!("expected a backend error, got {:?}", error);
+            };
+            assert!(
+                message.contains("request id: REQ123, extended request id: HOST456"),
+                "{}",
+                message
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn s3_store_sends_content_md5_with_every_body() {
+        assert_eq!(content_md5(b""), "1B2M2Y8AsgTpgAmY7PhCfg==");
+        assert_eq!(content_md5(b"pdf"), BASE64.encode(Md5::digest(b"pdf")));
+
+        let s3 = MockS3::default();
+        s3.respond(200, "")
+            .respond(200, UPLOAD_CREATED)
+            .respond_with_headers(200, &[("etag", "\"p1\"")], "")
+            .respond_with_headers(200, &[("etag", "\"p2\"")], "")
+            .respond(
+                200,
+                "<CompleteMultipartUploadResult><Key>big.pdf</Key></CompleteMultipartUploadResult>",
+            );
+        let store = S3Store::new(s3.client(), "results");
+        store
+            .put("a.pdf", b"pdf".to_vec(), PutOptions::default())
+            .await
+            .unwrap();
+        store
+            .put_multipart("big.pdf", vec![1, 2, 3, 4, 5, 6], PutOptions::default(), 4)
+            .await
+            .unwrap();
+
+        let requests = s3.requests();
+        assert_eq!(
+            requests[0].header("content-md5"),
+            Some(content_md5(b"pdf").as_str())
+        );
+        assert_eq!(
+            requests[2].header("content-md5"),
+            Some(content_md5(&[1, 2, 3, 4]).as_str())
+        );
+        assert_eq!(
+            requests[3].header("content-md5"),
+            Some(content_md5(&[5, 6]).as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn s3_store_sets_the_storage_class_of_uploads() {
+        let s3 = MockS3::default();
+        let store = S3Store::new(s3.client(), "results");
+        let opts = PutOptions {
+            storage_class: Some(StorageClass::GlacierIr),
+            ..Default::default()
+        };
+        store.put("a.pdf", b"pdf".to_vec(), opts).await.unwrap();
+        store
+            .put("b.pdf", b"pdf".to_vec(), PutOptions::default())
+            .await
+            .unwrap();
+
+        let requests = s3.requests();
+        assert_eq!(
+            requests[0].header("x-amz-storage-class"),
+            Some("GLACIER_IR")
+        );
+        assert_eq!(requests[1].header("x-amz-storage-class"), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_bodies_that_dont_match_their_checksum() {
+        let body = "hello";
+        let crc32 = BASE64.encode(crc32fast::hash(body.as_bytes()).to_be_bytes());
+        let s3 = MockS3::default();
+        s3.respond_with_headers(200, &[("x-amz-checksum-crc32", &crc32)], body)
+            .respond_with_headers(200, &[("x-amz-checksum-crc32", &crc32)], "hellO")
+            // Composite checksums of multipart uploads can't be checked
+            .respond_with_headers(200, &[("x-amz-checksum-crc32", "AAAAAA==-3")], "hellO");
+        let store = S3Store::new(s3.client(), "templates");
+
+        assert_eq!(store.get("a.typ").await.unwrap(), b"hello");
+        assert!(matches!(
+            store.get("a.typ").await,
+            Err(StoreError::ChecksumMismatch(message)) if message.starts_with("a.typ: ")
+        ));
+        assert_eq!(store.get("a.typ").await.unwrap(), b"hellO");
+        assert_eq!(
+            s3.requests()[0].header("x-amz-checksum-mode"),
+            Some("ENABLED")
+        );
+    }
+
+    #[tokio::test]
+    async fn bucket_checks_name_the_missing_list_permission() {
+        let s3 = MockS3::default();
+        s3.respond(200, "").respond(403, "").respond(500, "");
+        let store = S3Store::new(s3.client(), "results");
+
+        assert!(store.check().await.is_ok());
+        let denied = store.check().await.unwrap_err().to_string();
+        assert!(denied.contains("s3:ListBucket"), "{}", denied);
+        let failed = store.check().await.unwrap_err().to_string();
+        assert!(!failed.contains("s3:ListBucket"), "{}", failed);
+
+        let requests = s3.requests();
+        assert_eq!(requests[0].method, "HEAD");
+        assert!(requests[0].uri.contains("results"));
+    }
+}
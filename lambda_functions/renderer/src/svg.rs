@@ -0,0 +1,45 @@
+use papermake::typst::TypstWorld;
+use serde_json::Value;
+use thiserror::Error;
+use typst::layout::PagedDocument;
+
+#[derive(Error, Debug)]
+#[error("template failed to compile: {0}")]
+pub struct CompileError(pub String);
+
+// Lay out a template rendered with `data`. papermake only produces PDFs, so
+// outputs drawn from the pages (SVGs, thumbnails) compile the template here.
+pub fn compile(template: &str, data: &Value) -> Result<PagedDocument, CompileError> {
+    let world = TypstWorld::new(template.to_string(), data.to_string());
+    typst::compile(&world).output.map_err(|diagnostics| {
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        CompileError(messages.join("; "))
+    })
+}
+
+// Draw each page of a template rendered with `data` as an SVG document
+pub fn render_pages(template: &str, data: &Value) -> Result<Vec<String>, CompileError> {
+    let document = compile(template, data)?;
+    Ok(document.pages.iter().map(typst_svg::svg).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn renders_one_svg_per_page() {
+        let pages = render_pages("First\n#pagebreak()\nSecond", &json!({})).unwrap();
+        assert_eq!(pages.len(), 2);
+        for page in &pages {
+            assert!(page.starts_with("<svg"));
+        }
+    }
+
+    #[test]
+    fn reports_compile_errors() {
+        let error = render_pages("#panic(\"no\")", &json!({})).unwrap_err();
+        assert!(error.to_string().starts_with("template failed to compile"));
+    }
+}
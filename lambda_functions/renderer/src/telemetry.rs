@@ -0,0 +1,272 @@
+use aws_lambda_events::http::HeaderMap;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::{
+    BatchConfigBuilder, BatchSpanProcessor, Span, SpanData, SpanExporter, SpanProcessor,
+};
+use opentelemetry_sdk::Resource;
+use std::env;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Read W3C trace context (traceparent/tracestate) from request headers
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+// Extract the caller's trace context, if the request carries a valid one
+pub fn extract_remote_context(headers: &HeaderMap) -> Option<Context> {
+    let context =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    context.span().span_context().is_valid().then_some(context)
+}
+
+// Resource attributes set from an environment variable when it is non-empty
+const ENV_ATTRIBUTES: [(&str, &str); 3] = [
+    ("deployment.environment", "DEPLOYMENT_ENVIRONMENT"),
+    ("cloud.region", "AWS_REGION"),
+    ("vcs.ref.head.revision", "GIT_SHA"),
+];
+
+// Resource attributing exported spans to this deployment. The service name and
+// version can be overridden with OTEL_SERVICE_NAME and SERVICE_VERSION.
+pub fn resource() -> Resource {
+    resource_from(|name| env::var(name).ok())
+}
+
+// Resource built from the variables `var` looks up
+fn resource_from(var: impl Fn(&str) -> Option<String>) -> Resource {
+    let non_empty_var = |name: &str| var(name).filter(|value| !value.is_empty());
+    let service_name =
+        non_empty_var("OTEL_SERVICE_NAME").unwrap_or_else(|| "pdf-renderer-lambda".to_string());
+    let service_version =
+        non_empty_var("SERVICE_VERSION").unwrap_or_else(|| env!("CARGO_PKG_VERSION").to_string());
+
+    Resource::builder()
+        .with_service_name(service_name)
+        .with_attribute(KeyValue::new("service.version", service_version))
+        .with_attributes(ENV_ATTRIBUTES.iter().filter_map(|&(attribute, name)| {
+            non_empty_var(name).map(|value| KeyValue::new(attribute, value))
+        }))
+        .build()
+}
+
+// Spans dropped because the export queue was full, since the last report
+static DROPPED_SPANS: AtomicU64 = AtomicU64::new(0);
+
+// Batch span processor that counts the spans it drops. The SDK's processor
+// drops spans silently once its queue is full, so this one tracks spans
+// handed over but not yet exported, and drops (and counts) spans itself
+// before that queue can overflow.
+#[derive(Debug)]
+pub struct CountingBatchProcessor {
+    inner: BatchSpanProcessor,
+    pending: Arc<AtomicUsize>,
+    max_pending: usize,
+}
+
+impl CountingBatchProcessor {
+    pub fn new<E: SpanExporter + 'static>(exporter: E, max_queue_size: usize) -> Self {
+        let pending = Arc::new(AtomicUsize::new(0));
+        let exporter = ReleasingExporter {
+            inner: exporter,
+            pending: Arc::clone(&pending),
+        };
+        let config = BatchConfigBuilder::default()
+            .with_max_queue_size(max_queue_size)
+            .build();
+        Self {
+            inner: BatchSpanProcessor::builder(exporter)
+                .with_batch_config(config)
+                .build(),
+            pending,
+            max_pending: max_queue_size,
+        }
+    }
+}
+
+impl SpanProcessor for CountingBatchProcessor {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        if self.pending.fetch_add(1, Ordering::Relaxed) >= self.max_pending {
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+            DROPPED_SPANS.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.inner.on_end(span);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+// Exporter releasing the pending count of each batch once its export is over,
+// whether it succeeded, failed or was abandoned
+#[derive(Debug)]
+struct ReleasingExporter<E> {
+    inner: E,
+    pending: Arc<AtomicUsize>,
+}
+
+struct PendingRelease<'a>(&'a AtomicUsize, usize);
+
+impl Drop for PendingRelease<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(self.1, Ordering::Relaxed);
+    }
+}
+
+impl<E: SpanExporter> SpanExporter for ReleasingExporter<E> {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let _release = PendingRelease(&self.pending, batch.len());
+        self.inner.export(batch).await
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+// Log the spans dropped since the last call, as a `dropped_spans` field a log
+// metric filter can alarm on
+pub fn report_dropped_spans() {
+    let dropped = DROPPED_SPANS.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        tracing::warn!(
+            dropped_spans = dropped,
+            "Dropped {} spans because the OTLP export queue was full",
+            dropped
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_exporter::CountingExporter;
+    use opentelemetry::trace::TraceId;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use std::collections::HashMap;
+
+    fn headers(traceparent: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("traceparent", traceparent.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn extracts_valid_trace_context_only() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let context = extract_remote_context(&headers(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ))
+        .unwrap();
+        assert_eq!(
+            context.span().span_context().trace_id(),
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap()
+        );
+        assert!(context.span().span_context().is_remote());
+
+        assert!(extract_remote_context(&headers("00-not-a-trace-01")).is_none());
+        assert!(extract_remote_context(&HeaderMap::new()).is_none());
+    }
+
+    fn attribute(resource: &Resource, key: &'static str) -> Option<String> {
+        resource
+            .get(&opentelemetry::Key::from_static_str(key))
+            .map(|value| value.to_string())
+    }
+
+    #[test]
+    fn resource_attributes_come_from_non_empty_variables() {
+        let vars = HashMap::from([
+            ("OTEL_SERVICE_NAME", "renderer-eu"),
+            ("DEPLOYMENT_ENVIRONMENT", "staging"),
+            ("AWS_REGION", "eu-central-1"),
+            ("GIT_SHA", ""),
+        ]);
+        let resource = resource_from(|name| vars.get(name).map(|value| value.to_string()));
+        assert_eq!(
+            attribute(&resource, "service.name").as_deref(),
+            Some("renderer-eu")
+        );
+        assert_eq!(
+            attribute(&resource, "service.version").as_deref(),
+            Some(env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(
+            attribute(&resource, "deployment.environment").as_deref(),
+            Some("staging")
+        );
+        assert_eq!(
+            attribute(&resource, "cloud.region").as_deref(),
+            Some("eu-central-1")
+        );
+        assert_eq!(attribute(&resource, "vcs.ref.head.revision"), None);
+
+        let resource = resource_from(|_| None);
+        assert_eq!(
+            attribute(&resource, "service.name").as_deref(),
+            Some("pdf-renderer-lambda")
+        );
+    }
+
+    #[test]
+    fn counts_spans_dropped_once_the_queue_is_full() {
+        use opentelemetry::trace::{Span as _, Tracer as _, TracerProvider as _};
+
+        // Slow enough that a burst of spans finds the previous batch still exporting
+        let exporter = CountingExporter::with_delay(Duration::from_millis(200));
+        let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+            .with_span_processor(CountingBatchProcessor::new(exporter.clone(), 2))
+            .build();
+        let tracer = provider.tracer("test");
+        for _ in 0..5 {
+            tracer.start("render_job").end();
+        }
+        assert_eq!(DROPPED_SPANS.load(Ordering::Relaxed), 3);
+
+        report_dropped_spans();
+        assert_eq!(DROPPED_SPANS.load(Ordering::Relaxed), 0);
+
+        // Exported spans free their place in the queue
+        provider.force_flush().unwrap();
+        assert_eq!(exporter.exported(), 2);
+        tracer.start("render_job").end();
+        assert_eq!(DROPPED_SPANS.load(Ordering::Relaxed), 0);
+        provider.shutdown().unwrap();
+        assert_eq!(exporter.exported(), 3);
+    }
+}
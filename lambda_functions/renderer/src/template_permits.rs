@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{AcquireError, OwnedSemaphorePermit, Semaphore};
+
+// Slots held by a running render: its template's, if limited, and a global one
+#[derive(Debug)]
+pub struct RenderPermit {
+    _template: Option<OwnedSemaphorePermit>,
+    _global: OwnedSemaphorePermit,
+}
+
+// Per-template bounds on concurrent renders, on top of the global render
+// permits. Templates without a configured limit only wait for a global permit.
+// Limits above 1 are honored as given: every render works on its own clone of
+// the cached template, so papermake's per-template lock doesn't serialize
+// them. Each render still needs a global permit though, so a limit at or above
+// GLOBAL_RENDER_PERMITS has no effect.
+#[derive(Debug, Default)]
+pub struct TemplatePermits {
+    semaphores: HashMap<String, Arc<Semaphore>>,
+}
+
+impl TemplatePermits {
+    pub fn new(limits: HashMap<String, usize>) -> Self {
+        let semaphores = limits
+            .into_iter()
+            .map(|(template_id, limit)| (template_id, Arc::new(Semaphore::new(limit.max(1)))))
+            .collect();
+        Self { semaphores }
+    }
+
+    // Parse limits from a JSON object, e.g. {"annual-report.typ": 1}
+    pub fn from_json(config: &str) -> Result<Self, serde_json::Error> {
+        Ok(Self::new(serde_json::from_str(config)?))
+    }
+
+    // Wait for the template's permit, then for a global one. Taking the
+    // template's first keeps queued renders of a limited template from
+    // holding global permits other templates could use.
+    pub async fn acquire(
+        &self,
+        template_id: &str,
+        global: &Arc<Semaphore>,
+    ) -> Result<RenderPermit, AcquireError> {
+        let template = match self.semaphores.get(template_id) {
+            Some(semaphore) => Some(Arc::clone(semaphore).acquire_owned().await?),
+            None => None,
+        };
+        let global = Arc::clone(global).acquire_owned().await?;
+        Ok(RenderPermit {
+            _template: template,
+            _global: global,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn permits(config: &str) -> TemplatePermits {
+        TemplatePermits::from_json(config).unwrap()
+    }
+
+    // The permit for `template_id`, if acquiring it completes right away
+    async fn acquires_now(
+        permits: &TemplatePermits,
+        template_id: &str,
+        global: &Arc<Semaphore>,
+    ) -> Option<RenderPermit> {
+        tokio::time::timeout(
+            Duration::from_millis(20),
+            permits.acquire(template_id, global),
+        )
+        .await
+        .ok()
+        .map(Result::unwrap)
+    }
+
+    #[tokio::test]
+    async fn a_limit_of_one_serializes_a_template_while_others_proceed() {
+        let permits = permits(r#"{"heavy.typ": 1}"#);
+        let global = Arc::new(Semaphore::new(4));
+
+        let heavy = acquires_now(&permits, "heavy.typ", &global).await.unwrap();
+        assert!(acquires_now(&permits, "heavy.typ", &global).await.is_none());
+        let light = acquires_now(&permits, "light.typ", &global).await.unwrap();
+        // The waiting heavy render didn't hold on to a global permit
+        assert_eq!(global.available_permits(), 2);
+
+        drop(heavy);
+        assert!(acquires_now(&permits, "heavy.typ", &global).await.is_some());
+        drop(light);
+        assert_eq!(global.available_permits(), 4);
+    }
+
+    #[tokio::test]
+    async fn limits_above_one_run_that_many_renders_at_once() {
+        let permits = permits(r#"{"report.typ": 2, "zero.typ": 0}"#);
+        let global = Arc::new(Semaphore::new(4));
+
+        let first = acquires_now(&permits, "report.typ", &global).await;
+        let second = acquires_now(&permits, "report.typ", &global).await;
+        assert!(first.is_some() && second.is_some());
+        assert!(acquires_now(&permits, "report.typ", &global)
+            .await
+            .is_none());
+
+        // A limit of 0 would block the template forever, so it counts as 1
+        let zero = acquires_now(&permits, "zero.typ", &global).await;
+        assert!(zero.is_some());
+        assert!(acquires_now(&permits, "zero.typ", &global).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn unlimited_templates_are_bound_by_the_global_permits() {
+        let permits = TemplatePermits::default();
+        let global = Arc::new(Semaphore::new(2));
+        let _first = acquires_now(&permits, "a.typ", &global).await.unwrap();
+        let _second = acquires_now(&permits, "b.typ", &global).await.unwrap();
+        assert!(acquires_now(&permits, "a.typ", &global).await.is_none());
+        assert!(TemplatePermits::from_json(r#"{"a.typ": -1}"#).is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use crate::svg;
+use resvg::{tiny_skia, usvg};
+use serde_json::Value;
+use thiserror::Error;
+
+// Resolution used when a job asks for a thumbnail without thumbnail_dpi
+pub const DEFAULT_DPI: u32 = 72;
+// Upper bound on thumbnail_dpi; an A4 page at 300 DPI is about 2500x3500 pixels
+pub const MAX_DPI: u32 = 300;
+
+#[derive(Error, Debug)]
+pub enum ThumbnailError {
+    #[error("template failed to compile: {0}")]
+    Compile(String),
+    #[error("document has no pages")]
+    NoPages,
+    #[error("invalid page SVG: {0}")]
+    Svg(#[from] usvg::Error),
+    #[error("page of {0}x{1} pixels can't be rasterized")]
+    Size(u32, u32),
+    #[error("failed to encode PNG: {0}")]
+    Encode(String),
+}
+
+// Rasterize the first page of a template rendered with `data` into a PNG,
+// drawing the page through its SVG form
+pub fn first_page_png(template: &str, data: &Value, dpi: u32) -> Result<Vec<u8>, ThumbnailError> {
+    let document = svg::compile(template, data).map_err(|e| ThumbnailError::Compile(e.0))?;
+    let page = document.pages.first().ok_or(ThumbnailError::NoPages)?;
+
+    let tree = usvg::Tree::from_str(&typst_svg::svg(page), &usvg::Options::default())?;
+    let size = page.frame.size();
+    let width = (size.x.to_pt() * dpi as f64 / 72.0).round() as u32;
+    let height = (size.y.to_pt() * dpi as f64 / 72.0).round() as u32;
+    let mut pixmap =
+        tiny_skia::Pixmap::new(width, height).ok_or(ThumbnailError::Size(width, height))?;
+    let transform = tiny_skia::Transform::from_scale(
+        width as f32 / tree.size().width(),
+        height as f32 / tree.size().height(),
+    );
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    pixmap
+        .encode_png()
+        .map_err(|e| ThumbnailError::Encode(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // Width and height from a PNG's IHDR chunk
+    fn png_size(png: &[u8]) -> (u32, u32) {
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        let read = |at: usize| u32::from_be_bytes(png[at..at + 4].try_into().unwrap());
+        (read(16), read(20))
+    }
+
+    #[test]
+    fn rasterizes_the_first_page_at_the_given_resolution() {
+        let template = "First\n#pagebreak()\nSecond";
+        let png = first_page_png(template, &json!({}), DEFAULT_DPI).unwrap();
+        assert_eq!(png_size(&png), (595, 842));
+
+        // An A4 page is 595.28x841.89pt, so 1190.55x1683.78 pixels at 144 DPI
+        let png = first_page_png(template, &json!({}), 144).unwrap();
+        assert_eq!(png_size(&png), (1191, 1684));
+    }
+
+    #[test]
+    fn reports_compile_errors() {
+        assert!(matches!(
+            first_page_png("#panic(\"no\")", &json!({}), DEFAULT_DPI),
+            Err(ThumbnailError::Compile(_))
+        ));
+    }
+}
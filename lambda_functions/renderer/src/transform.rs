@@ -0,0 +1,218 @@
+use crate::pointer::to_json_pointer;
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+// Suffix of the transform object stored next to a template
+pub const TRANSFORM_SUFFIX: &str = ".transform.json";
+
+pub fn transform_key(template_id: &str) -> String {
+    format!("{}{}", template_id, TRANSFORM_SUFFIX)
+}
+
+#[derive(Error, Debug)]
+pub enum TransformError {
+    #[error("transform must be a JSON object mapping source fields to target fields")]
+    InvalidSpec,
+    #[error("transform source and target fields can't be empty")]
+    EmptyPointer,
+    #[error(
+        "can't write target {0}: a parent is not an object or the array index is out of range"
+    )]
+    InvalidTarget(String),
+}
+
+// Field moves applied to a job's data before it reaches the template, read
+// from {template_id}.transform.json. The spec is an object mapping source
+// fields to target fields, each a JSON Pointer or a dot-separated path:
+// {"customer.name": "client.name", "/lines": "/invoice/items"}.
+#[derive(Debug, Clone)]
+pub struct Transform {
+    // (source, target) pointers as reference tokens
+    moves: Vec<(Vec<String>, Vec<String>)>,
+}
+
+impl Transform {
+    pub fn parse(spec: &[u8]) -> Result<Transform, TransformError> {
+        let spec: Map<String, Value> =
+            serde_json::from_slice(spec).map_err(|_| TransformError::InvalidSpec)?;
+        let moves = spec
+            .iter()
+            .map(|(source, target)| {
+                let target = target.as_str().ok_or(TransformError::InvalidSpec)?;
+                Ok((tokens(source)?, tokens(target)?))
+            })
+            .collect::<Result<_, TransformError>>()?;
+        Ok(Transform { moves })
+    }
+
+    // Move every mapped field of `data` to its target, creating intermediate
+    // objects as needed. All sources are taken out before any target is
+    // written, so a field can move to where another one was moved from.
+    // Missing sources are skipped; unmapped fields stay where they are.
+    pub fn apply(&self, mut data: Value) -> Result<Value, TransformError> {
+        let values: Vec<Option<Value>> = self
+            .moves
+            .iter()
+            .map(|(source, _)| take(&mut data, source))
+            .collect();
+        for ((_, target), value) in self.moves.iter().zip(values) {
+            if let Some(value) = value {
+                insert(&mut data, target, value)?;
+            }
+        }
+        Ok(data)
+    }
+}
+
+// Reference tokens of a pointer or dot path, unescaped
+fn tokens(pointer: &str) -> Result<Vec<String>, TransformError> {
+    let pointer = to_json_pointer(pointer);
+    if pointer.is_empty() {
+        return Err(TransformError::EmptyPointer);
+    }
+    Ok(pointer[1..]
+        .split('/')
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+// Remove the value at `tokens`; values inside arrays are copied rather than
+// removed, so the indices of their siblings don't shift
+fn take(data: &mut Value, tokens: &[String]) -> Option<Value> {
+    let (last, parents) = tokens.split_last()?;
+    let parent = parents
+        .iter()
+        .try_fold(&mut *data, |value, token| child(value, token))?;
+    match parent {
+        Value::Object(map) => map.remove(last),
+        Value::Array(items) => items.get(last.parse::<usize>().ok()?).cloned(),
+        _ => None,
+    }
+}
+
+fn insert(data: &mut Value, tokens: &[String], value: Value) -> Result<(), TransformError> {
+    let invalid = || TransformError::InvalidTarget(format!("/{}", tokens.join("/")));
+    let Some((last, parents)) = tokens.split_last() else {
+        return Err(invalid());
+    };
+    let mut parent = data;
+    for token in parents {
+        if parent.is_null() {
+            *parent = Value::Object(Map::new());
+        }
+        if let Value::Object(map) = parent {
+            map.entry(token.clone())
+                .or_insert_with(|| Value::Object(Map::new()));
+        }
+        parent = child(parent, token).ok_or_else(invalid)?;
+    }
+    if parent.is_null() {
+        *parent = Value::Object(Map::new());
+    }
+    match parent {
+        Value::Object(map) => {
+            map.insert(last.clone(), value);
+        }
+        Value::Array(items) => {
+            let slot = last
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| items.get_mut(index))
+                .ok_or_else(invalid)?;
+            *slot = value;
+        }
+        _ => return Err(invalid()),
+    }
+    Ok(())
+}
+
+fn child<'a>(value: &'a mut Value, token: &str) -> Option<&'a mut Value> {
+    match value {
+        Value::Object(map) => map.get_mut(token),
+        Value::Array(items) => items.get_mut(token.parse::<usize>().ok()?),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn apply(spec: Value, data: Value) -> Result<Value, TransformError> {
+        Transform::parse(spec.to_string().as_bytes())?.apply(data)
+    }
+
+    #[test]
+    fn moves_mapped_fields_and_keeps_the_rest() {
+        let data = json!({"customer": {"name": "Ada", "id": 7}, "lines": [1, 2], "total": 3});
+        assert_eq!(
+            apply(
+                json!({"customer.name": "client.name", "/lines": "/invoice/items"}),
+                data
+            )
+            .unwrap(),
+            json!({
+                "customer": {"id": 7},
+                "client": {"name": "Ada"},
+                "invoice": {"items": [1, 2]},
+                "total": 3,
+            })
+        );
+    }
+
+    #[test]
+    fn swaps_fields_and_skips_missing_sources() {
+        assert_eq!(
+            apply(
+                json!({"a": "b", "b": "a", "missing": "c"}),
+                json!({"a": 1, "b": 2})
+            )
+            .unwrap(),
+            json!({"a": 2, "b": 1})
+        );
+    }
+
+    #[test]
+    fn copies_out_of_arrays_and_writes_existing_indices() {
+        assert_eq!(
+            apply(
+                json!({"items.0.sku": "first_sku", "name": "items.1"}),
+                json!({"items": [{"sku": "A"}, {"sku": "B"}], "name": "x"})
+            )
+            .unwrap(),
+            json!({"items": [{}, "x"], "first_sku": "A"})
+        );
+        // Array elements themselves are copied, keeping the indices of the rest
+        assert_eq!(
+            apply(json!({"items.0": "first"}), json!({"items": ["A", "B"]})).unwrap(),
+            json!({"items": ["A", "B"], "first": "A"})
+        );
+        assert!(matches!(
+            apply(json!({"name": "items.5"}), json!({"items": [], "name": "x"})),
+            Err(TransformError::InvalidTarget(target)) if target == "/items/5"
+        ));
+        assert!(matches!(
+            apply(
+                json!({"name": "total.amount"}),
+                json!({"total": 3, "name": "x"})
+            ),
+            Err(TransformError::InvalidTarget(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_specs() {
+        for spec in ["[]", "{\"a\": 1}", "not json"] {
+            assert!(matches!(
+                Transform::parse(spec.as_bytes()),
+                Err(TransformError::InvalidSpec)
+            ));
+        }
+        assert!(matches!(
+            Transform::parse(br#"{"": "a"}"#),
+            Err(TransformError::EmptyPointer)
+        ));
+        assert_eq!(transform_key("invoice.typ"), "invoice.typ.transform.json");
+    }
+}
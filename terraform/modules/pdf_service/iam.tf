@@ -59,11 +59,33 @@ resource "aws_iam_policy" "renderer_s3" {
         Resource = "${aws_s3_bucket.templates.arn}/*"
       },
       {
+        # The health action's HeadBucket checks are authorized by ListBucket
         Action = [
-          "s3:PutObject"
+          "s3:ListBucket"
+        ]
+        Effect   = "Allow"
+        Resource = aws_s3_bucket.templates.arn
+      },
+      {
+        Action = [
+          "s3:PutObject",
+          "s3:GetObject",
+          "s3:DeleteObject",
+          "s3:AbortMultipartUpload"
         ]
         Effect   = "Allow"
         Resource = "${aws_s3_bucket.results.arn}/*"
+      },
+      {
+        # ListBucket lets HeadObject report a missing result key as 404
+        # rather than 403, which on_conflict fail/skip rely on, and
+        # authorizes the health action's HeadBucket check
+        Action = [
+          "s3:ListBucket",
+          "s3:ListBucketMultipartUploads"
+        ]
+        Effect   = "Allow"
+        Resource = aws_s3_bucket.results.arn
       }
     ]
   })
@@ -49,6 +49,39 @@ resource "aws_lambda_function" "renderer" {
 }
 
 
+# Optional EventBridge schedule rendering the jobs listed in an S3 manifest
+resource "aws_cloudwatch_event_rule" "manifest_schedule" {
+  count               = var.manifest_schedule_expression != "" ? 1 : 0
+  name                = "${var.project_name}-manifest-schedule-${var.environment}"
+  schedule_expression = var.manifest_schedule_expression
+  tags                = local.common_tags
+}
+
+resource "aws_cloudwatch_event_target" "manifest_schedule" {
+  count = var.manifest_schedule_expression != "" ? 1 : 0
+  rule  = aws_cloudwatch_event_rule.manifest_schedule[0].name
+  arn   = aws_lambda_function.renderer.arn
+
+  # Wrap the manifest location in an event shape the renderer recognizes
+  input = jsonencode({
+    "detail-type" = "Scheduled Manifest Render"
+    source        = "papermake.scheduler"
+    detail = {
+      key = var.manifest_key
+    }
+  })
+}
+
+resource "aws_lambda_permission" "manifest_schedule" {
+  count         = var.manifest_schedule_expression != "" ? 1 : 0
+  statement_id  = "AllowExecutionFromEventBridge"
+  action        = "lambda:InvokeFunction"
+  function_name = aws_lambda_function.renderer.function_name
+  principal     = "events.amazonaws.com"
+  source_arn    = aws_cloudwatch_event_rule.manifest_schedule[0].arn
+}
+
+
 # SNS topic for scaling alerts
 resource "aws_sns_topic" "scaling_alerts" {
   name = "pdf-service-scaling-alerts-${var.environment}"
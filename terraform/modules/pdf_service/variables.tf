@@ -36,4 +36,16 @@ variable "otlp_endpoint" {
   description = "OpenTelemetry OTLP endpoint for tracing (optional — omit to disable OTLP export)"
   type        = string
   default     = ""
-} 
\ No newline at end of file
+} 
+
+variable "manifest_schedule_expression" {
+  description = "EventBridge schedule expression for rendering a manifest (optional — omit to disable)"
+  type        = string
+  default     = ""
+}
+
+variable "manifest_key" {
+  description = "Key of the render manifest in the templates bucket, used by the manifest schedule"
+  type        = string
+  default     = "manifests/nightly.json"
+}